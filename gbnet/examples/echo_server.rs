@@ -12,7 +12,7 @@ fn main() {
     loop {
         for event in server.update() {
             match event {
-                ServerEvent::ClientConnected(addr) => {
+                ServerEvent::ClientConnected { addr, .. } => {
                     println!("[+] Client connected: {}", addr);
                 }
                 ServerEvent::ClientDisconnected(addr, reason) => {
@@ -37,6 +37,9 @@ fn main() {
                 ServerEvent::ClientMigrated { old_addr, new_addr } => {
                     println!("[~] Client migrated: {} -> {}", old_addr, new_addr);
                 }
+                ServerEvent::ConnectionRequest { addr, .. } => {
+                    server.accept_connection(addr);
+                }
             }
         }
         std::thread::sleep(std::time::Duration::from_millis(16));
@@ -3,6 +3,7 @@
 //! Each [`Channel`] provides independent message buffering and delivery
 //! guarantees: Unreliable, UnreliableSequenced, ReliableUnordered,
 //! ReliableOrdered, and ReliableSequenced.
+use crate::clock::{system_clock, SharedClock};
 use crate::config::{ChannelConfig, DeliveryMode};
 use crate::stats::ChannelStats;
 use crate::util::sequence_greater_than;
@@ -65,6 +66,8 @@ pub struct Channel {
     bytes_received: u64,
     gap_sequences_skipped: u64,
     messages_dropped: u64,
+
+    clock: SharedClock,
 }
 
 impl Channel {
@@ -85,9 +88,16 @@ impl Channel {
             bytes_received: 0,
             gap_sequences_skipped: 0,
             messages_dropped: 0,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` for send timestamps and ordered-buffer timeouts.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Queue a message for sending on this channel.
     /// The `reliable` parameter allows per-message reliability override:
     /// - On an unreliable channel with `reliable=true`, the message is tracked for ACK/retransmit.
@@ -135,7 +145,7 @@ impl Channel {
 
             if message.reliable {
                 let mut pending = message;
-                pending.send_time = Some(Instant::now());
+                pending.send_time = Some(self.clock.now());
                 self.pending_ack.insert(seq, pending);
             }
 
@@ -145,12 +155,54 @@ impl Channel {
         }
     }
 
+    /// Wire size of the next message `get_outgoing_message` would return.
+    pub fn next_outgoing_size(&self) -> Option<usize> {
+        self.send_buffer
+            .front()
+            .map(|message| SEQUENCE_BYTES + message.data.len())
+    }
+
+    /// Wire bytes this channel wants to send at `now`: queued messages plus
+    /// reliable messages due for retransmission.
+    pub fn pending_send_bytes(&self, now: Instant, rto: std::time::Duration) -> usize {
+        let queued: usize = self
+            .send_buffer
+            .iter()
+            .map(|message| SEQUENCE_BYTES + message.data.len())
+            .sum();
+        let max_retries = self.config.max_reliable_retries;
+        let due: usize = self
+            .pending_ack
+            .values()
+            .filter(|msg| {
+                msg.retry_count < max_retries
+                    && msg.send_time.is_some_and(|send_time| {
+                        now.duration_since(send_time) >= backoff_rto(rto, msg.retry_count)
+                    })
+            })
+            .map(|msg| SEQUENCE_BYTES + msg.data.len())
+            .sum();
+        queued + due
+    }
+
     /// Get messages that need retransmission (for reliable channels).
     /// Messages exceeding `max_reliable_retries` are removed from pending_ack.
     pub fn get_retransmit_messages(
         &mut self,
         now: Instant,
         rto: std::time::Duration,
+    ) -> Vec<(u16, Vec<u8>)> {
+        self.get_retransmit_messages_within(now, rto, usize::MAX)
+    }
+
+    /// Like [`Self::get_retransmit_messages`], but stops once the returned
+    /// wire data would exceed `max_bytes`. Messages left out stay due and are
+    /// returned by a later call.
+    pub fn get_retransmit_messages_within(
+        &mut self,
+        now: Instant,
+        rto: std::time::Duration,
+        max_bytes: usize,
     ) -> Vec<(u16, Vec<u8>)> {
         if !self.config.delivery_mode.is_reliable() {
             return Vec::new();
@@ -159,14 +211,19 @@ impl Channel {
         let max_retries = self.config.max_reliable_retries;
         let mut retransmits = Vec::new();
         let mut expired = Vec::new();
+        let mut remaining = max_bytes;
         for (seq, msg) in &mut self.pending_ack {
             if let Some(send_time) = msg.send_time {
-                let backoff_rto = rto * (1u32 << msg.retry_count.min(MAX_BACKOFF_EXPONENT));
-                if now.duration_since(send_time) >= backoff_rto {
+                if now.duration_since(send_time) >= backoff_rto(rto, msg.retry_count) {
                     if msg.retry_count >= max_retries {
                         expired.push(*seq);
                         continue;
                     }
+                    let wire_size = SEQUENCE_BYTES + msg.data.len();
+                    if wire_size > remaining {
+                        continue;
+                    }
+                    remaining -= wire_size;
                     msg.retry_count += 1;
                     msg.send_time = Some(now);
 
@@ -232,8 +289,8 @@ impl Channel {
                     if self.ordered_receive_buffer.len() >= self.config.max_ordered_buffer_size {
                         self.evict_oldest_buffered();
                     }
-                    self.ordered_receive_buffer
-                        .insert(seq, (data, Instant::now()));
+                    let now = self.clock.now();
+                    self.ordered_receive_buffer.insert(seq, (data, now));
                 }
             }
             DeliveryMode::ReliableSequenced => {
@@ -288,6 +345,19 @@ impl Channel {
         self.delivery_queue.pop_front()
     }
 
+    /// Number of messages ready to be received.
+    pub fn pending_receive(&self) -> usize {
+        self.delivery_queue.len()
+    }
+
+    /// Discard all but the oldest `keep` received messages, returning how
+    /// many were discarded.
+    pub fn truncate_received(&mut self, keep: usize) -> usize {
+        let dropped = self.delivery_queue.len().saturating_sub(keep);
+        self.delivery_queue.truncate(keep);
+        dropped
+    }
+
     /// Update the channel state. Checks for ordered buffer timeout.
     pub fn update(&mut self) {
         // Check for ordered buffer timeout (ReliableOrdered only)
@@ -295,10 +365,11 @@ impl Channel {
             && !self.ordered_receive_buffer.is_empty()
         {
             let timeout = self.config.ordered_buffer_timeout;
+            let now = self.clock.now();
             let has_timed_out = self
                 .ordered_receive_buffer
                 .values()
-                .any(|(_, inserted_at)| inserted_at.elapsed() > timeout);
+                .any(|(_, inserted_at)| now.duration_since(*inserted_at) > timeout);
             if has_timed_out {
                 self.flush_ordered_buffer();
             }
@@ -393,16 +464,31 @@ impl Channel {
     /// Mark a message for fast retransmit by resetting its send_time,
     /// causing it to be retransmitted on the next `get_retransmit_messages()` call.
     pub fn mark_for_fast_retransmit(&mut self, seq: u16) {
+        let now = self.clock.now();
         if let Some(msg) = self.pending_ack.get_mut(&seq) {
-            msg.send_time = Some(Instant::now() - std::time::Duration::from_secs(60));
+            msg.send_time = Some(
+                now.checked_sub(std::time::Duration::from_secs(60))
+                    .unwrap_or(now),
+            );
         }
     }
 
+    /// Returns `true` if the message with `sequence` was sent reliably and is
+    /// still waiting for an ACK.
+    pub fn awaits_ack(&self, sequence: u16) -> bool {
+        self.pending_ack.contains_key(&sequence)
+    }
+
     pub fn pending_ack_count(&self) -> usize {
         self.pending_ack.len()
     }
 }
 
+/// Retransmission timeout after `retry_count` exponential backoffs.
+fn backoff_rto(rto: std::time::Duration, retry_count: u32) -> std::time::Duration {
+    rto * (1u32 << retry_count.min(MAX_BACKOFF_EXPONENT))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -620,4 +706,20 @@ mod tests {
         assert_eq!(ch.receive().unwrap(), b"b");
         assert!(ch.receive().is_none());
     }
+
+    #[test]
+    fn test_ordered_buffer_timeout_with_manual_clock() {
+        let clock = crate::clock::ManualClock::new();
+        let config = ChannelConfig::reliable_ordered();
+        let timeout = config.ordered_buffer_timeout;
+        let mut channel = Channel::new(0, config).with_clock(clock.shared());
+
+        channel.on_packet_received(make_wire(1, b"late"));
+        channel.update();
+        assert!(channel.receive().is_none(), "Gap at seq 0 holds delivery");
+
+        clock.advance(timeout + std::time::Duration::from_millis(1));
+        channel.update();
+        assert_eq!(channel.receive().unwrap(), b"late");
+    }
 }
@@ -6,12 +6,14 @@ use std::net::SocketAddr;
 use std::time::Instant;
 
 use crate::{
-    congestion,
+    clock::{system_clock, SharedClock},
     connection::{Connection, ConnectionError, ConnectionState, DisconnectReason},
-    packet::{disconnect_reason, Packet, PacketType},
-    security,
-    socket::{SocketError, UdpSocket},
-    wire, NetworkConfig, NetworkStats,
+    packet::{deny_reason, disconnect_reason, Packet, PacketType, MAX_CONNECT_DATA},
+    security::{self, ConnectToken, SessionRole},
+    socket::{bind_reaches, unspecified_addr_for, SocketError, UdpSocket},
+    transport::Transport,
+    wire::{self, DatagramLink},
+    NetworkConfig, NetworkStats,
 };
 
 /// Events emitted by [`NetClient::update`].
@@ -25,8 +27,10 @@ pub enum ClientEvent {
 /// A game client that connects to a server over UDP.
 ///
 /// Call [`NetClient::update`] once per game tick to process packets and collect events.
-pub struct NetClient {
-    socket: UdpSocket,
+/// The client runs over any [`Transport`]; [`NetClient::connect`] uses a [`UdpSocket`].
+pub struct NetClient<T: Transport = UdpSocket> {
+    transport: T,
+    link: DatagramLink,
     connection: Connection,
     server_addr: SocketAddr,
     connected_notified: bool,
@@ -34,6 +38,18 @@ pub struct NetClient {
     connect_time: Instant,
     disconnect_time: Option<Instant>,
     disconnect_retry_count: u32,
+    /// Serialized connect token attached to every connection request.
+    connect_token: Option<Vec<u8>>,
+    /// Payload of the server's connection deny, if it refused this client.
+    deny_payload: Vec<u8>,
+    /// Application data sent with the connection response.
+    connect_data: Vec<u8>,
+    /// Connection response payload: this side's key share and the sealed
+    /// connect data, built once per challenge so resends match.
+    response_payload: Vec<u8>,
+    /// Payload of the server's connection accept.
+    accept_payload: Vec<u8>,
+    clock: SharedClock,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -47,29 +63,143 @@ enum ClientState {
 
 impl NetClient {
     /// Connect to a server at the given address. Initiates the handshake immediately.
+    ///
+    /// Binds [`NetworkConfig::local_bind_addr`] if set, otherwise an ephemeral
+    /// port on the unspecified address of the server's family. Fails if the
+    /// configured bind address cannot reach the server's address family.
     pub fn connect(server_addr: SocketAddr, config: NetworkConfig) -> Result<Self, SocketError> {
+        let socket = Self::bind_for(server_addr, &config)?;
+        Self::with_transport(socket, server_addr, config)
+    }
+
+    /// Connect using a token issued by the backend, to the first server it lists.
+    pub fn connect_with_token(
+        token: ConnectToken,
+        config: NetworkConfig,
+    ) -> Result<Self, SocketError> {
+        let Some(&server_addr) = token.server_addresses.first() else {
+            return Err(SocketError::Other(
+                "connect token lists no servers".to_string(),
+            ));
+        };
+        let socket = Self::bind_for(server_addr, &config)?;
+        let mut client = Self::new_unstarted(socket, server_addr, config)?;
+        client.connect_token = Some(token.to_bytes());
+        client.send_connection_request(wire::connection_request(client.connection.config()));
+        Ok(client)
+    }
+
+    /// Connect to a server at the given address, sending `data` (an auth
+    /// ticket, player name, build hash...) with the handshake. The server
+    /// receives it in [`crate::ServerEvent::ClientConnected`]. Fails if
+    /// `data` is longer than [`MAX_CONNECT_DATA`].
+    pub fn connect_with_data(
+        server_addr: SocketAddr,
+        config: NetworkConfig,
+        data: &[u8],
+    ) -> Result<Self, SocketError> {
+        check_connect_data(data)?;
+        Self::connect(server_addr, config)?.with_connect_data(data)
+    }
+
+    /// Bind the socket [`NetClient::connect`] uses to reach `server_addr`.
+    fn bind_for(server_addr: SocketAddr, config: &NetworkConfig) -> Result<UdpSocket, SocketError> {
+        if let Err(e) = config.validate() {
+            return Err(SocketError::Other(e.to_string()));
+        }
+        let bind_addr = config
+            .local_bind_addr
+            .unwrap_or_else(|| unspecified_addr_for(&server_addr));
+        if !bind_reaches(&bind_addr, &server_addr) {
+            return Err(SocketError::Other(format!(
+                "local bind address {bind_addr} cannot reach server {server_addr}"
+            )));
+        }
+        UdpSocket::bind(bind_addr)
+    }
+}
+
+impl<T: Transport> NetClient<T> {
+    /// Connect to a server through an existing transport. Initiates the handshake immediately.
+    pub fn with_transport(
+        transport: T,
+        server_addr: SocketAddr,
+        config: NetworkConfig,
+    ) -> Result<Self, SocketError> {
+        let mut client = Self::new_unstarted(transport, server_addr, config)?;
+        client.send_connection_request(wire::connection_request(client.connection.config()));
+        Ok(client)
+    }
+
+    /// A client that has not yet sent its connection request.
+    fn new_unstarted(
+        transport: T,
+        server_addr: SocketAddr,
+        config: NetworkConfig,
+    ) -> Result<Self, SocketError> {
         if let Err(e) = config.validate() {
             return Err(SocketError::Other(e.to_string()));
         }
-        let bind_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let socket = UdpSocket::bind(bind_addr)?;
-        let local_addr = socket.local_addr()?;
+        let local_addr = transport.local_addr()?;
+        let link = DatagramLink::from_config(&config);
+        let clock = system_clock();
 
         let connection = Connection::new(config, local_addr, server_addr);
 
-        let mut client = Self {
-            socket,
+        Ok(Self {
+            transport,
+            link,
             connection,
             server_addr,
             connected_notified: false,
             state: ClientState::Connecting,
-            connect_time: Instant::now(),
+            connect_time: clock.now(),
             disconnect_time: None,
             disconnect_retry_count: 0,
-        };
+            connect_token: None,
+            deny_payload: Vec::new(),
+            connect_data: Vec::new(),
+            response_payload: Vec::new(),
+            accept_payload: Vec::new(),
+            clock,
+        })
+    }
 
-        client.send_raw(PacketType::ConnectionRequest);
-        Ok(client)
+    /// Drive all client and connection timers from `clock` instead of the
+    /// system clock. Chain onto the constructor; restarts the handshake
+    /// timeout at the clock's current time.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.connection = Connection::new(
+            self.connection.config().clone(),
+            self.connection.local_addr,
+            self.server_addr,
+        )
+        .with_clock(clock.clone());
+        self.link.set_clock(clock.clone());
+        self.connect_time = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Present `token` in connection requests, for servers configured with
+    /// [`NetworkConfig::connect_token_key`]. Chain onto the constructor;
+    /// resends the connection request with the token attached.
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.connect_token = Some(token.to_bytes());
+        if self.state == ClientState::Connecting {
+            self.send_connection_request(wire::connection_request(self.connection.config()));
+        }
+        self
+    }
+
+    /// Send up to [`MAX_CONNECT_DATA`] bytes of `data` with the handshake.
+    /// It travels in the connection response, after the server has proven it
+    /// holds the challenge, and is protected like the session. Chain onto
+    /// the constructor; fails if `data` is longer than [`MAX_CONNECT_DATA`].
+    pub fn with_connect_data(mut self, data: &[u8]) -> Result<Self, SocketError> {
+        check_connect_data(data)?;
+        self.connect_data = data.to_vec();
+        Ok(self)
     }
 
     /// Process incoming packets, send keepalives, and return events.
@@ -82,7 +212,7 @@ impl NetClient {
             ClientState::Connecting | ClientState::ChallengeResponse
         ) {
             let config = self.connection.config().clone();
-            if self.connect_time.elapsed() > config.connection_timeout {
+            if self.clock.now().duration_since(self.connect_time) > config.connection_timeout {
                 self.state = ClientState::Disconnected;
                 events.push(ClientEvent::Disconnected(DisconnectReason::Timeout));
                 return events;
@@ -92,44 +222,39 @@ impl NetClient {
         if self.state == ClientState::Disconnecting {
             let config = self.connection.config().clone();
             if let Some(disc_time) = self.disconnect_time {
-                if disc_time.elapsed() > config.disconnect_retry_timeout {
+                if self.clock.now().duration_since(disc_time) > config.disconnect_retry_timeout {
                     if self.disconnect_retry_count >= config.disconnect_retries {
                         self.state = ClientState::Disconnected;
                         events.push(ClientEvent::Disconnected(DisconnectReason::Requested));
                         return events;
                     }
                     self.disconnect_retry_count += 1;
-                    self.disconnect_time = Some(Instant::now());
-                    self.send_raw(PacketType::Disconnect {
+                    self.disconnect_time = Some(self.clock.now());
+                    self.send_control(PacketType::Disconnect {
                         reason: disconnect_reason::REQUESTED,
                     });
                 }
             }
         }
 
-        loop {
-            match self.socket.recv_from() {
-                Ok((data, addr)) => {
-                    if addr != self.server_addr {
-                        continue;
-                    }
-                    let validated = match security::validate_and_strip_crc32(data) {
-                        Some(valid) => valid.to_vec(),
-                        None => continue,
-                    };
-                    let packet = match Packet::deserialize(&validated) {
-                        Ok(p) => p,
-                        Err(_) => continue,
-                    };
-                    if packet.header.protocol_id != self.connection.config().protocol_id {
-                        continue;
-                    }
-                    self.connection.record_bytes_received(validated.len());
-                    self.handle_packet(packet, &mut events);
-                }
-                Err(SocketError::WouldBlock) => break,
-                Err(_) => break,
+        self.flush();
+
+        for (data, addr) in self.link.receive(&mut self.transport) {
+            if addr != self.server_addr {
+                continue;
+            }
+            let Some(validated) = security::validate_and_strip_crc32(&data) else {
+                continue;
+            };
+            let packet = match Packet::deserialize(validated) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            if packet.header.protocol_id != self.connection.config().protocol_id {
+                continue;
             }
+            self.connection.record_bytes_received(validated.len());
+            self.handle_packet(packet, &mut events);
         }
 
         if self.state == ClientState::Connected {
@@ -139,23 +264,10 @@ impl NetClient {
                 return events;
             }
 
-            let packets = self.connection.drain_send_queue();
-            for packet in packets {
-                if let Ok(data) = packet.serialize() {
-                    let mut data_with_crc = data;
-                    security::append_crc32(&mut data_with_crc);
-                    let byte_len = data_with_crc.len();
-                    if let Err(e) = self.socket.send_to(&data_with_crc, self.server_addr) {
-                        log::warn!("Failed to send to {}: {:?}", self.server_addr, e);
-                        self.connection.stats.send_errors += 1;
-                    } else {
-                        self.connection.record_bytes_sent(byte_len);
-                    }
-                }
-            }
+            self.send_queued();
 
             let channel_count = self.connection.channel_count();
-            for ch in 0..channel_count as u8 {
+            for ch in (0..channel_count).map(|ch| ch as u8) {
                 while let Some(data) = self.connection.receive(ch) {
                     events.push(ClientEvent::Message { channel: ch, data });
                 }
@@ -189,28 +301,29 @@ impl NetClient {
         self.connection.set_state(ConnectionState::Disconnected);
         self.connection.stats = NetworkStats::default();
         self.connected_notified = false;
+        self.accept_payload.clear();
         self.state = ClientState::Connecting;
-        self.connect_time = Instant::now();
+        self.connect_time = self.clock.now();
         self.disconnect_time = None;
         self.disconnect_retry_count = 0;
-        self.send_raw(PacketType::ConnectionRequest);
+        self.send_connection_request(wire::connection_request(self.connection.config()));
     }
 
     /// Disconnect from the server. Sends a disconnect packet and enters
     /// `Disconnecting` state with retry logic until acknowledged or max retries.
     pub fn disconnect(&mut self) {
-        self.send_raw(PacketType::Disconnect {
+        self.send_control(PacketType::Disconnect {
             reason: disconnect_reason::REQUESTED,
         });
         self.state = ClientState::Disconnecting;
-        self.disconnect_time = Some(Instant::now());
+        self.disconnect_time = Some(self.clock.now());
         self.disconnect_retry_count = 0;
     }
 
     /// Shut down the client, sending a disconnect to the server.
     pub fn shutdown(&mut self) {
         if self.state == ClientState::Connected || self.state == ClientState::Disconnecting {
-            self.send_raw(PacketType::Disconnect {
+            self.send_control(PacketType::Disconnect {
                 reason: disconnect_reason::REQUESTED,
             });
             self.state = ClientState::Disconnected;
@@ -235,11 +348,37 @@ impl NetClient {
         self.state == ClientState::Connected
     }
 
+    /// The payload the server sent with its connection deny, e.g. a
+    /// maintenance message. Empty unless the server refused this client.
+    pub fn deny_payload(&self) -> &[u8] {
+        &self.deny_payload
+    }
+
+    /// The payload the server admitted this client with, e.g. an assigned
+    /// player id. Empty until connected or if the server sent none.
+    pub fn accept_payload(&self) -> &[u8] {
+        &self.accept_payload
+    }
+
     pub fn channel_stats(&self) -> Vec<crate::stats::ChannelStats> {
         self.connection.channel_stats()
     }
 
+    /// Returns the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the underlying transport mutably.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
     fn handle_packet(&mut self, packet: Packet, events: &mut Vec<ClientEvent>) {
+        let Some(packet) = self.connection.open_packet(packet) else {
+            self.connection.stats.packets_rejected += 1;
+            return;
+        };
         match (&self.state, packet.packet_type) {
             (
                 ClientState::Connecting,
@@ -249,24 +388,41 @@ impl NetClient {
                 },
             ) => {
                 // Echo the cookie back in a ConnectionRequestWithCookie
-                self.send_raw(PacketType::ConnectionRequestWithCookie {
+                self.send_connection_request(PacketType::ConnectionRequestWithCookie {
                     cookie_high,
                     cookie_low,
                 });
             }
             (ClientState::Connecting, PacketType::ConnectionChallenge { server_salt }) => {
                 self.connection.set_server_salt(server_salt);
+                if !self.connection.begin_key_exchange(&packet.payload) {
+                    log::debug!("Ignoring challenge without a valid key share");
+                    return;
+                }
+                // Derive the session now so the connect data can be sealed.
+                let key_share = self.connection.key_share_payload();
+                if !self.connection.establish_session(SessionRole::Client) {
+                    log::warn!("Session key exchange failed");
+                    return;
+                }
+                let Some(sealed) = self.connection.seal_handshake_data(&self.connect_data) else {
+                    log::warn!("Failed to seal connect data");
+                    return;
+                };
+                self.response_payload = [key_share, sealed].concat();
                 self.state = ClientState::ChallengeResponse;
-                self.send_raw(PacketType::ConnectionResponse {
-                    client_salt: self.connection.client_salt(),
-                });
+                self.send_connection_response();
             }
             (ClientState::ChallengeResponse, PacketType::ConnectionChallenge { .. }) => {
-                self.send_raw(PacketType::ConnectionResponse {
-                    client_salt: self.connection.client_salt(),
-                });
+                self.send_connection_response();
             }
             (ClientState::ChallengeResponse, PacketType::ConnectionAccept) => {
+                let Some(accept_payload) = self.connection.open_handshake_data(&packet.payload)
+                else {
+                    log::debug!("Ignoring accept with an invalid payload");
+                    return;
+                };
+                self.accept_payload = accept_payload;
                 self.state = ClientState::Connected;
                 self.connection.set_state(ConnectionState::Connected);
                 self.connection.touch_recv_time();
@@ -280,7 +436,13 @@ impl NetClient {
                 PacketType::ConnectionDeny { reason },
             ) => {
                 self.state = ClientState::Disconnected;
-                events.push(ClientEvent::Disconnected(DisconnectReason::Unknown(reason)));
+                self.deny_payload = packet.payload;
+                let reason = match reason {
+                    deny_reason::VERSION_MISMATCH => DisconnectReason::VersionMismatch,
+                    deny_reason::BANNED => DisconnectReason::Banned,
+                    _ => DisconnectReason::Unknown(reason),
+                };
+                events.push(ClientEvent::Disconnected(reason));
             }
             (ClientState::Connected, PacketType::Disconnect { reason }) => {
                 self.state = ClientState::Disconnected;
@@ -317,10 +479,16 @@ impl NetClient {
                         .receive_payload_direct(channel, packet.payload);
                 }
             }
+            (ClientState::Connected, PacketType::MultiPayload) => {
+                self.connection.touch_recv_time();
+                self.connection.process_incoming_header(&packet.header);
+                self.connection.receive_multi_payload(&packet.payload);
+            }
             (ClientState::Connected, PacketType::MtuProbe { probe_size }) => {
                 self.connection.touch_recv_time();
                 self.connection.process_incoming_header(&packet.header);
-                self.send_raw(PacketType::MtuProbeAck { probe_size });
+                self.connection
+                    .queue_control(PacketType::MtuProbeAck { probe_size });
             }
             (ClientState::Connected, PacketType::MtuProbeAck { probe_size }) => {
                 self.connection.touch_recv_time();
@@ -332,14 +500,13 @@ impl NetClient {
             (ClientState::Connected, PacketType::BatchedPayload { channel }) => {
                 self.connection.touch_recv_time();
                 self.connection.process_incoming_header(&packet.header);
-                if let Some(messages) = congestion::unbatch_messages(&packet.payload) {
-                    for msg in messages {
-                        self.connection.receive_payload_direct(channel, msg);
-                    }
-                }
+                self.connection
+                    .receive_batched_payload(channel, &packet.payload);
             }
             (ClientState::Connected, PacketType::KeepAlive)
-            | (ClientState::Connected, PacketType::AckOnly) => {
+            | (ClientState::Connected, PacketType::AckOnly)
+            | (ClientState::Connected, PacketType::RekeyRequest { .. })
+            | (ClientState::Connected, PacketType::RekeyResponse { .. }) => {
                 self.connection.touch_recv_time();
                 self.connection.process_incoming_header(&packet.header);
             }
@@ -351,18 +518,64 @@ impl NetClient {
         }
     }
 
-    fn send_raw(&mut self, packet_type: PacketType) {
-        wire::send_raw_packet(
-            &mut self.socket,
-            self.server_addr,
-            self.connection.config().protocol_id,
-            0,
-            packet_type,
-        );
+    fn send_connection_response(&mut self) {
+        let response = PacketType::ConnectionResponse {
+            client_salt: self.connection.client_salt(),
+        };
+        let payload = wire::pad_handshake_payload(&response, &self.response_payload);
+        self.send_raw_with_payload(response, payload);
+    }
+
+    /// Send a post-handshake control packet through the connection, sealed
+    /// once the session is encrypted.
+    fn send_control(&mut self, packet_type: PacketType) {
+        self.connection.queue_control(packet_type);
+        self.send_queued();
+    }
+
+    /// Send everything queued on the connection.
+    fn send_queued(&mut self) {
+        for data in self.connection.drain_datagrams() {
+            self.link.send(data, self.server_addr);
+        }
+        self.flush();
+    }
+
+    /// Send a connection request, carrying the connect token if one was given.
+    fn send_connection_request(&mut self, packet_type: PacketType) {
+        let token = self.connect_token.as_deref().unwrap_or_default();
+        let payload = wire::pad_handshake_payload(&packet_type, token);
+        self.send_raw_with_payload(packet_type, payload);
+    }
+
+    fn send_raw_with_payload(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let protocol_id = self.connection.config().protocol_id;
+        if let Some(data) =
+            wire::encode_raw_packet_with_payload(protocol_id, 0, packet_type, payload)
+        {
+            self.link.send(data, self.server_addr);
+            self.flush();
+        }
+    }
+
+    /// Write all queued datagrams, batching where the transport supports it.
+    fn flush(&mut self) {
+        for (data, _) in self.link.flush(&mut self.transport) {
+            self.connection.record_send_failure(&data);
+        }
+    }
+}
+
+fn check_connect_data(data: &[u8]) -> Result<(), SocketError> {
+    if data.len() > MAX_CONNECT_DATA {
+        return Err(SocketError::Other(format!(
+            "connect data exceeds {MAX_CONNECT_DATA} bytes"
+        )));
     }
+    Ok(())
 }
 
-impl Drop for NetClient {
+impl<T: Transport> Drop for NetClient<T> {
     fn drop(&mut self) {
         self.shutdown();
     }
@@ -3,6 +3,7 @@
 //! [`NetworkConfig`] controls all tunable parameters: timeouts, MTU, channels,
 //! congestion, and encryption. [`ChannelConfig`] configures individual channel
 //! delivery modes.
+use std::net::SocketAddr;
 use std::time::Duration;
 
 pub const DEFAULT_PROTOCOL_ID: u32 = 0x12345678;
@@ -31,25 +32,38 @@ pub const DEFAULT_CONGESTION_RECOVERY_TIME_SECS: u64 = 10;
 pub const DEFAULT_DISCONNECT_RETRIES: u32 = 3;
 pub const DEFAULT_DISCONNECT_RETRY_TIMEOUT_MILLIS: u64 = 500;
 pub const DEFAULT_MAX_BANDWIDTH_UNLIMITED: usize = 0;
+pub const DEFAULT_CLIENT_BANDWIDTH_WEIGHT: u32 = 1;
 pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
 pub const DEFAULT_MESSAGE_BUFFER_SIZE: usize = 1024;
 pub const DEFAULT_MAX_PENDING: usize = 256;
 pub const DEFAULT_ORDERED_BUFFER_TIMEOUT_SECS: u64 = 5;
 pub const DEFAULT_MAX_ORDERED_BUFFER_SIZE: usize = 1024;
 pub const DEFAULT_RATE_LIMIT_PER_SECOND: usize = 10;
+pub const DEFAULT_SUBNET_RATE_LIMIT_PER_SECOND: usize = 40;
+pub const DEFAULT_RATE_LIMIT_IPV4_PREFIX: u8 = 24;
+pub const DEFAULT_RATE_LIMIT_IPV6_PREFIX: u8 = 64;
+pub const DEFAULT_HANDSHAKE_RATE_LIMIT_PER_SECOND: usize = 1000;
 pub const DEFAULT_COOKIE_WINDOW_SECS: u64 = 5;
+pub const DEFAULT_COOKIE_SECRET_ROTATION_SECS: u64 = 300;
+/// Packets per connection, or messages per channel, that
+/// [`FloodPolicy::Throttle`] holds for later ticks before dropping the excess.
+pub const MAX_THROTTLED_BACKLOG: usize = 256;
 pub const DEFAULT_DELTA_BASELINE_TIMEOUT_SECS: u64 = 2;
 pub const DEFAULT_MAX_BASELINE_SNAPSHOTS: usize = 32;
 pub const DEFAULT_MAX_IN_FLIGHT: usize = 256;
 pub const DEFAULT_MAX_TRACKED_TOKENS: usize = 4096;
 pub const DEFAULT_CHANNEL_PRIORITY: u8 = 128;
+/// Packets sealed under one set of session keys before a rekey.
+pub const DEFAULT_REKEY_AFTER_PACKETS: u64 = 1 << 24;
+pub const DEFAULT_REKEY_INTERVAL_SECS: u64 = 3600;
 
 /// Maximum exponential backoff exponent for retransmission (caps at 2^5 = 32x RTO).
 pub const MAX_BACKOFF_EXPONENT: u32 = 5;
 
 pub const MIN_MTU: usize = 576;
 pub const MAX_MTU: usize = 65535;
-pub const MAX_CHANNEL_COUNT: usize = 256;
+/// Channels addressable by the wire channel id.
+pub const MAX_CHANNEL_COUNT: usize = 1 << crate::packet::CHANNEL_ID_BITS;
 
 /// The 5 LiteNetLib-style delivery modes.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -107,6 +121,24 @@ pub enum ConfigError {
     InvalidFragmentThreshold,
     SendRateExceedsMaxPacketRate,
     InvalidCongestionThreshold,
+    InvalidSimulation,
+    InvalidVersionPolicy,
+    /// A connect token key was set but the `encryption` feature is disabled.
+    ConnectTokensRequireEncryption,
+    /// Authenticated or encrypted packets were selected but the `encryption`
+    /// feature is disabled.
+    ProtectionRequiresFeature,
+    /// A pre-shared, identity or pinned key was set with only checksum
+    /// protection.
+    KeyRequiresProtection,
+    /// The cookie window is under a second or longer than the cookie secret
+    /// rotation interval.
+    InvalidCookieTiming,
+    /// A shared cookie secret was set but the `encryption` feature is
+    /// disabled.
+    CookieSecretRequiresEncryption,
+    /// A rate limit subnet prefix is longer than its address family.
+    InvalidRateLimitPrefix,
 }
 
 impl std::fmt::Display for ConfigError {
@@ -151,16 +183,120 @@ impl std::fmt::Display for ConfigError {
             ConfigError::InvalidCongestionThreshold => {
                 write!(f, "congestion thresholds must be finite and not NaN")
             }
+            ConfigError::InvalidSimulation => {
+                write!(f, "simulation chances must be within 0.0..=1.0")
+            }
+            ConfigError::InvalidVersionPolicy => {
+                write!(f, "version_policy range must have min <= max")
+            }
+            ConfigError::ConnectTokensRequireEncryption => {
+                write!(f, "connect_token_key requires the encryption feature")
+            }
+            ConfigError::ProtectionRequiresFeature => {
+                write!(
+                    f,
+                    "authenticated or encrypted packets require the encryption feature"
+                )
+            }
+            ConfigError::KeyRequiresProtection => {
+                write!(
+                    f,
+                    "encryption_key, server_identity_key and pinned_server_key require authenticated or encrypted packets"
+                )
+            }
+            ConfigError::InvalidCookieTiming => {
+                write!(
+                    f,
+                    "cookie_window must be >= 1s and <= cookie_secret_rotation"
+                )
+            }
+            ConfigError::CookieSecretRequiresEncryption => {
+                write!(f, "cookie_secret requires the encryption feature")
+            }
+            ConfigError::InvalidRateLimitPrefix => {
+                write!(
+                    f,
+                    "rate_limit_ipv4_prefix must be <= 32 and rate_limit_ipv6_prefix <= 128"
+                )
+            }
         }
     }
 }
 
 impl std::error::Error for ConfigError {}
 
+/// How packets after the handshake are protected on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PacketProtection {
+    /// CRC32C only: catches corruption but not spoofing or tampering.
+    #[default]
+    Checksum,
+    /// Payloads stay readable; each packet carries a truncated HMAC-SHA256
+    /// tag keyed from the session secret.
+    Authenticated,
+    /// Packets are sealed with AES-256-GCM.
+    Encrypted,
+}
+
+impl PacketProtection {
+    /// Returns `true` if this mode needs a session key exchange.
+    pub fn uses_session_keys(&self) -> bool {
+        !matches!(self, PacketProtection::Checksum)
+    }
+}
+
+/// What a server does with a connected client that exceeds its inbound
+/// limits ([`NetworkConfig::max_inbound_packets_per_sec`] and friends).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FloodPolicy {
+    /// Discard the traffic over the limit.
+    #[default]
+    Drop,
+    /// Hold packets and messages over the limit for later ticks, up to
+    /// [`MAX_THROTTLED_BACKLOG`], and discard the rest. Fragments that would
+    /// open a reassembly buffer over the limit are discarded.
+    Throttle,
+    /// Disconnect the client with `DisconnectReason::Flooding`.
+    Kick,
+}
+
+/// Which client application versions a server accepts. Clients send their
+/// [`NetworkConfig::app_version`] in the connection request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VersionPolicy {
+    /// Client version must equal the server's `app_version`.
+    #[default]
+    Exact,
+    /// Client version must be at least the given version.
+    AtLeast(u32),
+    /// Client version must lie within `min..=max`.
+    Range { min: u32, max: u32 },
+    /// Any client version is accepted.
+    Any,
+}
+
+impl VersionPolicy {
+    /// Returns `true` if a client at `client_version` may connect to a server
+    /// at `server_version`.
+    pub fn accepts(&self, server_version: u32, client_version: u32) -> bool {
+        match *self {
+            VersionPolicy::Exact => client_version == server_version,
+            VersionPolicy::AtLeast(min) => client_version >= min,
+            VersionPolicy::Range { min, max } => (min..=max).contains(&client_version),
+            VersionPolicy::Any => true,
+        }
+    }
+}
+
 /// Top-level network configuration for both client and server.
 #[derive(Debug, Clone)]
 pub struct NetworkConfig {
     pub protocol_id: u32,
+    /// Application build version sent by clients during the handshake.
+    pub app_version: u32,
+    /// Client versions a server accepts; mismatches are denied with
+    /// `deny_reason::VERSION_MISMATCH`.
+    pub version_policy: VersionPolicy,
     pub max_clients: usize,
 
     pub connection_timeout: Duration,
@@ -189,30 +325,111 @@ pub struct NetworkConfig {
     pub max_packet_rate: f32,
     pub congestion_threshold: f32,
 
-    pub encryption: bool,
+    /// Protection for every packet after the handshake. Authenticated and
+    /// encrypted modes key each connection from an ephemeral X25519 exchange
+    /// in the challenge and response.
+    pub protection: PacketProtection,
+    /// Optional pre-shared key mixed into every session's key derivation;
+    /// peers without it cannot complete the exchange.
     pub encryption_key: Option<[u8; 32]>,
+    /// Ed25519 seed of the server's long-term identity, which signs the
+    /// server's key share in each challenge.
+    pub server_identity_key: Option<[u8; 32]>,
+    /// Server identity public key a client requires key shares to be signed
+    /// by. Challenges without a valid signature are ignored.
+    pub pinned_server_key: Option<[u8; 32]>,
+    /// Rekey an encrypted session after sealing this many packets under the
+    /// same keys; 0 disables the packet limit.
+    pub rekey_after_packets: u64,
+    /// Rekey an encrypted session once its keys are this old; zero disables
+    /// the time limit.
+    pub rekey_interval: Duration,
     pub max_tracked_tokens: usize,
+    /// Private key shared with the token backend. When set, the server only
+    /// accepts clients presenting a valid [`crate::ConnectToken`].
+    pub connect_token_key: Option<[u8; 32]>,
 
     pub congestion_good_rtt_threshold: f32,
     pub congestion_bad_loss_threshold: f32,
     pub congestion_recovery_time: Duration,
 
+    /// Conditions applied to outgoing datagrams by `NetServer`/`NetClient`.
     pub simulation: Option<SimulationConfig>,
+    /// Conditions applied to incoming datagrams before they are processed.
+    pub incoming_simulation: Option<SimulationConfig>,
 
     pub disconnect_retries: u32,
     pub disconnect_retry_timeout: Duration,
 
+    /// Per-connection cap on outgoing bytes per second; 0 means unlimited.
+    /// Traffic over the cap waits in its channel, lowest priority first.
     pub max_bandwidth_bytes_per_sec: usize,
+    /// Server-wide cap on outgoing bytes per second, shared among clients in
+    /// proportion to their weights (`NetServer::set_client_weight`); 0 means
+    /// unlimited.
+    pub server_bandwidth_bytes_per_sec: usize,
+    /// Per-connection cap on packets a server accepts per second once the
+    /// client is connected; 0 means unlimited.
+    pub max_inbound_packets_per_sec: usize,
+    /// Per-connection cap on bytes a server accepts per second once the
+    /// client is connected; 0 means unlimited.
+    pub max_inbound_bytes_per_sec: usize,
+    /// Messages a server delivers per channel per connection each tick; 0
+    /// means unlimited.
+    pub max_messages_per_channel_per_tick: usize,
+    /// Fragmented messages a connection reassembles at once; 0 means
+    /// unlimited.
+    pub max_reassembly_buffers: usize,
+    /// What a server does with a client that exceeds the inbound limits.
+    pub flood_policy: FloodPolicy,
     pub max_pending: usize,
+    /// Handshake requests a server handles per second from one IP.
     pub rate_limit_per_second: usize,
+    /// Handshake requests a server handles per second from one subnet, sized
+    /// by the prefixes below; 0 means unlimited.
+    pub subnet_rate_limit_per_second: usize,
+    /// Prefix length of the IPv4 subnets charged by
+    /// `subnet_rate_limit_per_second`.
+    pub rate_limit_ipv4_prefix: u8,
+    /// Prefix length of the IPv6 subnets charged by
+    /// `subnet_rate_limit_per_second`.
+    pub rate_limit_ipv6_prefix: u8,
+    /// Handshake requests a server handles per second from all sources
+    /// together; 0 means unlimited.
+    pub handshake_rate_limit_per_second: usize,
 
     pub enable_stateless_cookie: bool,
+    /// How long a handshake cookie stays valid; cookies from the previous
+    /// window are accepted too.
+    pub cookie_window: Duration,
+    /// Interval at which the server switches to a new cookie secret. The
+    /// previous secret is still accepted for one cookie window.
+    pub cookie_secret_rotation: Duration,
+    /// Secret shared by server processes behind one address, from which each
+    /// derives the same rotating cookie secrets so any of them accepts
+    /// cookies issued by another. When unset each server draws its own from
+    /// the OS CSPRNG.
+    pub cookie_secret: Option<[u8; 32]>,
+    /// Hold clients that complete the handshake until the application
+    /// answers their `ServerEvent::ConnectionRequest` with
+    /// `NetServer::accept_connection` or `NetServer::deny_connection`.
+    /// When disabled every client that completes the handshake is accepted.
+    pub manual_admission: bool,
     pub use_cwnd_congestion: bool,
+    /// Pack small messages sent in the same tick into shared packets
+    /// (`BatchedPayload` for one channel, `MultiPayload` across channels).
+    /// When disabled every message travels in its own packet.
+    pub enable_batching: bool,
 
     pub delta_baseline_timeout: Duration,
     pub max_baseline_snapshots: usize,
 
     pub enable_connection_migration: bool,
+
+    /// Local address `NetClient::connect` binds to. Defaults to the
+    /// unspecified address of the server's family; an address of the other
+    /// family is rejected at connect, except `[::]`, which binds dual-stack.
+    pub local_bind_addr: Option<SocketAddr>,
 }
 
 fn is_valid_positive_f32(v: f32) -> bool {
@@ -268,6 +485,42 @@ impl NetworkConfig {
         {
             return Err(ConfigError::InvalidCongestionThreshold);
         }
+        for sim in [&self.simulation, &self.incoming_simulation]
+            .into_iter()
+            .flatten()
+        {
+            if !sim.is_valid() {
+                return Err(ConfigError::InvalidSimulation);
+            }
+        }
+        if let VersionPolicy::Range { min, max } = self.version_policy {
+            if min > max {
+                return Err(ConfigError::InvalidVersionPolicy);
+            }
+        }
+        if self.connect_token_key.is_some() && !cfg!(feature = "encryption") {
+            return Err(ConfigError::ConnectTokensRequireEncryption);
+        }
+        let has_keys = self.encryption_key.is_some()
+            || self.server_identity_key.is_some()
+            || self.pinned_server_key.is_some();
+        if has_keys && !self.protection.uses_session_keys() {
+            return Err(ConfigError::KeyRequiresProtection);
+        }
+        if self.protection.uses_session_keys() && !cfg!(feature = "encryption") {
+            return Err(ConfigError::ProtectionRequiresFeature);
+        }
+        if self.cookie_window < Duration::from_secs(1)
+            || self.cookie_window > self.cookie_secret_rotation
+        {
+            return Err(ConfigError::InvalidCookieTiming);
+        }
+        if self.cookie_secret.is_some() && !cfg!(feature = "encryption") {
+            return Err(ConfigError::CookieSecretRequiresEncryption);
+        }
+        if self.rate_limit_ipv4_prefix > 32 || self.rate_limit_ipv6_prefix > 128 {
+            return Err(ConfigError::InvalidRateLimitPrefix);
+        }
         Ok(())
     }
 }
@@ -277,6 +530,14 @@ impl NetworkConfig {
         self.protocol_id = id;
         self
     }
+    pub fn with_app_version(mut self, version: u32) -> Self {
+        self.app_version = version;
+        self
+    }
+    pub fn with_version_policy(mut self, policy: VersionPolicy) -> Self {
+        self.version_policy = policy;
+        self
+    }
     pub fn with_max_clients(mut self, max: usize) -> Self {
         self.max_clients = max;
         self
@@ -301,11 +562,47 @@ impl NetworkConfig {
         self.send_rate = rate;
         self
     }
+    pub fn with_encryption(mut self) -> Self {
+        self.protection = PacketProtection::Encrypted;
+        self
+    }
+    pub fn with_authentication(mut self) -> Self {
+        self.protection = PacketProtection::Authenticated;
+        self
+    }
     pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
-        self.encryption = true;
+        if !self.protection.uses_session_keys() {
+            self.protection = PacketProtection::Encrypted;
+        }
         self.encryption_key = Some(key);
         self
     }
+    pub fn with_server_identity_key(mut self, seed: [u8; 32]) -> Self {
+        if !self.protection.uses_session_keys() {
+            self.protection = PacketProtection::Encrypted;
+        }
+        self.server_identity_key = Some(seed);
+        self
+    }
+    pub fn with_pinned_server_key(mut self, public_key: [u8; 32]) -> Self {
+        if !self.protection.uses_session_keys() {
+            self.protection = PacketProtection::Encrypted;
+        }
+        self.pinned_server_key = Some(public_key);
+        self
+    }
+    pub fn with_rekey_after_packets(mut self, packets: u64) -> Self {
+        self.rekey_after_packets = packets;
+        self
+    }
+    pub fn with_rekey_interval(mut self, interval: Duration) -> Self {
+        self.rekey_interval = interval;
+        self
+    }
+    pub fn with_connect_token_key(mut self, key: [u8; 32]) -> Self {
+        self.connect_token_key = Some(key);
+        self
+    }
     pub fn with_channel_config(mut self, index: usize, config: ChannelConfig) -> Self {
         if index >= self.channel_configs.len() {
             self.channel_configs
@@ -318,16 +615,88 @@ impl NetworkConfig {
         self.rate_limit_per_second = per_second;
         self
     }
+    pub fn with_subnet_rate_limit(mut self, per_second: usize) -> Self {
+        self.subnet_rate_limit_per_second = per_second;
+        self
+    }
+    pub fn with_rate_limit_prefixes(mut self, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        self.rate_limit_ipv4_prefix = ipv4_prefix;
+        self.rate_limit_ipv6_prefix = ipv6_prefix;
+        self
+    }
+    pub fn with_handshake_rate_limit(mut self, per_second: usize) -> Self {
+        self.handshake_rate_limit_per_second = per_second;
+        self
+    }
     pub fn with_max_in_flight(mut self, max: usize) -> Self {
         self.max_in_flight = max;
         self
     }
+    pub fn with_simulation(mut self, sim: SimulationConfig) -> Self {
+        self.simulation = Some(sim);
+        self
+    }
+    pub fn with_incoming_simulation(mut self, sim: SimulationConfig) -> Self {
+        self.incoming_simulation = Some(sim);
+        self
+    }
+    pub fn with_local_bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_bind_addr = Some(addr);
+        self
+    }
+    pub fn with_max_bandwidth(mut self, bytes_per_sec: usize) -> Self {
+        self.max_bandwidth_bytes_per_sec = bytes_per_sec;
+        self
+    }
+    pub fn with_server_bandwidth(mut self, bytes_per_sec: usize) -> Self {
+        self.server_bandwidth_bytes_per_sec = bytes_per_sec;
+        self
+    }
+    pub fn with_batching(mut self, enabled: bool) -> Self {
+        self.enable_batching = enabled;
+        self
+    }
+    pub fn with_manual_admission(mut self, enabled: bool) -> Self {
+        self.manual_admission = enabled;
+        self
+    }
+    pub fn with_inbound_limits(mut self, packets_per_sec: usize, bytes_per_sec: usize) -> Self {
+        self.max_inbound_packets_per_sec = packets_per_sec;
+        self.max_inbound_bytes_per_sec = bytes_per_sec;
+        self
+    }
+    pub fn with_max_messages_per_channel_per_tick(mut self, max: usize) -> Self {
+        self.max_messages_per_channel_per_tick = max;
+        self
+    }
+    pub fn with_max_reassembly_buffers(mut self, max: usize) -> Self {
+        self.max_reassembly_buffers = max;
+        self
+    }
+    pub fn with_flood_policy(mut self, policy: FloodPolicy) -> Self {
+        self.flood_policy = policy;
+        self
+    }
+    pub fn with_cookie_window(mut self, window: Duration) -> Self {
+        self.cookie_window = window;
+        self
+    }
+    pub fn with_cookie_secret_rotation(mut self, interval: Duration) -> Self {
+        self.cookie_secret_rotation = interval;
+        self
+    }
+    pub fn with_cookie_secret(mut self, secret: [u8; 32]) -> Self {
+        self.cookie_secret = Some(secret);
+        self
+    }
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             protocol_id: DEFAULT_PROTOCOL_ID,
+            app_version: 0,
+            version_policy: VersionPolicy::Exact,
             max_clients: DEFAULT_MAX_CLIENTS,
 
             connection_timeout: Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT_SECS),
@@ -358,15 +727,21 @@ impl Default for NetworkConfig {
             max_packet_rate: DEFAULT_MAX_PACKET_RATE_HZ,
             congestion_threshold: DEFAULT_CONGESTION_THRESHOLD,
 
-            encryption: false,
+            protection: PacketProtection::Checksum,
             encryption_key: None,
+            server_identity_key: None,
+            pinned_server_key: None,
+            rekey_after_packets: DEFAULT_REKEY_AFTER_PACKETS,
+            rekey_interval: Duration::from_secs(DEFAULT_REKEY_INTERVAL_SECS),
             max_tracked_tokens: DEFAULT_MAX_TRACKED_TOKENS,
+            connect_token_key: None,
 
             congestion_good_rtt_threshold: DEFAULT_CONGESTION_GOOD_RTT_THRESHOLD_MS,
             congestion_bad_loss_threshold: DEFAULT_CONGESTION_BAD_LOSS_THRESHOLD,
             congestion_recovery_time: Duration::from_secs(DEFAULT_CONGESTION_RECOVERY_TIME_SECS),
 
             simulation: None,
+            incoming_simulation: None,
 
             disconnect_retries: DEFAULT_DISCONNECT_RETRIES,
             disconnect_retry_timeout: Duration::from_millis(
@@ -374,18 +749,35 @@ impl Default for NetworkConfig {
             ),
 
             max_bandwidth_bytes_per_sec: DEFAULT_MAX_BANDWIDTH_UNLIMITED,
+            server_bandwidth_bytes_per_sec: DEFAULT_MAX_BANDWIDTH_UNLIMITED,
+            max_inbound_packets_per_sec: 0,
+            max_inbound_bytes_per_sec: 0,
+            max_messages_per_channel_per_tick: 0,
+            max_reassembly_buffers: 0,
+            flood_policy: FloodPolicy::Drop,
 
             max_pending: DEFAULT_MAX_PENDING,
 
             rate_limit_per_second: DEFAULT_RATE_LIMIT_PER_SECOND,
+            subnet_rate_limit_per_second: DEFAULT_SUBNET_RATE_LIMIT_PER_SECOND,
+            rate_limit_ipv4_prefix: DEFAULT_RATE_LIMIT_IPV4_PREFIX,
+            rate_limit_ipv6_prefix: DEFAULT_RATE_LIMIT_IPV6_PREFIX,
+            handshake_rate_limit_per_second: DEFAULT_HANDSHAKE_RATE_LIMIT_PER_SECOND,
 
             enable_stateless_cookie: true,
+            cookie_window: Duration::from_secs(DEFAULT_COOKIE_WINDOW_SECS),
+            cookie_secret_rotation: Duration::from_secs(DEFAULT_COOKIE_SECRET_ROTATION_SECS),
+            cookie_secret: None,
+            manual_admission: false,
             use_cwnd_congestion: false,
+            enable_batching: true,
 
             delta_baseline_timeout: Duration::from_secs(DEFAULT_DELTA_BASELINE_TIMEOUT_SECS),
             max_baseline_snapshots: DEFAULT_MAX_BASELINE_SNAPSHOTS,
 
             enable_connection_migration: false,
+
+            local_bind_addr: None,
         }
     }
 }
@@ -475,6 +867,47 @@ pub struct SimulationConfig {
     pub duplicate_chance: f32,
     pub out_of_order_chance: f32,
     pub bandwidth_limit_bytes_per_sec: usize,
+    /// Seed for the simulator's RNG. `None` seeds from system entropy.
+    pub seed: Option<u64>,
+    /// Timed condition changes, relative to when the simulator is created.
+    pub script: Vec<ScheduledCondition>,
+}
+
+impl SimulationConfig {
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Apply `condition` for `duration` starting `start` after creation.
+    pub fn with_condition(
+        mut self,
+        start: Duration,
+        duration: Duration,
+        condition: NetworkCondition,
+    ) -> Self {
+        self.script.push(ScheduledCondition {
+            start,
+            duration,
+            condition,
+        });
+        self
+    }
+
+    fn is_valid(&self) -> bool {
+        let burst_losses = self.script.iter().filter_map(|s| match s.condition {
+            NetworkCondition::LossBurst(p) => Some(p),
+            _ => None,
+        });
+        [
+            self.packet_loss,
+            self.duplicate_chance,
+            self.out_of_order_chance,
+        ]
+        .into_iter()
+        .chain(burst_losses)
+        .all(|c| (0.0..=1.0).contains(&c))
+    }
 }
 
 impl Default for SimulationConfig {
@@ -486,6 +919,35 @@ impl Default for SimulationConfig {
             duplicate_chance: 0.0,
             out_of_order_chance: 0.0,
             bandwidth_limit_bytes_per_sec: 0,
+            seed: None,
+            script: Vec::new(),
         }
     }
 }
+
+/// A temporary change in simulated network conditions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkCondition {
+    /// Raise packet loss to at least this probability.
+    LossBurst(f32),
+    /// Add this many milliseconds of latency to every packet.
+    LatencySpike(u32),
+    /// Drop every packet.
+    Outage,
+    /// Replace the bandwidth limit (bytes/sec, 0 = unlimited).
+    Bandwidth(usize),
+}
+
+/// A [`NetworkCondition`] active from `start` for `duration`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduledCondition {
+    pub start: Duration,
+    pub duration: Duration,
+    pub condition: NetworkCondition,
+}
+
+impl ScheduledCondition {
+    pub fn is_active(&self, elapsed: Duration) -> bool {
+        elapsed >= self.start && elapsed < self.start + self.duration
+    }
+}
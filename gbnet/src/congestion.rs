@@ -1,13 +1,17 @@
 //! Binary congestion control (Gaffer-style Good/Bad modes), byte-budget gating,
-//! adaptive recovery timer, message batching, and bandwidth tracking.
+//! adaptive recovery timer, message batching, and bandwidth tracking and limiting.
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
+use crate::clock::{system_clock, SharedClock};
+
 pub const CONGESTION_RATE_REDUCTION: f32 = 0.5;
 pub const MIN_SEND_RATE: f32 = 1.0;
 pub const BATCH_HEADER_SIZE: usize = 1;
 pub const BATCH_LENGTH_SIZE: usize = 2;
 pub const MAX_BATCH_MESSAGES: u8 = 255;
+/// Per-message overhead in a multi-channel payload: channel id and u16 length.
+pub const MULTI_ENTRY_OVERHEAD: usize = 1 + BATCH_LENGTH_SIZE;
 
 pub const INITIAL_CWND_PACKETS: usize = 10;
 pub const MIN_CWND_BYTES: usize = 1200;
@@ -17,6 +21,9 @@ pub const MAX_RECOVERY_SECS: f64 = 60.0;
 pub const RECOVERY_HALVE_INTERVAL_SECS: f64 = 10.0;
 pub const QUICK_DROP_THRESHOLD_SECS: f64 = 10.0;
 
+/// Seconds of traffic a [`BandwidthLimiter`] may burst after sitting idle.
+pub const BANDWIDTH_BURST_SECS: f64 = 0.1;
+
 /// Phase for window-based congestion control.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CongestionPhase {
@@ -48,6 +55,8 @@ pub struct CongestionController {
     adaptive_recovery_secs: f64,
     last_good_entry: Option<Instant>,
     last_bad_entry: Option<Instant>,
+
+    clock: SharedClock,
 }
 
 impl CongestionController {
@@ -70,9 +79,16 @@ impl CongestionController {
             adaptive_recovery_secs: recovery_secs,
             last_good_entry: None,
             last_bad_entry: None,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` for mode transitions and the adaptive recovery timer.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Refill the byte budget at the start of each tick.
     pub fn refill_budget(&mut self, mtu: usize) {
         self.bytes_per_tick = (self.current_send_rate * mtu as f32) as usize;
@@ -87,32 +103,34 @@ impl CongestionController {
     /// Update congestion state based on current network conditions.
     pub fn update(&mut self, packet_loss: f32, rtt_ms: f32) {
         let is_bad = packet_loss > self.loss_threshold || rtt_ms > self.rtt_threshold_ms;
+        let now = self.clock.now();
 
         match self.mode {
             CongestionMode::Good => {
                 if is_bad {
                     // Quick re-entry to Bad doubles recovery timer
                     if let Some(good_entry) = self.last_good_entry {
-                        if good_entry.elapsed().as_secs_f64() < QUICK_DROP_THRESHOLD_SECS {
+                        if now.duration_since(good_entry).as_secs_f64() < QUICK_DROP_THRESHOLD_SECS
+                        {
                             self.adaptive_recovery_secs =
                                 (self.adaptive_recovery_secs * 2.0).min(MAX_RECOVERY_SECS);
                         }
                     }
 
                     self.mode = CongestionMode::Bad;
-                    self.last_bad_entry = Some(Instant::now());
+                    self.last_bad_entry = Some(now);
                     self.current_send_rate =
                         (self.base_send_rate * CONGESTION_RATE_REDUCTION).max(MIN_SEND_RATE);
                     self.good_conditions_start = None;
                 } else if let Some(good_entry) = self.last_good_entry {
-                    let elapsed = good_entry.elapsed().as_secs_f64();
+                    let elapsed = now.duration_since(good_entry).as_secs_f64();
                     let intervals = (elapsed / RECOVERY_HALVE_INTERVAL_SECS).floor() as u32;
                     if intervals > 0 {
                         for _ in 0..intervals {
                             self.adaptive_recovery_secs =
                                 (self.adaptive_recovery_secs / 2.0).max(MIN_RECOVERY_SECS);
                         }
-                        self.last_good_entry = Some(Instant::now());
+                        self.last_good_entry = Some(now);
                     }
                 }
             }
@@ -120,13 +138,13 @@ impl CongestionController {
                 if !is_bad {
                     match self.good_conditions_start {
                         None => {
-                            self.good_conditions_start = Some(Instant::now());
+                            self.good_conditions_start = Some(now);
                         }
                         Some(start) => {
                             let required = Duration::from_secs_f64(self.adaptive_recovery_secs);
-                            if start.elapsed() >= required {
+                            if now.duration_since(start) >= required {
                                 self.mode = CongestionMode::Good;
-                                self.last_good_entry = Some(Instant::now());
+                                self.last_good_entry = Some(now);
                                 self.current_send_rate = self.base_send_rate;
                                 self.good_conditions_start = None;
                             }
@@ -170,6 +188,7 @@ pub struct CongestionWindow {
     mtu: usize,
     last_send_time: Option<Instant>,
     min_inter_packet_delay: Duration,
+    clock: SharedClock,
 }
 
 impl CongestionWindow {
@@ -182,9 +201,16 @@ impl CongestionWindow {
             mtu,
             last_send_time: None,
             min_inter_packet_delay: Duration::ZERO,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` to timestamp sends for pacing.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Called when bytes are acknowledged.
     pub fn on_ack(&mut self, bytes: usize) {
         self.bytes_in_flight = self.bytes_in_flight.saturating_sub(bytes as u64);
@@ -225,7 +251,7 @@ impl CongestionWindow {
     /// Record bytes sent.
     pub fn on_send(&mut self, bytes: usize) {
         self.bytes_in_flight += bytes as u64;
-        self.last_send_time = Some(Instant::now());
+        self.last_send_time = Some(self.clock.now());
     }
 
     /// Returns true if a packet of the given size can be sent.
@@ -341,12 +367,60 @@ pub fn unbatch_messages(data: &[u8]) -> Option<Vec<Vec<u8>>> {
     Some(messages)
 }
 
+/// Packs messages from several channels into one payload. Callers keep the
+/// count within [`MAX_BATCH_MESSAGES`] and each message under `u16::MAX` bytes.
+/// Wire format: [u8 message_count]([u8 channel][u16 len][data])...
+pub fn pack_channel_messages(messages: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let size = BATCH_HEADER_SIZE
+        + messages
+            .iter()
+            .map(|(_, msg)| MULTI_ENTRY_OVERHEAD + msg.len())
+            .sum::<usize>();
+    let mut payload = Vec::with_capacity(size);
+    payload.push(messages.len() as u8);
+    for (channel, msg) in messages {
+        payload.push(*channel);
+        payload.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        payload.extend_from_slice(msg);
+    }
+    payload
+}
+
+/// Unpack a multi-channel payload into `(channel, message)` pairs.
+pub fn unpack_channel_messages(data: &[u8]) -> Option<Vec<(u8, Vec<u8>)>> {
+    if data.is_empty() {
+        return None;
+    }
+
+    let msg_count = data[0] as usize;
+    let mut messages = Vec::with_capacity(msg_count);
+    let mut offset = 1;
+
+    for _ in 0..msg_count {
+        if offset + MULTI_ENTRY_OVERHEAD > data.len() {
+            return None;
+        }
+        let channel = data[offset];
+        let len = u16::from_be_bytes([data[offset + 1], data[offset + 2]]) as usize;
+        offset += MULTI_ENTRY_OVERHEAD;
+
+        if offset + len > data.len() {
+            return None;
+        }
+        messages.push((channel, data[offset..offset + len].to_vec()));
+        offset += len;
+    }
+
+    Some(messages)
+}
+
 /// Bandwidth tracker using a sliding window backed by a `VecDeque`
 /// to avoid unbounded growth.
 #[derive(Debug)]
 pub struct BandwidthTracker {
     window: VecDeque<(Instant, usize)>,
     window_duration: Duration,
+    clock: SharedClock,
 }
 
 impl BandwidthTracker {
@@ -354,14 +428,30 @@ impl BandwidthTracker {
         Self {
             window: VecDeque::new(),
             window_duration,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` to timestamp samples in the sliding window.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn record(&mut self, bytes: usize) {
-        self.window.push_back((Instant::now(), bytes));
+        let now = self.clock.now();
+        self.window.push_back((now, bytes));
         self.cleanup();
     }
 
+    /// Withdraw the latest sample of `bytes`, recorded for a send that then
+    /// failed.
+    pub fn unrecord(&mut self, bytes: usize) {
+        if let Some(pos) = self.window.iter().rposition(|&(_, b)| b == bytes) {
+            self.window.remove(pos);
+        }
+    }
+
     pub fn bytes_per_second(&self) -> f64 {
         if self.window.is_empty() {
             return 0.0;
@@ -377,8 +467,9 @@ impl BandwidthTracker {
 
     fn cleanup(&mut self) {
         let cutoff = self.window_duration;
+        let now = self.clock.now();
         while let Some(&(t, _)) = self.window.front() {
-            if t.elapsed() >= cutoff {
+            if now.duration_since(t) >= cutoff {
                 self.window.pop_front();
             } else {
                 break;
@@ -387,9 +478,146 @@ impl BandwidthTracker {
     }
 }
 
+/// Token bucket capping outgoing bytes per second.
+///
+/// Each tick the sender refills the bucket and reserves bytes for the
+/// messages it commits to; the bytes actually written to the wire are then
+/// consumed. The balance may go negative when a send overshoots, in which
+/// case later sends wait until the debt is repaid.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_second: f64,
+    capacity: f64,
+    tokens: f64,
+    reserved: f64,
+    last_refill: Instant,
+    clock: SharedClock,
+}
+
+impl BandwidthLimiter {
+    /// Creates a full bucket refilling at `bytes_per_second` and holding at
+    /// most `capacity` bytes.
+    pub fn new(bytes_per_second: usize, capacity: usize) -> Self {
+        let clock = system_clock();
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            reserved: 0.0,
+            last_refill: clock.now(),
+            clock,
+        }
+    }
+
+    /// Use `clock` to measure refill intervals.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.last_refill = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Add the tokens earned since the last refill and release the previous
+    /// tick's reservations.
+    pub fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_second).min(self.capacity);
+        self.reserved = 0.0;
+    }
+
+    /// Returns true if `bytes` more can be reserved this tick. A full bucket
+    /// admits a single send larger than its capacity so oversized messages
+    /// are not starved.
+    pub fn can_send(&self, bytes: usize) -> bool {
+        let bytes = bytes as f64;
+        self.tokens - self.reserved >= bytes
+            || (self.reserved == 0.0 && self.tokens >= self.capacity)
+    }
+
+    /// Commit `bytes` to this tick's sends.
+    pub fn reserve(&mut self, bytes: usize) {
+        self.reserved += bytes as f64;
+    }
+
+    /// Consume tokens for bytes written to the wire.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// Return tokens consumed for bytes that never reached the wire.
+    pub fn refund(&mut self, bytes: usize) {
+        self.tokens = (self.tokens + bytes as f64).min(self.capacity);
+    }
+
+    /// Bytes that can still be reserved without going into debt.
+    pub fn available(&self) -> usize {
+        (self.tokens - self.reserved).max(0.0) as usize
+    }
+
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes_per_second
+    }
+}
+
+/// Builds a limiter for `bytes_per_second`, or `None` when it is zero
+/// (unlimited). The bucket holds a short burst but always at least one
+/// `mtu`-sized packet.
+pub(crate) fn bandwidth_limiter(bytes_per_second: usize, mtu: usize) -> Option<BandwidthLimiter> {
+    if bytes_per_second == 0 {
+        return None;
+    }
+    let burst = ((bytes_per_second as f64 * BANDWIDTH_BURST_SECS) as usize).max(mtu);
+    Some(BandwidthLimiter::new(bytes_per_second, burst))
+}
+
+/// Builds a limiter admitting `per_second` units of inbound traffic (packets
+/// or bytes), or `None` when it is zero (unlimited). The bucket holds a full
+/// second's allowance so bursts that arrive within one tick are not punished.
+pub(crate) fn inbound_limiter(per_second: usize) -> Option<BandwidthLimiter> {
+    (per_second > 0).then(|| BandwidthLimiter::new(per_second, per_second))
+}
+
+/// Splits `budget` bytes among senders by weighted max-min fairness.
+///
+/// Each entry is `(weight, demand)`. Senders whose demand is below their
+/// weighted share get exactly their demand and the surplus is redistributed
+/// among the rest, so the returned shares never exceed their demands and sum
+/// to at most `budget`. Zero-weight senders receive nothing.
+pub fn weighted_fair_shares(budget: usize, senders: &[(u32, usize)]) -> Vec<usize> {
+    let mut shares = vec![0usize; senders.len()];
+    let mut active: Vec<usize> = (0..senders.len())
+        .filter(|&i| senders[i].0 > 0 && senders[i].1 > 0)
+        .collect();
+    let mut remaining = budget;
+
+    while !active.is_empty() && remaining > 0 {
+        let total_weight: u64 = active.iter().map(|&i| senders[i].0 as u64).sum();
+        let fair_share =
+            |i: usize| (remaining as u128 * senders[i].0 as u128 / total_weight as u128) as usize;
+
+        let (satisfied, unsatisfied): (Vec<usize>, Vec<usize>) = active
+            .iter()
+            .partition(|&&i| senders[i].1 - shares[i] <= fair_share(i));
+        if satisfied.is_empty() {
+            for &i in &unsatisfied {
+                shares[i] += fair_share(i);
+            }
+            break;
+        }
+        for &i in &satisfied {
+            remaining -= senders[i].1 - shares[i];
+            shares[i] = senders[i].1;
+        }
+        active = unsatisfied;
+    }
+    shares
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
 
     #[test]
     fn test_congestion_mode_transition() {
@@ -439,6 +667,21 @@ mod tests {
         assert_eq!(total, 10);
     }
 
+    #[test]
+    fn test_pack_unpack_channel_messages() {
+        let messages = vec![(0u8, vec![1, 2, 3]), (200, vec![]), (7, vec![9; 300])];
+        let packed = pack_channel_messages(&messages);
+        assert_eq!(
+            packed.len(),
+            BATCH_HEADER_SIZE + 3 * MULTI_ENTRY_OVERHEAD + 3 + 300
+        );
+        assert_eq!(unpack_channel_messages(&packed).unwrap(), messages);
+
+        // Truncated payloads are rejected rather than partially decoded.
+        assert!(unpack_channel_messages(&packed[..packed.len() - 1]).is_none());
+        assert!(unpack_channel_messages(&[]).is_none());
+    }
+
     #[test]
     fn test_can_send_respects_rate_and_budget() {
         let mut cc = CongestionController::new(60.0, 0.1, 250.0, Duration::from_secs(10));
@@ -463,6 +706,79 @@ mod tests {
         assert!(tracker.bytes_per_second() > 0.0);
     }
 
+    #[test]
+    fn test_bandwidth_limiter_refills_at_rate() {
+        let clock = ManualClock::new();
+        let mut limiter = BandwidthLimiter::new(10_000, 1_000).with_clock(clock.shared());
+        assert!(limiter.can_send(600));
+        limiter.reserve(600);
+        assert!(limiter.can_send(400));
+        assert!(!limiter.can_send(401));
+
+        limiter.consume(1_000);
+        clock.advance(Duration::from_millis(50));
+        limiter.refill();
+        assert_eq!(limiter.available(), 500);
+        assert!(limiter.can_send(500));
+        assert!(!limiter.can_send(501));
+
+        // Refill never exceeds capacity.
+        clock.advance(Duration::from_secs(5));
+        limiter.refill();
+        assert_eq!(limiter.available(), 1_000);
+    }
+
+    #[test]
+    fn test_bandwidth_limiter_oversized_send_goes_into_debt() {
+        let clock = ManualClock::new();
+        let mut limiter = BandwidthLimiter::new(10_000, 1_000).with_clock(clock.shared());
+        assert!(limiter.can_send(3_000));
+        limiter.reserve(3_000);
+        assert!(!limiter.can_send(3_000));
+        limiter.consume(3_000);
+
+        // The 2000-byte debt is repaid before anything else goes out.
+        clock.advance(Duration::from_millis(250));
+        limiter.refill();
+        assert_eq!(limiter.available(), 500);
+        assert!(!limiter.can_send(3_000));
+    }
+
+    #[test]
+    fn test_bandwidth_limiter_refund_restores_tokens() {
+        let clock = ManualClock::new();
+        let mut limiter = BandwidthLimiter::new(10_000, 1_000).with_clock(clock.shared());
+        limiter.consume(800);
+        limiter.refund(800);
+        assert_eq!(limiter.available(), 1_000);
+
+        // Refunds never exceed capacity.
+        limiter.refund(500);
+        assert_eq!(limiter.available(), 1_000);
+    }
+
+    #[test]
+    fn test_weighted_fair_shares_splits_by_weight() {
+        let shares = weighted_fair_shares(3_000, &[(1, 10_000), (2, 10_000)]);
+        assert_eq!(shares, vec![1_000, 2_000]);
+    }
+
+    #[test]
+    fn test_weighted_fair_shares_redistributes_surplus() {
+        // The light sender only needs 100 bytes; the rest goes to the others.
+        let shares = weighted_fair_shares(3_000, &[(1, 100), (1, 10_000), (1, 10_000)]);
+        assert_eq!(shares, vec![100, 1_450, 1_450]);
+
+        // Everyone satisfied leaves budget unspent.
+        let shares = weighted_fair_shares(3_000, &[(1, 500), (5, 700)]);
+        assert_eq!(shares, vec![500, 700]);
+
+        // Idle and zero-weight senders get nothing.
+        let shares = weighted_fair_shares(1_000, &[(0, 5_000), (1, 0), (1, 5_000)]);
+        assert_eq!(shares, vec![0, 0, 1_000]);
+        assert_eq!(weighted_fair_shares(0, &[(1, 100)]), vec![0]);
+    }
+
     #[test]
     fn test_byte_budget_depletes_and_replenishes() {
         let mut cc = CongestionController::new(60.0, 0.1, 250.0, Duration::from_secs(10));
@@ -1,6 +1,5 @@
-use std::time::Instant;
-
-use crate::packet::{disconnect_reason, Packet, PacketHeader, PacketType};
+use crate::packet::{deny_reason, disconnect_reason, Packet, PacketHeader, PacketType};
+use crate::security::SessionRole;
 
 use super::{Connection, ConnectionError, ConnectionState};
 
@@ -11,7 +10,7 @@ impl Connection {
         }
 
         self.state = ConnectionState::Connecting;
-        self.connection_request_time = Some(Instant::now());
+        self.connection_request_time = Some(self.clock.now());
         self.connection_retry_count = 0;
         self.send_connection_request()?;
         Ok(())
@@ -26,7 +25,7 @@ impl Connection {
         let packet = Packet::new(header, PacketType::Disconnect { reason });
         self.send_queue.push_back(packet);
         self.state = ConnectionState::Disconnecting;
-        self.disconnect_time = Some(Instant::now());
+        self.disconnect_time = Some(self.clock.now());
         self.disconnect_retry_count = 0;
         Ok(())
     }
@@ -38,8 +37,10 @@ impl Connection {
             ack: 0,
             ack_bits: 0,
         };
-        let packet = Packet::new(header, PacketType::ConnectionRequest);
-        self.send_queue.push_back(packet);
+        let request = crate::wire::connection_request(&self.config);
+        let payload = crate::wire::pad_handshake_payload(&request, &[]);
+        self.send_queue
+            .push_back(Packet::new(header, request).with_payload(payload));
         Ok(())
     }
 
@@ -47,22 +48,29 @@ impl Connection {
         match (&self.state, &packet.packet_type) {
             (ConnectionState::Connecting, PacketType::ConnectionChallenge { server_salt }) => {
                 self.server_salt = *server_salt;
+                if !self.begin_key_exchange(&packet.payload) {
+                    return Ok(());
+                }
                 self.state = ConnectionState::ChallengeResponse;
 
                 let header = self.create_header();
-                let response = Packet::new(
-                    header,
-                    PacketType::ConnectionResponse {
-                        client_salt: self.client_salt,
-                    },
-                );
-                self.send_queue.push_back(response);
+                let response = PacketType::ConnectionResponse {
+                    client_salt: self.client_salt,
+                };
+                let payload =
+                    crate::wire::pad_handshake_payload(&response, &self.key_share_payload());
+                self.send_queue
+                    .push_back(Packet::new(header, response).with_payload(payload));
             }
 
             (ConnectionState::ChallengeResponse, PacketType::ConnectionAccept) => {
+                if !self.establish_session(SessionRole::Client) {
+                    return Ok(());
+                }
                 self.state = ConnectionState::Connected;
-                self.connection_start_time = Some(Instant::now());
-                self.last_packet_recv_time = Instant::now();
+                let now = self.clock.now();
+                self.connection_start_time = Some(now);
+                self.last_packet_recv_time = now;
                 self.local_sequence = 0;
                 self.remote_sequence = 0;
             }
@@ -72,6 +80,9 @@ impl Connection {
                 PacketType::ConnectionDeny { reason },
             ) => {
                 self.state = ConnectionState::Disconnected;
+                if *reason == deny_reason::VERSION_MISMATCH {
+                    return Err(ConnectionError::VersionMismatch);
+                }
                 return Err(ConnectionError::ConnectionDenied(*reason));
             }
 
@@ -83,20 +94,15 @@ impl Connection {
                         channel,
                         is_fragment: _,
                     } => {
-                        if (channel as usize) < self.channels.len() {
-                            self.channels[channel as usize].on_packet_received(packet.payload);
+                        if let Some(ch) = self.channels.get_mut(channel as usize) {
+                            ch.on_packet_received(packet.payload);
                         }
                     }
                     PacketType::BatchedPayload { channel } => {
-                        if (channel as usize) < self.channels.len() {
-                            if let Some(messages) =
-                                crate::congestion::unbatch_messages(&packet.payload)
-                            {
-                                for msg in messages {
-                                    self.channels[channel as usize].on_packet_received(msg);
-                                }
-                            }
-                        }
+                        self.receive_batched_payload(channel, &packet.payload);
+                    }
+                    PacketType::MultiPayload => {
+                        self.receive_multi_payload(&packet.payload);
                     }
                     PacketType::MtuProbe { probe_size } => {
                         let header = self.create_header();
@@ -147,6 +153,13 @@ impl Connection {
         self.data_sent_this_tick = false;
         self.next_fragment_id = 0;
         self.pending_fragments.clear();
+        self.key_share = None;
+        self.peer_key_share = None;
+        #[cfg(feature = "encryption")]
+        {
+            self.session = None;
+        }
+        self.accept_payload.clear();
 
         for channel in &mut self.channels {
             channel.reset();
@@ -157,16 +170,34 @@ impl Connection {
             self.config.congestion_bad_loss_threshold,
             self.config.congestion_good_rtt_threshold,
             self.config.congestion_recovery_time,
-        );
+        )
+        .with_clock(self.clock.clone());
         self.bandwidth_up =
-            crate::congestion::BandwidthTracker::new(std::time::Duration::from_secs(1));
+            crate::congestion::BandwidthTracker::new(std::time::Duration::from_secs(1))
+                .with_clock(self.clock.clone());
         self.bandwidth_down =
-            crate::congestion::BandwidthTracker::new(std::time::Duration::from_secs(1));
+            crate::congestion::BandwidthTracker::new(std::time::Duration::from_secs(1))
+                .with_clock(self.clock.clone());
+        self.bandwidth_limiter = crate::congestion::bandwidth_limiter(
+            self.config.max_bandwidth_bytes_per_sec,
+            self.config.mtu,
+        )
+        .map(|limiter| limiter.with_clock(self.clock.clone()));
         self.fragment_assembler = crate::fragment::FragmentAssembler::new(
             self.config.fragment_timeout,
             self.config.max_reassembly_buffer_size,
-        );
+        )
+        .with_max_buffers(self.config.max_reassembly_buffers)
+        .with_clock(self.clock.clone());
+        self.inbound_packets =
+            crate::congestion::inbound_limiter(self.config.max_inbound_packets_per_sec)
+                .map(|limiter| limiter.with_clock(self.clock.clone()));
+        self.inbound_bytes =
+            crate::congestion::inbound_limiter(self.config.max_inbound_bytes_per_sec)
+                .map(|limiter| limiter.with_clock(self.clock.clone()));
+        self.throttled.clear();
         self.mtu_discovery =
-            crate::fragment::MtuDiscovery::new(crate::fragment::MIN_MTU, self.config.mtu);
+            crate::fragment::MtuDiscovery::for_peer(&self.remote_addr, self.config.mtu)
+                .with_clock(self.clock.clone());
     }
 }
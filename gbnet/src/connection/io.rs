@@ -1,27 +1,35 @@
 use std::time::Instant;
 
 use crate::{
-    packet::{disconnect_reason, Packet, PacketType},
+    config::PacketProtection,
+    congestion::{self, BATCH_HEADER_SIZE, MAX_BATCH_MESSAGES, MULTI_ENTRY_OVERHEAD},
+    packet::{disconnect_reason, Packet, PacketType, MAX_PACKET_OVERHEAD},
+    reliability::PacketMessages,
     security,
-    socket::{SocketError, UdpSocket},
+    socket::SocketError,
+    transport::Transport,
+    wire,
 };
 
 use super::{Connection, ConnectionError, ConnectionState};
 
 impl Connection {
-    /// Full update cycle including socket I/O. Used by Connection-driven flows
-    /// (e.g. disconnecting connections that own their socket interaction).
-    pub fn update(&mut self, socket: &mut UdpSocket) -> Result<(), ConnectionError> {
+    /// Full update cycle including transport I/O. Used by Connection-driven flows
+    /// (e.g. disconnecting connections that own their transport interaction).
+    pub fn update<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<(), ConnectionError> {
         self.update_tick()?;
-        self.process_send_queue(socket)?;
-        self.receive_packets(socket)?;
+        self.process_send_queue(transport)?;
+        self.receive_packets(transport)?;
         Ok(())
     }
 
     /// Update connection state without socket I/O. Server/Client call this per tick
     /// after feeding received packets in, then drain `send_queue` themselves.
     pub fn update_tick(&mut self) -> Result<(), ConnectionError> {
-        let now = Instant::now();
+        let now = self.clock.now();
 
         if self.state != ConnectionState::Disconnected
             && self.state != ConnectionState::Disconnecting
@@ -55,6 +63,9 @@ impl Connection {
                 self.congestion
                     .update(self.stats.packet_loss, self.stats.rtt);
                 self.congestion.refill_budget(self.config.mtu);
+                if let Some(ref mut limiter) = self.bandwidth_limiter {
+                    limiter.refill();
+                }
 
                 if let Some(ref mut cw) = self.cwnd {
                     cw.update_pacing(self.reliability.rto());
@@ -65,6 +76,9 @@ impl Connection {
                     self.send_keepalive()?;
                 }
 
+                #[cfg(feature = "encryption")]
+                self.poll_rekey(now);
+
                 if let Some(probe_size) = self.mtu_discovery.next_probe() {
                     let header = self.create_header();
                     let padding = vec![0u8; probe_size.saturating_sub(16)];
@@ -80,7 +94,23 @@ impl Connection {
 
                 self.data_sent_this_tick = false;
                 let mut packets_sent_this_cycle: u32 = 0;
+                let mut coalesced = CoalescedPayload::default();
+                // A zero budget never fits a second message, so each one
+                // flushes on its own when batching is disabled.
+                let payload_budget = if self.config.enable_batching {
+                    self.mtu_discovery
+                        .discovered_mtu()
+                        .saturating_sub(MAX_PACKET_OVERHEAD)
+                } else {
+                    0
+                };
+                // Once a message no longer fits the bandwidth limiter, it and
+                // every lower-priority channel wait for the bucket to refill.
+                let mut bandwidth_exhausted = false;
                 for ch_idx_ref in 0..self.channel_priority_order.len() {
+                    if bandwidth_exhausted {
+                        break;
+                    }
                     let ch_idx = self.channel_priority_order[ch_idx_ref];
                     loop {
                         let estimated_size = self.config.mtu;
@@ -96,18 +126,31 @@ impl Connection {
                                 break;
                             }
                         }
+                        let Some(size) = self.channels[ch_idx].next_outgoing_size() else {
+                            break;
+                        };
+                        if !self.try_reserve_bandwidth(size) {
+                            bandwidth_exhausted = true;
+                            break;
+                        }
                         let Some((msg_seq, wire_data)) =
                             self.channels[ch_idx].get_outgoing_message()
                         else {
                             break;
                         };
                         let packet_size = wire_data.len();
-                        packets_sent_this_cycle += 1;
+                        let reliable = self.channels[ch_idx].awaits_ack(msg_seq);
                         self.congestion.deduct_budget(packet_size);
-                        let header = self.create_header();
-                        let pkt_seq = header.sequence;
 
                         if wire_data.len() > self.config.fragment_threshold {
+                            // Earlier small messages must not go out after
+                            // the fragments of a later one.
+                            if self.flush_coalesced(&mut coalesced, now) {
+                                packets_sent_this_cycle += 1;
+                            }
+                            packets_sent_this_cycle += 1;
+                            let header = self.create_header();
+                            let pkt_seq = header.sequence;
                             let frag_id = self.next_fragment_id;
                             self.next_fragment_id = self.next_fragment_id.wrapping_add(1);
                             if let Ok(fragments) = crate::fragment::fragment_message(
@@ -132,48 +175,48 @@ impl Connection {
                                 }
                                 self.pending_fragments.insert(frag_id, frag_entries);
                             }
+                            if reliable {
+                                self.reliability.on_packet_sent(
+                                    pkt_seq,
+                                    now,
+                                    ch_idx as u8,
+                                    msg_seq,
+                                    packet_size,
+                                );
+                            }
                         } else {
-                            let packet = Packet::new(
-                                header,
-                                PacketType::Payload {
-                                    channel: ch_idx as u8,
-                                    is_fragment: false,
-                                },
-                            )
-                            .with_payload(wire_data);
-                            self.send_queue.push_back(packet);
+                            if !coalesced.fits(packet_size, payload_budget)
+                                && self.flush_coalesced(&mut coalesced, now)
+                            {
+                                packets_sent_this_cycle += 1;
+                            }
+                            coalesced.push(ch_idx as u8, msg_seq, wire_data, reliable);
                         }
 
                         self.data_sent_this_tick = true;
                         if let Some(ref mut cw) = self.cwnd {
                             cw.on_send(packet_size);
                         }
-                        if self.channels[ch_idx].is_reliable() {
-                            self.reliability.on_packet_sent(
-                                pkt_seq,
-                                now,
-                                ch_idx as u8,
-                                msg_seq,
-                                packet_size,
-                            );
-                        }
                     }
 
+                    if bandwidth_exhausted {
+                        break;
+                    }
                     let rto = self.reliability.rto();
-                    let retransmits = self.channels[ch_idx].get_retransmit_messages(now, rto);
-                    for (_seq, wire_data) in retransmits {
-                        let header = self.create_header();
-                        let packet = Packet::new(
-                            header,
-                            PacketType::Payload {
-                                channel: ch_idx as u8,
-                                is_fragment: false,
-                            },
-                        )
-                        .with_payload(wire_data);
-                        self.send_queue.push_back(packet);
+                    let available = self.bandwidth_available();
+                    let retransmits =
+                        self.channels[ch_idx].get_retransmit_messages_within(now, rto, available);
+                    self.reserve_bandwidth(retransmits.iter().map(|(_, d)| d.len()).sum());
+                    for (msg_seq, wire_data) in retransmits {
+                        if !coalesced.fits(wire_data.len(), payload_budget)
+                            && self.flush_coalesced(&mut coalesced, now)
+                        {
+                            packets_sent_this_cycle += 1;
+                        }
+                        coalesced.push(ch_idx as u8, msg_seq, wire_data, true);
                     }
                 }
+                self.flush_coalesced(&mut coalesced, now);
 
                 for channel in &mut self.channels {
                     channel.update();
@@ -226,6 +269,93 @@ impl Connection {
         Ok(())
     }
 
+    /// Queue the messages gathered in `coalesced` as one packet: a plain
+    /// [`PacketType::Payload`] for a single message, a
+    /// [`PacketType::BatchedPayload`] when every message shares a channel,
+    /// otherwise a [`PacketType::MultiPayload`]. Returns `false` if there was
+    /// nothing to send.
+    fn flush_coalesced(&mut self, coalesced: &mut CoalescedPayload, now: Instant) -> bool {
+        let batch = std::mem::take(coalesced);
+        if batch.messages.is_empty() {
+            return false;
+        }
+        let size = batch.size();
+        let header = self.create_header();
+        let sequence = header.sequence;
+        let mut messages = batch.messages;
+        let message_count = messages.len() as u64;
+        let packet = if messages.len() == 1 {
+            let (channel, data) = messages.swap_remove(0);
+            Packet::new(
+                header,
+                PacketType::Payload {
+                    channel,
+                    is_fragment: false,
+                },
+            )
+            .with_payload(data)
+        } else if let Some(channel) = batch.single_channel {
+            let data: Vec<Vec<u8>> = messages.into_iter().map(|(_, data)| data).collect();
+            // The gathered messages already fit the budget, so this yields one batch.
+            let payload = congestion::batch_messages(&data, size)
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            Packet::new(header, PacketType::BatchedPayload { channel }).with_payload(payload)
+        } else {
+            Packet::new(header, PacketType::MultiPayload)
+                .with_payload(congestion::pack_channel_messages(&messages))
+        };
+        self.send_queue.push_back(packet);
+
+        if message_count > 1 {
+            self.stats.batched_packets_sent += 1;
+            self.stats.batched_messages_sent += message_count;
+        }
+        if !batch.reliable.is_empty() {
+            self.reliability
+                .on_packet_sent_with_messages(sequence, now, batch.reliable, size);
+        }
+        true
+    }
+
+    /// Bytes that can still be committed this tick under the connection's
+    /// own limiter and any server-granted credit.
+    fn bandwidth_available(&self) -> usize {
+        let limit = self
+            .bandwidth_limiter
+            .as_ref()
+            .map_or(usize::MAX, |limiter| limiter.available());
+        self.bandwidth_credit
+            .map_or(limit, |credit| credit.min(limit))
+    }
+
+    /// Commit `bytes` to this tick's sends if both the connection limiter and
+    /// the server credit allow it.
+    fn try_reserve_bandwidth(&mut self, bytes: usize) -> bool {
+        if self.bandwidth_credit.is_some_and(|credit| credit < bytes) {
+            return false;
+        }
+        if self
+            .bandwidth_limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.can_send(bytes))
+        {
+            return false;
+        }
+        self.reserve_bandwidth(bytes);
+        true
+    }
+
+    fn reserve_bandwidth(&mut self, bytes: usize) {
+        if let Some(ref mut limiter) = self.bandwidth_limiter {
+            limiter.reserve(bytes);
+        }
+        if let Some(ref mut credit) = self.bandwidth_credit {
+            *credit = credit.saturating_sub(bytes);
+        }
+    }
+
     fn send_keepalive(&mut self) -> Result<(), ConnectionError> {
         let header = self.create_header();
         let packet = Packet::new(header, PacketType::KeepAlive);
@@ -233,36 +363,106 @@ impl Connection {
         Ok(())
     }
 
-    fn process_send_queue(&mut self, socket: &mut UdpSocket) -> Result<(), ConnectionError> {
-        while let Some(packet) = self.send_queue.pop_front() {
-            let data = packet
-                .serialize()
-                .map_err(|_| ConnectionError::InvalidPacket)?;
-
-            #[cfg(feature = "encryption")]
-            let data = if let Some(ref enc) = self.encryption_state {
-                enc.encrypt(&data, self.local_sequence as u64)
-                    .unwrap_or(data)
-            } else {
-                data
+    /// Serialize `packet` for the wire, sealing or authenticating it under the
+    /// session keys once they are established, and append the CRC32C.
+    pub(crate) fn encode_packet(&mut self, packet: &Packet) -> Option<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(session) = self.session.as_mut() {
+            let body = packet.serialize_body().ok()?;
+            let (epoch, counter, sealed) =
+                match session.seal(&body, &packet.header.associated_data()) {
+                    Ok(sealed) => sealed,
+                    Err(e) => {
+                        log::warn!("Failed to seal packet to {}: {}", self.remote_addr, e);
+                        return None;
+                    }
+                };
+            let wrapper = match self.config.protection {
+                PacketProtection::Authenticated => PacketType::Authenticated { epoch, counter },
+                _ => PacketType::Encrypted { epoch, counter },
             };
+            let sealed = Packet::new(packet.header.clone(), wrapper).with_payload(sealed);
+            return wire::encode_packet(&sealed);
+        }
+        wire::encode_packet(packet)
+    }
 
-            let mut data_with_crc = data;
-            security::append_crc32(&mut data_with_crc);
+    /// Unwrap a packet received from the peer. Sealed or authenticated
+    /// packets are opened with the session keys of their epoch, and rekey
+    /// packets are acted on; once a session has keys, packets other than the
+    /// handshake must be protected as configured. Returns `None` if the
+    /// packet must be dropped.
+    pub(crate) fn open_packet(&mut self, packet: Packet) -> Option<Packet> {
+        match (self.config.protection, &packet.packet_type) {
+            (PacketProtection::Encrypted, &PacketType::Encrypted { epoch, counter })
+            | (PacketProtection::Authenticated, &PacketType::Authenticated { epoch, counter }) => {
+                return self.open_sealed(epoch, counter, packet);
+            }
+            (_, PacketType::Encrypted { .. } | PacketType::Authenticated { .. }) => return None,
+            _ => {}
+        }
+        if self.has_session_keys() && !packet.packet_type.is_handshake() {
+            return None;
+        }
+        Some(packet)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn open_sealed(&mut self, epoch: u8, counter: u64, packet: Packet) -> Option<Packet> {
+        let now = self.clock.now();
+        let grace = self.config.connection_timeout;
+        let session = self.session.as_mut()?;
+        let (body, rekeyed) = session
+            .open(
+                epoch,
+                counter,
+                &packet.payload,
+                &packet.header.associated_data(),
+                now,
+                grace,
+            )
+            .ok()?;
+        if rekeyed {
+            self.stats.rekeys += 1;
+        }
+        let packet = Packet::deserialize_body(packet.header, &body).ok()?;
+        if matches!(
+            packet.packet_type,
+            PacketType::RekeyRequest { .. } | PacketType::RekeyResponse { .. }
+        ) {
+            self.handle_rekey(&packet);
+        }
+        Some(packet)
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    fn open_sealed(&mut self, _epoch: u8, _counter: u64, _packet: Packet) -> Option<Packet> {
+        None
+    }
+
+    fn process_send_queue<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<(), ConnectionError> {
+        while let Some(packet) = self.send_queue.pop_front() {
+            let data = self
+                .encode_packet(&packet)
+                .ok_or(ConnectionError::InvalidPacket)?;
 
-            socket.send_to(&data_with_crc, self.remote_addr)?;
+            transport.send_to(&data, self.remote_addr)?;
 
-            self.bandwidth_up.record(data_with_crc.len());
-            self.last_packet_send_time = Instant::now();
-            self.stats.packets_sent += 1;
-            self.stats.bytes_sent += data_with_crc.len() as u64;
+            self.record_bytes_sent(data.len());
         }
         Ok(())
     }
 
-    fn receive_packets(&mut self, socket: &mut UdpSocket) -> Result<(), ConnectionError> {
+    fn receive_packets<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<(), ConnectionError> {
         loop {
-            match socket.recv_from() {
+            match transport.recv_from() {
                 Ok((data, addr)) => {
                     if addr != self.remote_addr {
                         continue;
@@ -273,21 +473,6 @@ impl Connection {
                         None => continue,
                     };
 
-                    #[cfg(feature = "encryption")]
-                    let decrypted;
-                    #[cfg(feature = "encryption")]
-                    let validated = if let Some(ref enc) = self.encryption_state {
-                        match enc.decrypt(validated, self.remote_sequence as u64) {
-                            Ok(d) => {
-                                decrypted = d;
-                                &decrypted
-                            }
-                            Err(_) => continue,
-                        }
-                    } else {
-                        validated
-                    };
-
                     let packet = match Packet::deserialize(validated) {
                         Ok(p) => p,
                         Err(_) => continue,
@@ -297,8 +482,13 @@ impl Connection {
                         continue;
                     }
 
+                    let Some(packet) = self.open_packet(packet) else {
+                        self.stats.packets_rejected += 1;
+                        continue;
+                    };
+
                     self.bandwidth_down.record(data.len());
-                    self.last_packet_recv_time = Instant::now();
+                    self.last_packet_recv_time = self.clock.now();
                     self.stats.packets_received += 1;
                     self.stats.bytes_received += data.len() as u64;
 
@@ -311,3 +501,39 @@ impl Connection {
         Ok(())
     }
 }
+
+/// Channel messages gathered during a tick to share one packet.
+#[derive(Default)]
+struct CoalescedPayload {
+    messages: Vec<(u8, Vec<u8>)>,
+    reliable: PacketMessages,
+    entry_bytes: usize,
+    /// The channel every gathered message belongs to, if there is only one.
+    single_channel: Option<u8>,
+}
+
+impl CoalescedPayload {
+    /// Packed size of the messages gathered so far.
+    fn size(&self) -> usize {
+        BATCH_HEADER_SIZE + self.entry_bytes
+    }
+
+    /// Whether a message of `len` bytes can join without exceeding `budget`.
+    fn fits(&self, len: usize, budget: usize) -> bool {
+        self.messages.len() < MAX_BATCH_MESSAGES as usize
+            && self.size() + MULTI_ENTRY_OVERHEAD + len <= budget
+    }
+
+    fn push(&mut self, channel: u8, sequence: u16, data: Vec<u8>, reliable: bool) {
+        if reliable {
+            self.reliable.push((channel, sequence));
+        }
+        if self.messages.is_empty() {
+            self.single_channel = Some(channel);
+        } else if self.single_channel != Some(channel) {
+            self.single_channel = None;
+        }
+        self.entry_bytes += MULTI_ENTRY_OVERHEAD + data.len();
+        self.messages.push((channel, data));
+    }
+}
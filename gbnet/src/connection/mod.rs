@@ -7,16 +7,24 @@ use std::time::Instant;
 
 use crate::{
     channel::{Channel, ChannelError},
-    congestion::{BandwidthTracker, CongestionController, CongestionWindow},
+    clock::{system_clock, SharedClock},
+    config::DEFAULT_CLIENT_BANDWIDTH_WEIGHT,
+    congestion::{
+        bandwidth_limiter, inbound_limiter, BandwidthLimiter, BandwidthTracker,
+        CongestionController, CongestionWindow,
+    },
     fragment::{FragmentAssembler, MtuDiscovery},
-    packet::{Packet, PacketHeader},
+    packet::{Packet, PacketHeader, PacketType},
     reliability::ReliableEndpoint,
+    security::{KeyShare, SessionRole, KEY_SHARE_BYTES},
     socket::SocketError,
     NetworkConfig, NetworkStats,
 };
 
 mod handshake;
 mod io;
+#[cfg(feature = "encryption")]
+mod session;
 
 /// States of the connection state machine.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -36,6 +44,8 @@ pub enum ConnectionError {
     ConnectionDenied(u8),
     Timeout,
     ProtocolMismatch,
+    /// The server rejected this client's protocol or application version.
+    VersionMismatch,
     InvalidPacket,
     InvalidChannel(u8),
     SocketError(SocketError),
@@ -51,6 +61,7 @@ impl std::fmt::Display for ConnectionError {
             ConnectionError::ConnectionDenied(r) => write!(f, "Connection denied: {}", r),
             ConnectionError::Timeout => write!(f, "Connection timed out"),
             ConnectionError::ProtocolMismatch => write!(f, "Protocol mismatch"),
+            ConnectionError::VersionMismatch => write!(f, "Version mismatch"),
             ConnectionError::InvalidPacket => write!(f, "Invalid packet"),
             ConnectionError::InvalidChannel(ch) => write!(f, "Invalid channel: {}", ch),
             ConnectionError::SocketError(e) => write!(f, "Socket error: {}", e),
@@ -82,6 +93,13 @@ pub enum DisconnectReason {
     Kicked,
     ServerFull,
     ProtocolMismatch,
+    /// The server rejected this client's protocol or application version.
+    VersionMismatch,
+    /// The server banned this client's address.
+    Banned,
+    /// The client exceeded the server's inbound limits under
+    /// `FloodPolicy::Kick`.
+    Flooding,
     Unknown(u8),
 }
 
@@ -93,6 +111,9 @@ impl From<u8> for DisconnectReason {
             2 => DisconnectReason::Kicked,
             3 => DisconnectReason::ServerFull,
             4 => DisconnectReason::ProtocolMismatch,
+            5 => DisconnectReason::VersionMismatch,
+            6 => DisconnectReason::Banned,
+            7 => DisconnectReason::Flooding,
             other => DisconnectReason::Unknown(other),
         }
     }
@@ -128,11 +149,35 @@ pub struct Connection {
     pub(crate) cwnd: Option<CongestionWindow>,
     pub(crate) bandwidth_up: BandwidthTracker,
     pub(crate) bandwidth_down: BandwidthTracker,
+    pub(crate) bandwidth_limiter: Option<BandwidthLimiter>,
+    /// Bytes the server has granted this connection from its shared egress
+    /// budget; `None` when no server-wide budget applies.
+    pub(crate) bandwidth_credit: Option<usize>,
+    /// Relative share of the server egress budget.
+    pub(crate) bandwidth_weight: u32,
     pub(crate) fragment_assembler: FragmentAssembler,
     pub(crate) mtu_discovery: MtuDiscovery,
 
+    /// Inbound packet budget, `None` when packets per second are unlimited.
+    pub(crate) inbound_packets: Option<BandwidthLimiter>,
+    /// Inbound byte budget, `None` when bytes per second are unlimited.
+    pub(crate) inbound_bytes: Option<BandwidthLimiter>,
+    /// Packets held back under [`crate::FloodPolicy::Throttle`] until the
+    /// inbound budgets admit them, with their datagram sizes.
+    pub(crate) throttled: VecDeque<(Packet, usize)>,
+
+    /// This side's ephemeral key share, held from the challenge until the
+    /// session is established.
+    pub(crate) key_share: Option<KeyShare>,
+    /// The peer's public key share from the challenge or response.
+    #[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+    pub(crate) peer_key_share: Option<[u8; KEY_SHARE_BYTES]>,
+    /// Session keys, present once an encrypted handshake completes.
     #[cfg(feature = "encryption")]
-    pub(crate) encryption_state: Option<crate::security::EncryptionState>,
+    pub(crate) session: Option<session::Session>,
+    /// Payload of the accept a server sent this connection, sealed, kept so
+    /// repeated accepts carry it too.
+    pub(crate) accept_payload: Vec<u8>,
 
     pub(crate) channel_priority_order: Vec<usize>,
 
@@ -147,6 +192,8 @@ pub struct Connection {
     /// Tracks per-fragment packet sequences for selective retransmission.
     /// Maps fragment_message_id → Vec<(packet_seq, fragment_index, fragment_data)>
     pub(crate) pending_fragments: HashMap<u32, Vec<(u16, u8, Vec<u8>)>>,
+
+    pub(crate) clock: SharedClock,
 }
 
 impl Connection {
@@ -184,14 +231,16 @@ impl Connection {
         };
         let bandwidth_up = BandwidthTracker::new(std::time::Duration::from_secs(1));
         let bandwidth_down = BandwidthTracker::new(std::time::Duration::from_secs(1));
+        let bandwidth_limiter = bandwidth_limiter(config.max_bandwidth_bytes_per_sec, config.mtu);
         let fragment_assembler =
-            FragmentAssembler::new(config.fragment_timeout, config.max_reassembly_buffer_size);
-        let mtu_discovery = MtuDiscovery::new(crate::fragment::MIN_MTU, config.mtu);
+            FragmentAssembler::new(config.fragment_timeout, config.max_reassembly_buffer_size)
+                .with_max_buffers(config.max_reassembly_buffers);
+        let inbound_packets = inbound_limiter(config.max_inbound_packets_per_sec);
+        let inbound_bytes = inbound_limiter(config.max_inbound_bytes_per_sec);
+        let mtu_discovery = MtuDiscovery::for_peer(&remote_addr, config.mtu);
 
-        #[cfg(feature = "encryption")]
-        let encryption_state = config
-            .encryption_key
-            .and_then(|key| crate::security::EncryptionState::new(&key).ok());
+        let clock = system_clock();
+        let now = clock.now();
 
         Self {
             config,
@@ -200,8 +249,8 @@ impl Connection {
             remote_addr,
             client_salt: random(),
             server_salt: 0,
-            last_packet_send_time: Instant::now(),
-            last_packet_recv_time: Instant::now(),
+            last_packet_send_time: now,
+            last_packet_recv_time: now,
             connection_start_time: None,
             connection_request_time: None,
             connection_retry_count: 0,
@@ -216,12 +265,21 @@ impl Connection {
             cwnd,
             bandwidth_up,
             bandwidth_down,
+            bandwidth_limiter,
+            bandwidth_credit: None,
+            bandwidth_weight: DEFAULT_CLIENT_BANDWIDTH_WEIGHT,
             fragment_assembler,
             mtu_discovery,
+            inbound_packets,
+            inbound_bytes,
+            throttled: VecDeque::new(),
             send_queue: VecDeque::new(),
             recv_queue: VecDeque::new(),
+            key_share: None,
+            peer_key_share: None,
             #[cfg(feature = "encryption")]
-            encryption_state,
+            session: None,
+            accept_payload: Vec::new(),
             stats: NetworkStats::default(),
             disconnect_retry_count: 0,
             disconnect_time: None,
@@ -229,9 +287,46 @@ impl Connection {
             data_sent_this_tick: false,
             next_fragment_id: 0,
             pending_fragments: HashMap::new(),
+            clock,
         }
     }
 
+    /// Use `clock` for every timeout, timer, and timestamp in this connection
+    /// and its channels, reliability, congestion, and fragmentation state.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        let now = clock.now();
+        self.channels = self
+            .channels
+            .into_iter()
+            .map(|ch| ch.with_clock(clock.clone()))
+            .collect();
+        self.reliability = self.reliability.with_clock(clock.clone());
+        self.congestion = self.congestion.with_clock(clock.clone());
+        self.cwnd = self.cwnd.map(|cw| cw.with_clock(clock.clone()));
+        self.bandwidth_up = self.bandwidth_up.with_clock(clock.clone());
+        self.bandwidth_limiter = self
+            .bandwidth_limiter
+            .map(|limiter| limiter.with_clock(clock.clone()));
+        self.bandwidth_down = self.bandwidth_down.with_clock(clock.clone());
+        self.fragment_assembler = self.fragment_assembler.with_clock(clock.clone());
+        self.mtu_discovery = self.mtu_discovery.with_clock(clock.clone());
+        self.inbound_packets = self
+            .inbound_packets
+            .map(|limiter| limiter.with_clock(clock.clone()));
+        self.inbound_bytes = self
+            .inbound_bytes
+            .map(|limiter| limiter.with_clock(clock.clone()));
+        self.last_packet_send_time = now;
+        self.last_packet_recv_time = now;
+        self.clock = clock;
+        self
+    }
+
+    /// Current time according to this connection's clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     pub fn send(
         &mut self,
         channel_id: u8,
@@ -250,6 +345,43 @@ impl Connection {
         Ok(())
     }
 
+    /// Charge a `len`-byte datagram to the inbound packet and byte budgets.
+    /// Returns false, charging nothing, when either budget is exhausted.
+    pub(crate) fn admit_inbound(&mut self, len: usize) -> bool {
+        for limiter in [&mut self.inbound_packets, &mut self.inbound_bytes]
+            .into_iter()
+            .flatten()
+        {
+            limiter.refill();
+        }
+        let fits = self.inbound_packets.as_ref().is_none_or(|l| l.can_send(1))
+            && self.inbound_bytes.as_ref().is_none_or(|l| l.can_send(len));
+        if fits {
+            if let Some(ref mut limiter) = self.inbound_packets {
+                limiter.consume(1);
+            }
+            if let Some(ref mut limiter) = self.inbound_bytes {
+                limiter.consume(len);
+            }
+        }
+        fits
+    }
+
+    /// Number of messages ready to be received on `channel_id`.
+    pub(crate) fn pending_receive(&self, channel_id: u8) -> usize {
+        self.channels
+            .get(channel_id as usize)
+            .map_or(0, |ch| ch.pending_receive())
+    }
+
+    /// Discard all but the oldest `keep` messages ready on `channel_id`,
+    /// returning how many were discarded.
+    pub(crate) fn truncate_received(&mut self, channel_id: u8, keep: usize) -> usize {
+        self.channels
+            .get_mut(channel_id as usize)
+            .map_or(0, |ch| ch.truncate_received(keep))
+    }
+
     pub fn receive(&mut self, channel_id: u8) -> Option<Vec<u8>> {
         if channel_id as usize >= self.channels.len() {
             return None;
@@ -319,20 +451,170 @@ impl Connection {
         self.server_salt = salt;
     }
 
+    /// Client side of the key exchange: take the server's share from a
+    /// challenge payload, checking its signature when a server key is pinned,
+    /// and generate this side's share. Returns false if the challenge must be
+    /// ignored.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn begin_key_exchange(&mut self, challenge_payload: &[u8]) -> bool {
+        if !self.config.protection.uses_session_keys() {
+            return true;
+        }
+        let Some(server_share) = challenge_payload.get(..KEY_SHARE_BYTES) else {
+            return false;
+        };
+        if let Some(pinned) = &self.config.pinned_server_key {
+            let signature = &challenge_payload[KEY_SHARE_BYTES..];
+            if !crate::security::verify_key_share(
+                pinned,
+                self.config.protocol_id,
+                self.server_salt,
+                server_share,
+                signature,
+            ) {
+                log::warn!("Challenge key share is not signed by the pinned server key");
+                return false;
+            }
+        }
+        self.peer_key_share = server_share.try_into().ok();
+        self.key_share = KeyShare::generate().ok();
+        self.key_share.is_some()
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn begin_key_exchange(&mut self, _challenge_payload: &[u8]) -> bool {
+        !self.config.protection.uses_session_keys()
+    }
+
+    /// Payload carrying this side's public key share; empty without one.
+    pub(crate) fn key_share_payload(&self) -> Vec<u8> {
+        self.key_share
+            .as_ref()
+            .map(|share| share.public_key().to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Take the peer's key share from the front of a connection response
+    /// payload when session keys are configured, returning the application
+    /// data that follows it.
+    pub(crate) fn split_key_share<'a>(&mut self, payload: &'a [u8]) -> &'a [u8] {
+        if !self.config.protection.uses_session_keys() {
+            return payload;
+        }
+        let (share, rest) = payload.split_at(payload.len().min(KEY_SHARE_BYTES));
+        self.peer_key_share = share.try_into().ok();
+        rest
+    }
+
+    /// Complete the key exchange, deriving this connection's session cipher
+    /// from both key shares, the handshake salts and any
+    /// [`NetworkConfig::encryption_key`]. Every packet sent or accepted
+    /// afterwards is protected. Returns false if session keys are configured
+    /// but the exchange failed.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn establish_session(&mut self, role: SessionRole) -> bool {
+        let protection = self.config.protection;
+        if !protection.uses_session_keys() {
+            return true;
+        }
+        let (Some(share), Some(peer)) = (self.key_share.take(), self.peer_key_share.take()) else {
+            return false;
+        };
+        let cipher = share
+            .agree(&peer, self.config.encryption_key.as_ref())
+            .and_then(|secret| {
+                session::derive_cipher(
+                    protection,
+                    &secret,
+                    self.client_salt,
+                    self.server_salt,
+                    role,
+                )
+            });
+        self.session = cipher
+            .ok()
+            .map(|cipher| session::Session::new(cipher, role, self.clock.now()));
+        self.session.is_some()
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn establish_session(&mut self, _role: SessionRole) -> bool {
+        !self.config.protection.uses_session_keys()
+    }
+
+    /// Protect handshake application data under the session keys, as a
+    /// little-endian `u64` counter followed by the sealed bytes. Without
+    /// session keys the data is returned unchanged.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn seal_handshake_data(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let aad = self.config.protocol_id.to_le_bytes();
+        let Some(session) = self.session.as_mut() else {
+            return Some(data.to_vec());
+        };
+        let (_, counter, sealed) = session.seal(data, &aad).ok()?;
+        let mut framed = counter.to_le_bytes().to_vec();
+        framed.extend_from_slice(&sealed);
+        Some(framed)
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn seal_handshake_data(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        Some(data.to_vec())
+    }
+
+    /// Open handshake application data sealed by the peer's
+    /// [`Connection::seal_handshake_data`]. Returns `None` if it was forged
+    /// or replayed.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn open_handshake_data(&mut self, framed: &[u8]) -> Option<Vec<u8>> {
+        let aad = self.config.protocol_id.to_le_bytes();
+        let now = self.clock.now();
+        let grace = self.config.connection_timeout;
+        let Some(session) = self.session.as_mut() else {
+            return Some(framed.to_vec());
+        };
+        let counter = u64::from_le_bytes(framed.get(..8)?.try_into().ok()?);
+        let (data, _) = session
+            .open(0, counter, &framed[8..], &aad, now, grace)
+            .ok()?;
+        Some(data)
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn open_handshake_data(&mut self, framed: &[u8]) -> Option<Vec<u8>> {
+        Some(framed.to_vec())
+    }
+
+    /// Whether packets on this connection are protected by session keys.
+    pub fn has_session_keys(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        {
+            self.session.is_some()
+        }
+        #[cfg(not(feature = "encryption"))]
+        {
+            false
+        }
+    }
+
     pub fn touch_recv_time(&mut self) {
-        self.last_packet_recv_time = Instant::now();
+        self.last_packet_recv_time = self.clock.now();
     }
 
     pub fn last_recv_elapsed(&self) -> std::time::Duration {
-        self.last_packet_recv_time.elapsed()
+        self.clock.now().duration_since(self.last_packet_recv_time)
     }
 
     pub fn last_send_elapsed(&self) -> std::time::Duration {
-        self.last_packet_send_time.elapsed()
+        self.clock.now().duration_since(self.last_packet_send_time)
     }
 
     pub fn touch_send_time(&mut self) {
-        self.last_packet_send_time = Instant::now();
+        self.last_packet_send_time = self.clock.now();
     }
 
     /// Get next outgoing message from a channel (used by client/server).
@@ -351,6 +633,24 @@ impl Connection {
         }
     }
 
+    /// Deliver every message in a [`crate::packet::PacketType::MultiPayload`] payload.
+    pub fn receive_multi_payload(&mut self, payload: &[u8]) {
+        if let Some(messages) = crate::congestion::unpack_channel_messages(payload) {
+            for (channel, msg) in messages {
+                self.receive_payload_direct(channel, msg);
+            }
+        }
+    }
+
+    /// Deliver every message in a [`crate::packet::PacketType::BatchedPayload`] payload.
+    pub fn receive_batched_payload(&mut self, channel: u8, payload: &[u8]) {
+        if let Some(messages) = crate::congestion::unbatch_messages(payload) {
+            for msg in messages {
+                self.receive_payload_direct(channel, msg);
+            }
+        }
+    }
+
     pub fn channel_count(&self) -> usize {
         self.channels.len()
     }
@@ -366,10 +666,8 @@ impl Connection {
     /// Process an incoming packet's header for reliability tracking (ACKs, sequence).
     /// Called by server/client after deserializing a data packet from a connected peer.
     pub fn process_incoming_header(&mut self, header: &crate::packet::PacketHeader) {
-        use std::time::Instant;
-
-        self.reliability
-            .on_packet_received(header.sequence, Instant::now());
+        let now = self.clock.now();
+        self.reliability.on_packet_received(header.sequence, now);
         self.pending_ack_send = true;
 
         if crate::util::sequence_greater_than(header.sequence, self.remote_sequence) {
@@ -404,19 +702,92 @@ impl Connection {
         });
     }
 
+    /// Wire bytes the channels want to send this tick, counting queued
+    /// messages and due retransmissions.
+    pub fn send_demand(&self) -> usize {
+        let now = self.clock.now();
+        let rto = self.reliability.rto();
+        self.channels
+            .iter()
+            .map(|ch| ch.pending_send_bytes(now, rto))
+            .sum()
+    }
+
+    /// Add `bytes` of server egress budget to this connection's credit. Unused
+    /// credit carries over while the connection still has traffic waiting, so
+    /// a message larger than one tick's share is eventually sent.
+    pub(crate) fn grant_bandwidth(&mut self, bytes: usize, demand: usize) {
+        let credit = if demand == 0 {
+            0
+        } else {
+            (self.bandwidth_credit.unwrap_or(0) + bytes).min(demand)
+        };
+        self.bandwidth_credit = Some(credit);
+    }
+
     /// Drain the send queue, returning packets that need to be sent over the wire.
     pub fn drain_send_queue(&mut self) -> Vec<Packet> {
         self.send_queue.drain(..).collect()
     }
 
-    /// Record that bytes were sent (for bandwidth tracking).
+    /// Drain the send queue into wire-ready datagrams, recording bytes sent.
+    pub(crate) fn drain_datagrams(&mut self) -> Vec<Vec<u8>> {
+        let packets: Vec<Packet> = self.send_queue.drain(..).collect();
+        packets
+            .iter()
+            .filter_map(|packet| {
+                let data = self.encode_packet(packet)?;
+                self.record_bytes_sent(data.len());
+                Some(data)
+            })
+            .collect()
+    }
+
+    /// Queue a control packet such as a disconnect or probe ack, stamped
+    /// with the next sequence and current acks.
+    pub(crate) fn queue_control(&mut self, packet_type: PacketType) {
+        self.queue_control_with_payload(packet_type, Vec::new());
+    }
+
+    pub(crate) fn queue_control_with_payload(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let header = self.create_header();
+        self.send_queue
+            .push_back(Packet::new(header, packet_type).with_payload(payload));
+    }
+
+    /// Record that bytes were sent (for bandwidth tracking and limiting).
     pub fn record_bytes_sent(&mut self, bytes: usize) {
         self.bandwidth_up.record(bytes);
-        self.last_packet_send_time = Instant::now();
+        if let Some(ref mut limiter) = self.bandwidth_limiter {
+            limiter.consume(bytes);
+        }
+        self.last_packet_send_time = self.clock.now();
         self.stats.packets_sent += 1;
         self.stats.bytes_sent += bytes as u64;
     }
 
+    /// Record that the transport failed to send `datagram`, undoing
+    /// [`Connection::record_bytes_sent`] for it. Handshake datagrams are sent
+    /// outside the connection and were never recorded.
+    pub(crate) fn record_send_failure(&mut self, datagram: &[u8]) {
+        self.stats.send_errors += 1;
+        let recorded = datagram
+            .len()
+            .checked_sub(4)
+            .and_then(|len| Packet::deserialize_type(&datagram[..len]).ok())
+            .is_some_and(|(_, packet_type)| !packet_type.is_handshake());
+        if !recorded {
+            return;
+        }
+        let bytes = datagram.len();
+        self.bandwidth_up.unrecord(bytes);
+        if let Some(ref mut limiter) = self.bandwidth_limiter {
+            limiter.refund(bytes);
+        }
+        self.stats.packets_sent = self.stats.packets_sent.saturating_sub(1);
+        self.stats.bytes_sent = self.stats.bytes_sent.saturating_sub(bytes as u64);
+    }
+
     /// Record that bytes were received (for bandwidth tracking).
     pub fn record_bytes_received(&mut self, bytes: usize) {
         self.bandwidth_down.record(bytes);
@@ -0,0 +1,302 @@
+//! Epoch-keyed session ciphers and the in-band rekey handshake.
+//!
+//! Either side starts a rekey once its keys reach
+//! [`crate::NetworkConfig::rekey_after_packets`] or
+//! [`crate::NetworkConfig::rekey_interval`], sending a sealed
+//! [`PacketType::RekeyRequest`] with a fresh key share until the peer answers.
+//! The responder derives the next keys at once but keeps sealing under the
+//! current ones until the initiator's first packet under the new epoch
+//! arrives, so neither side ever sends under keys the other cannot open.
+//! Keys of the previous epoch stay available for opening reordered packets.
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::PacketProtection,
+    packet::{Packet, PacketType},
+    security::{EncryptionError, KeyShare, PacketCipher, SessionRole, KEY_SHARE_BYTES},
+};
+
+use super::Connection;
+
+/// Build the cipher `protection` calls for from an agreed session secret.
+pub(super) fn derive_cipher(
+    protection: PacketProtection,
+    secret: &[u8],
+    client_salt: u64,
+    server_salt: u64,
+    role: SessionRole,
+) -> Result<PacketCipher, EncryptionError> {
+    match protection {
+        PacketProtection::Authenticated => {
+            PacketCipher::authenticating(secret, client_salt, server_salt, role)
+        }
+        _ => PacketCipher::new(secret, client_salt, server_salt, role),
+    }
+}
+
+/// A rekey this side started, waiting for the peer's response.
+struct PendingRekey {
+    epoch: u8,
+    share: KeyShare,
+    sent_at: Instant,
+}
+
+/// Keys derived in answer to the peer's rekey request, taking over once the
+/// peer seals its first packet under them.
+struct NextKeys {
+    epoch: u8,
+    cipher: PacketCipher,
+    public: [u8; KEY_SHARE_BYTES],
+}
+
+/// The ciphers of an encrypted session.
+pub(crate) struct Session {
+    role: SessionRole,
+    epoch: u8,
+    current: PacketCipher,
+    started_at: Instant,
+    /// Epoch, keys and expiry of the keys replaced by the last rekey.
+    previous: Option<(u8, PacketCipher, Instant)>,
+    pending: Option<PendingRekey>,
+    next: Option<NextKeys>,
+}
+
+impl Session {
+    pub(crate) fn new(cipher: PacketCipher, role: SessionRole, now: Instant) -> Self {
+        Self {
+            role,
+            epoch: 0,
+            current: cipher,
+            started_at: now,
+            previous: None,
+            pending: None,
+            next: None,
+        }
+    }
+
+    /// Seal under the current keys, returning the epoch and counter the peer
+    /// needs to open the packet.
+    pub(crate) fn seal(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<(u8, u64, Vec<u8>), EncryptionError> {
+        let (counter, sealed) = self.current.seal(plaintext, aad)?;
+        Ok((self.epoch, counter, sealed))
+    }
+
+    /// Open a packet sealed under `epoch`. The boolean is true when the
+    /// packet was the first under keys this side derived as responder, which
+    /// completes the rekey.
+    pub(crate) fn open(
+        &mut self,
+        epoch: u8,
+        counter: u64,
+        ciphertext: &[u8],
+        aad: &[u8],
+        now: Instant,
+        grace: Duration,
+    ) -> Result<(Vec<u8>, bool), EncryptionError> {
+        if epoch == self.epoch {
+            return Ok((self.current.open(counter, ciphertext, aad)?, false));
+        }
+        if let Some(next) = self.next.as_mut().filter(|next| next.epoch == epoch) {
+            let plaintext = next.cipher.open(counter, ciphertext, aad)?;
+            if let Some(next) = self.next.take() {
+                self.switch(next.epoch, next.cipher, now, grace);
+            }
+            return Ok((plaintext, true));
+        }
+        match self.previous.as_mut() {
+            Some((previous, cipher, expires)) if *previous == epoch && now < *expires => {
+                Ok((cipher.open(counter, ciphertext, aad)?, false))
+            }
+            _ => Err(EncryptionError::DecryptFailed),
+        }
+    }
+
+    fn switch(&mut self, epoch: u8, cipher: PacketCipher, now: Instant, grace: Duration) {
+        let replaced = std::mem::replace(&mut self.current, cipher);
+        self.previous = Some((self.epoch, replaced, now + grace));
+        self.epoch = epoch;
+        self.started_at = now;
+        self.pending = None;
+        self.next = None;
+    }
+
+    fn rekey_due(&self, now: Instant, after_packets: u64, interval: Duration) -> bool {
+        if self.pending.is_some() || self.next.is_some() {
+            return false;
+        }
+        (after_packets > 0 && self.current.packets_sealed() >= after_packets)
+            || (!interval.is_zero() && now.duration_since(self.started_at) >= interval)
+    }
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("role", &self.role)
+            .field("epoch", &self.epoch)
+            .field("current", &self.current)
+            .field("rekeying", &(self.pending.is_some() || self.next.is_some()))
+            .finish()
+    }
+}
+
+impl Connection {
+    /// Start a rekey when the current keys are due, or resend an unanswered
+    /// request once a retransmission timeout has passed.
+    pub(super) fn poll_rekey(&mut self, now: Instant) {
+        let rto = self.reliability.rto();
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let (epoch, payload) = if let Some(pending) = session.pending.as_mut() {
+            if now.duration_since(pending.sent_at) < rto {
+                return;
+            }
+            pending.sent_at = now;
+            (pending.epoch, pending.share.public_key().to_vec())
+        } else if session.rekey_due(
+            now,
+            self.config.rekey_after_packets,
+            self.config.rekey_interval,
+        ) {
+            let share = match KeyShare::generate() {
+                Ok(share) => share,
+                Err(e) => {
+                    log::warn!("Failed to generate rekey share: {}", e);
+                    return;
+                }
+            };
+            let epoch = session.epoch.wrapping_add(1);
+            let payload = share.public_key().to_vec();
+            session.pending = Some(PendingRekey {
+                epoch,
+                share,
+                sent_at: now,
+            });
+            (epoch, payload)
+        } else {
+            return;
+        };
+        self.queue_control_with_payload(PacketType::RekeyRequest { epoch }, payload);
+    }
+
+    /// Act on an opened rekey request or response from the peer.
+    pub(super) fn handle_rekey(&mut self, packet: &Packet) {
+        let now = self.clock.now();
+        let grace = self.config.connection_timeout;
+        let (protection, psk) = (self.config.protection, self.config.encryption_key);
+        let (client_salt, server_salt) = (self.client_salt, self.server_salt);
+        let agree = |share: KeyShare, role: SessionRole| {
+            share
+                .agree(&packet.payload, psk.as_ref())
+                .and_then(|secret| {
+                    derive_cipher(protection, &secret, client_salt, server_salt, role)
+                })
+        };
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        match packet.packet_type {
+            PacketType::RekeyRequest { epoch } => {
+                if epoch != session.epoch.wrapping_add(1) {
+                    return;
+                }
+                if session.pending.is_some() {
+                    // Both sides started the same rekey: the client's wins.
+                    if session.role == SessionRole::Client {
+                        return;
+                    }
+                    session.pending = None;
+                }
+                if session.next.is_none() {
+                    let Ok(share) = KeyShare::generate() else {
+                        return;
+                    };
+                    let public = *share.public_key();
+                    let Ok(cipher) = agree(share, session.role) else {
+                        return;
+                    };
+                    session.next = Some(NextKeys {
+                        epoch,
+                        cipher,
+                        public,
+                    });
+                }
+                let payload = session
+                    .next
+                    .as_ref()
+                    .map(|next| next.public.to_vec())
+                    .unwrap_or_default();
+                self.queue_control_with_payload(PacketType::RekeyResponse { epoch }, payload);
+            }
+            PacketType::RekeyResponse { epoch } => {
+                let Some(pending) = session.pending.take_if(|pending| pending.epoch == epoch)
+                else {
+                    return;
+                };
+                match agree(pending.share, session.role) {
+                    Ok(cipher) => {
+                        session.switch(epoch, cipher, now, grace);
+                        self.stats.rekeys += 1;
+                    }
+                    Err(e) => log::warn!("Rekey with {} failed: {}", self.remote_addr, e),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(key: u8, role: SessionRole) -> PacketCipher {
+        PacketCipher::new(&[key; 32], 1, 2, role).unwrap()
+    }
+
+    #[test]
+    fn test_key_switch_tolerates_reordering_until_grace_expires() {
+        let now = Instant::now();
+        let grace = Duration::from_secs(10);
+        let mut client = Session::new(cipher(1, SessionRole::Client), SessionRole::Client, now);
+        let mut server = Session::new(cipher(1, SessionRole::Server), SessionRole::Server, now);
+
+        // The server answered a rekey request; the client switched on the response.
+        server.next = Some(NextKeys {
+            epoch: 1,
+            cipher: cipher(2, SessionRole::Server),
+            public: [0; KEY_SHARE_BYTES],
+        });
+        client.switch(1, cipher(2, SessionRole::Client), now, grace);
+
+        // A server packet still under the old keys arrives after the switch.
+        let (epoch, counter, old) = server.seal(b"old", &[]).unwrap();
+        assert_eq!(epoch, 0);
+        let (new_epoch, new_counter, new) = client.seal(b"new", &[]).unwrap();
+        assert_eq!(new_epoch, 1);
+        assert_eq!(
+            client.open(epoch, counter, &old, &[], now, grace).unwrap(),
+            (b"old".to_vec(), false)
+        );
+        assert_eq!(
+            server
+                .open(new_epoch, new_counter, &new, &[], now, grace)
+                .unwrap(),
+            (b"new".to_vec(), true)
+        );
+        assert_eq!(server.seal(b"x", &[]).unwrap().0, 1);
+
+        // Once the grace period passes, the old keys no longer open anything.
+        let mut straggler = cipher(1, SessionRole::Server);
+        straggler.seal(b"", &[]).unwrap();
+        let (counter, late) = straggler.seal(b"late", &[]).unwrap();
+        assert!(client
+            .open(0, counter, &late, &[], now + grace, grace)
+            .is_err());
+    }
+}
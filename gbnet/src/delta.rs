@@ -8,6 +8,7 @@ use std::collections::VecDeque;
 use std::io;
 use std::time::{Duration, Instant};
 
+use crate::clock::{system_clock, SharedClock};
 use crate::serialize::bit_io::{BitBuffer, BitRead, BitWrite};
 use crate::serialize::{BitDeserialize, BitSerialize, NetworkDelta};
 
@@ -126,6 +127,7 @@ pub struct BaselineManager<T> {
     snapshots: VecDeque<(BaselineSeq, T, Instant)>,
     max_snapshots: usize,
     timeout: Duration,
+    clock: SharedClock,
 }
 
 impl<T: Clone> BaselineManager<T> {
@@ -135,12 +137,19 @@ impl<T: Clone> BaselineManager<T> {
             snapshots: VecDeque::with_capacity(max_snapshots),
             max_snapshots,
             timeout,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` for baseline expiry.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Store a confirmed snapshot at the given sequence.
     pub fn push_snapshot(&mut self, seq: BaselineSeq, state: T) {
-        let now = Instant::now();
+        let now = self.clock.now();
         // Evict expired
         while self
             .snapshots
@@ -1,10 +1,15 @@
 //! Message fragmentation, reassembly, and path MTU discovery.
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+use crate::clock::{system_clock, SharedClock};
+
 pub const DEFAULT_PROBE_TIMEOUT_MILLIS: u64 = 500;
 pub const DEFAULT_MAX_PROBE_ATTEMPTS: u32 = 10;
 pub const MIN_MTU: usize = 576;
+/// Minimum link MTU every IPv6 path must support (RFC 8200).
+pub const MIN_MTU_IPV6: usize = 1280;
 pub const MAX_MTU: usize = 1500;
 pub const MTU_CONVERGENCE_THRESHOLD: usize = 1;
 
@@ -113,7 +118,7 @@ struct FragmentBuffer {
 }
 
 impl FragmentBuffer {
-    fn new(fragment_count: u8) -> Self {
+    fn new(fragment_count: u8, created_at: Instant) -> Self {
         let mut fragments = Vec::with_capacity(fragment_count as usize);
         for _ in 0..fragment_count {
             fragments.push(None);
@@ -122,7 +127,7 @@ impl FragmentBuffer {
             fragments,
             fragment_count,
             received_count: 0,
-            created_at: Instant::now(),
+            created_at,
             total_size: 0,
         }
     }
@@ -167,6 +172,9 @@ pub struct FragmentAssembler {
     timeout: Duration,
     max_buffer_size: usize,
     current_buffer_size: usize,
+    /// Messages reassembled at once; 0 means unlimited.
+    max_buffers: usize,
+    clock: SharedClock,
 }
 
 impl FragmentAssembler {
@@ -176,14 +184,43 @@ impl FragmentAssembler {
             timeout,
             max_buffer_size,
             current_buffer_size: 0,
+            max_buffers: 0,
+            clock: system_clock(),
         }
     }
 
+    /// Reassemble at most `max` messages at once; fragments of further
+    /// messages are refused. 0 means unlimited.
+    pub fn with_max_buffers(mut self, max: usize) -> Self {
+        self.max_buffers = max;
+        self
+    }
+
+    /// Whether the fragment `data` belongs to a message already being
+    /// reassembled or there is room to start a new one.
+    pub fn has_room_for(&self, data: &[u8]) -> bool {
+        let Some(header) = FragmentHeader::deserialize(data) else {
+            return true;
+        };
+        self.max_buffers == 0
+            || self.buffers.len() < self.max_buffers
+            || self.buffers.contains_key(&header.message_id)
+    }
+
+    /// Use `clock` for reassembly timeouts.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Process a fragment. Returns the reassembled message if all fragments arrived.
     pub fn process_fragment(&mut self, data: &[u8]) -> Option<Vec<u8>> {
         self.cleanup();
 
         let header = FragmentHeader::deserialize(data)?;
+        if !self.has_room_for(data) {
+            return None;
+        }
         let fragment_data = data[FRAGMENT_HEADER_SIZE..].to_vec();
         let fragment_size = fragment_data.len();
 
@@ -197,10 +234,11 @@ impl FragmentAssembler {
             self.expire_oldest();
         }
 
+        let now = self.clock.now();
         let buffer = self
             .buffers
             .entry(header.message_id)
-            .or_insert_with(|| FragmentBuffer::new(header.fragment_count));
+            .or_insert_with(|| FragmentBuffer::new(header.fragment_count, now));
 
         self.current_buffer_size += fragment_size;
 
@@ -218,9 +256,10 @@ impl FragmentAssembler {
     /// Remove expired incomplete fragment buffers.
     pub fn cleanup(&mut self) {
         let timeout = self.timeout;
+        let now = self.clock.now();
         let mut removed_size = 0;
         self.buffers.retain(|_, buf| {
-            let keep = buf.created_at.elapsed() < timeout;
+            let keep = now.duration_since(buf.created_at) < timeout;
             if !keep {
                 removed_size += buf.total_size;
             }
@@ -249,6 +288,16 @@ impl FragmentAssembler {
     }
 }
 
+/// Smallest MTU guaranteed on the path to `addr`. IPv4-mapped IPv6
+/// addresses count as IPv4.
+pub fn min_mtu_for(addr: &SocketAddr) -> usize {
+    if addr.ip().to_canonical().is_ipv6() {
+        MIN_MTU_IPV6
+    } else {
+        MIN_MTU
+    }
+}
+
 /// MTU discovery using binary search between min and max.
 #[derive(Debug)]
 pub struct MtuDiscovery {
@@ -261,6 +310,7 @@ pub struct MtuDiscovery {
     last_probe_time: Option<Instant>,
     attempts: u32,
     max_attempts: u32,
+    clock: SharedClock,
 }
 
 #[derive(Debug, PartialEq)]
@@ -282,13 +332,28 @@ impl MtuDiscovery {
             last_probe_time: None,
             attempts: 0,
             max_attempts: DEFAULT_MAX_PROBE_ATTEMPTS,
+            clock: system_clock(),
         }
     }
 
+    /// Use `clock` for probe timeouts.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn default_discovery() -> Self {
         Self::new(MIN_MTU, MAX_MTU)
     }
 
+    /// Discovery for a path to `peer`, starting from its family's guaranteed
+    /// MTU (see [`min_mtu_for`]). A `max_mtu` below that floor is raised to
+    /// it, since every path of the family carries datagrams that large.
+    pub fn for_peer(peer: &SocketAddr, max_mtu: usize) -> Self {
+        let floor = min_mtu_for(peer);
+        Self::new(floor, max_mtu.max(floor))
+    }
+
     /// Get the next probe size to send, or None if discovery is complete.
     pub fn next_probe(&mut self) -> Option<usize> {
         if self.state == MtuState::Complete || self.attempts >= self.max_attempts {
@@ -301,14 +366,15 @@ impl MtuDiscovery {
             return None;
         }
 
+        let now = self.clock.now();
         if let Some(last) = self.last_probe_time {
-            if last.elapsed() < self.probe_timeout {
+            if now.duration_since(last) < self.probe_timeout {
                 return None;
             }
         }
 
         self.current_probe = (self.min_mtu + self.max_mtu) / 2;
-        self.last_probe_time = Some(Instant::now());
+        self.last_probe_time = Some(now);
         self.attempts += 1;
         Some(self.current_probe)
     }
@@ -341,7 +407,7 @@ impl MtuDiscovery {
             return;
         }
         if let Some(last) = self.last_probe_time {
-            if last.elapsed() >= self.probe_timeout {
+            if self.clock.now().duration_since(last) >= self.probe_timeout {
                 self.on_probe_timeout();
             }
         }
@@ -401,6 +467,27 @@ mod tests {
         assert!(assembler.buffers.is_empty());
     }
 
+    #[test]
+    fn test_fragment_max_buffers_refuses_new_messages() {
+        let mut assembler =
+            FragmentAssembler::new(Duration::from_secs(5), 1024 * 1024).with_max_buffers(1);
+        let first = fragment_message(1, &[1u8; 2000], 1024).unwrap();
+        let second = fragment_message(2, &[2u8; 2000], 1024).unwrap();
+
+        assert!(assembler.process_fragment(&first[0]).is_none());
+        assert!(!assembler.has_room_for(&second[0]));
+        assert!(assembler.process_fragment(&second[0]).is_none());
+        assert!(assembler.has_room_for(&first[1]));
+        assert_eq!(assembler.process_fragment(&first[1]), Some(vec![1u8; 2000]));
+
+        assert!(assembler.has_room_for(&second[0]));
+        assert!(assembler.process_fragment(&second[0]).is_none());
+        assert_eq!(
+            assembler.process_fragment(&second[1]),
+            Some(vec![2u8; 2000])
+        );
+    }
+
     #[test]
     fn test_fragment_various_sizes() {
         for size in [1, 100, 1023, 1024, 1025, 5000, 10000] {
@@ -419,6 +506,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mtu_discovery_ipv6_floor() {
+        let v4: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[::1]:9000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:9000".parse().unwrap();
+
+        assert_eq!(MtuDiscovery::for_peer(&v4, 1400).discovered_mtu(), MIN_MTU);
+        assert_eq!(
+            MtuDiscovery::for_peer(&v6, 1400).discovered_mtu(),
+            MIN_MTU_IPV6
+        );
+        assert_eq!(
+            MtuDiscovery::for_peer(&mapped, 1400).discovered_mtu(),
+            MIN_MTU
+        );
+        // A configured MTU below the IPv6 floor is raised to it.
+        let mut clamped = MtuDiscovery::for_peer(&v6, 1000);
+        assert_eq!(clamped.discovered_mtu(), MIN_MTU_IPV6);
+        assert_eq!(clamped.next_probe(), None);
+    }
+
     #[test]
     fn test_mtu_discovery_converges() {
         let mut mtu = MtuDiscovery::new(576, 1500);
@@ -499,4 +607,21 @@ mod tests {
         assert!(!assembler.buffers.contains_key(&1));
         assert!(assembler.buffers.contains_key(&2));
     }
+
+    #[test]
+    fn test_fragment_timeout_with_manual_clock() {
+        let clock = crate::clock::ManualClock::new();
+        let mut assembler =
+            FragmentAssembler::new(Duration::from_secs(5), 1024 * 1024).with_clock(clock.shared());
+        let fragments = fragment_message(1, &[1u8; 2000], 1024).unwrap();
+
+        assembler.process_fragment(&fragments[0]);
+        clock.advance(Duration::from_secs(4));
+        assembler.cleanup();
+        assert_eq!(assembler.buffers.len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assembler.cleanup();
+        assert!(assembler.buffers.is_empty());
+    }
 }
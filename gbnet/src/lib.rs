@@ -19,7 +19,8 @@
 //! - **Auto fragmentation** with per-fragment retransmission, 32-bit fragment IDs,
 //!   MTU discovery, and probe timeout detection
 //! - **Security**: CRC32C integrity, stateless cookie handshake, IP-based rate
-//!   limiting, deserialization bounds checking, optional AES-256-GCM encryption
+//!   limiting, deserialization bounds checking, optional HMAC authentication or
+//!   AES-256-GCM encryption keyed by an X25519 exchange, with replay protection
 //! - **Delta compression**: `#[derive(NetworkDelta)]` for bitmask-based delta
 //!   encoding with baseline tracking and automatic full-state fallback
 //! - **Replication**: Priority accumulator, radius and grid interest management
@@ -39,7 +40,7 @@
 //! loop {
 //!     for event in server.update() {
 //!         match event {
-//!             ServerEvent::ClientConnected(addr) => println!("Connected: {}", addr),
+//!             ServerEvent::ClientConnected { addr, .. } => println!("Connected: {}", addr),
 //!             ServerEvent::Message { addr, channel, data } => {
 //!                 server.send(addr, channel, &data).ok();
 //!             }
@@ -53,6 +54,7 @@ extern crate self as gbnet;
 
 pub mod channel;
 pub mod client;
+pub mod clock;
 pub mod config;
 pub mod congestion;
 pub mod connection;
@@ -69,6 +71,7 @@ pub mod server;
 pub mod simulator;
 pub mod socket;
 pub mod stats;
+pub mod transport;
 pub mod util;
 pub mod wire;
 
@@ -77,22 +80,33 @@ mod tests;
 
 pub use channel::{Channel, ChannelError};
 pub use client::{ClientEvent, NetClient};
-pub use config::{ChannelConfig, ConfigError, DeliveryMode, NetworkConfig, SimulationConfig};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::{
+    ChannelConfig, ConfigError, DeliveryMode, FloodPolicy, NetworkCondition, NetworkConfig,
+    PacketProtection, ScheduledCondition, SimulationConfig, VersionPolicy,
+};
 pub use congestion::{
-    BandwidthTracker, CongestionController, CongestionMode, CongestionPhase, CongestionWindow,
+    BandwidthLimiter, BandwidthTracker, CongestionController, CongestionMode, CongestionPhase,
+    CongestionWindow,
 };
 pub use connection::{Connection, ConnectionError, ConnectionState, DisconnectReason};
 pub use fragment::{FragmentAssembler, FragmentError, FragmentHeader, MtuDiscovery};
 pub use packet::{Packet, PacketHeader, PacketType};
 pub use reliability::{ReliableEndpoint, SequenceBuffer};
-pub use security::{crc32c, ConnectToken, ConnectionRateLimiter, TokenValidator};
+#[cfg(feature = "encryption")]
+pub use security::ServerIdentity;
+pub use security::{
+    crc32c, AddressFilter, ConnectToken, ConnectTokenData, ConnectionRateLimiter, CookieSecrets,
+    IpRange, IpRangeError, TokenError, TokenValidator,
+};
 pub use server::{NetServer, ServerEvent};
 pub use simulator::NetworkSimulator;
 pub use socket::{SocketError, UdpSocket};
 pub use stats::{
     assess_connection_quality, ChannelStats, ConnectionQuality, NetworkStats, ReliabilityStats,
-    SocketStats,
+    ServerStats, SocketStats,
 };
+pub use transport::Transport;
 pub use util::{sequence_diff, sequence_greater_than};
 
 pub use gbnet_macros::{NetworkDelta, NetworkSerialize};
@@ -8,6 +8,47 @@ use std::io;
 
 pub use crate::util::{sequence_diff, sequence_greater_than};
 
+/// Version of the gbnet wire layout, sent in [`PacketType::ConnectionRequest`].
+/// Bumped whenever the packet format changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// Upper bound on bytes a packet adds around its payload: header and type,
+/// CRC32C, and the key epoch, packet counter and AEAD or MAC tag when packets
+/// are authenticated or encrypted.
+pub const MAX_PACKET_OVERHEAD: usize = 48;
+
+/// Largest application payload a [`PacketType::ConnectionDeny`] carries.
+pub const MAX_DENY_PAYLOAD: usize = 128;
+
+/// Bytes sealing adds to handshake application data on an authenticated or
+/// encrypted session: a `u64` packet counter and a 16-byte tag.
+pub const HANDSHAKE_DATA_SEAL_OVERHEAD: usize = 8 + 16;
+
+/// Largest application payload a client sends with its connection response.
+pub const MAX_CONNECT_DATA: usize = 256;
+
+/// Largest application payload a [`PacketType::ConnectionAccept`] carries.
+/// Sealed, it still fits in [`MAX_DENY_PAYLOAD`], so the accept weighs no
+/// more than the deny [`MIN_HANDSHAKE_REQUEST_SIZE`] is sized for.
+pub const MAX_ACCEPT_PAYLOAD: usize = MAX_DENY_PAYLOAD - HANDSHAKE_DATA_SEAL_OVERHEAD;
+
+/// Size client handshake datagrams are padded to and below which the server
+/// drops them unanswered: that of the largest server handshake reply, a
+/// [`PacketType::ConnectionDeny`] carrying [`MAX_DENY_PAYLOAD`] (header, type,
+/// payload and CRC32C). No reply can then outweigh the request that
+/// triggered it, so a spoofed source gains no amplification. Requests with
+/// a foreign protocol version are still denied, with a reply smaller than
+/// the request.
+pub const MIN_HANDSHAKE_REQUEST_SIZE: usize = 16 + 2 + MAX_DENY_PAYLOAD + 4;
+
+/// Bytes spanning the header and the largest packet type, read by
+/// [`Packet::deserialize_type`].
+const MAX_TYPED_HEADER: usize = 16 + 17;
+
+/// Width of the channel id in [`PacketType::Payload`] and
+/// [`PacketType::BatchedPayload`]; bounds [`crate::config::MAX_CHANNEL_COUNT`].
+pub const CHANNEL_ID_BITS: u32 = 8;
+
 /// Fixed-size header present on every packet: protocol ID, sequence, ack, and ack bitfield.
 #[derive(Debug, Clone, PartialEq, NetworkSerialize)]
 pub struct PacketHeader {
@@ -21,11 +62,29 @@ pub struct PacketHeader {
     pub ack_bits: u64,
 }
 
-/// Discriminated packet type encoded in 4 bits (up to 16 variants).
+impl PacketHeader {
+    /// Header bytes authenticated alongside a sealed packet body, so the
+    /// acks and sequence the receiver acts on cannot be altered in transit.
+    pub fn associated_data(&self) -> [u8; 16] {
+        let mut aad = [0u8; 16];
+        aad[..4].copy_from_slice(&self.protocol_id.to_le_bytes());
+        aad[4..6].copy_from_slice(&self.sequence.to_le_bytes());
+        aad[6..8].copy_from_slice(&self.ack.to_le_bytes());
+        aad[8..].copy_from_slice(&self.ack_bits.to_le_bytes());
+        aad
+    }
+}
+
+/// Discriminated packet type encoded in 5 bits (up to 32 variants).
 #[derive(Debug, Clone, PartialEq, NetworkSerialize)]
-#[bits = 4] // 16 packet types max
+#[bits = 5] // 32 packet types max
 pub enum PacketType {
-    ConnectionRequest,
+    ConnectionRequest {
+        #[bits = 16]
+        protocol_version: u16,
+        #[bits = 32]
+        app_version: u32,
+    },
     ConnectionChallenge {
         #[bits = 64]
         server_salt: u64,
@@ -45,13 +104,13 @@ pub enum PacketType {
     },
     KeepAlive,
     Payload {
-        #[bits = 3]
+        #[bits = 8] // CHANNEL_ID_BITS
         channel: u8,
         #[bits = 1]
         is_fragment: bool,
     },
     BatchedPayload {
-        #[bits = 3]
+        #[bits = 8] // CHANNEL_ID_BITS
         channel: u8,
     },
     MtuProbe {
@@ -75,6 +134,67 @@ pub enum PacketType {
         #[bits = 64]
         cookie_low: u64,
     },
+    /// Messages from any channels packed by [`crate::congestion::pack_channel_messages`].
+    MultiPayload,
+    /// A packet sealed under the connection's session cipher. The payload is
+    /// the encrypted type and payload of the inner packet, which shares this
+    /// header; `epoch` selects the session keys and `counter` is the sender's
+    /// packet counter under them, from which the nonce derives.
+    Encrypted {
+        #[bits = 8]
+        epoch: u8,
+        #[bits = 64]
+        counter: u64,
+    },
+    /// A packet whose type and payload follow in the clear, trailed by a
+    /// truncated MAC over them and this header under the session keys of
+    /// `epoch`.
+    Authenticated {
+        #[bits = 8]
+        epoch: u8,
+        #[bits = 64]
+        counter: u64,
+    },
+    /// Sealed request to switch the session to keys for `epoch`; the payload
+    /// is the sender's fresh key share.
+    RekeyRequest {
+        #[bits = 8]
+        epoch: u8,
+    },
+    /// Sealed answer to [`PacketType::RekeyRequest`], carrying the responder's
+    /// key share for `epoch`.
+    RekeyResponse {
+        #[bits = 8]
+        epoch: u8,
+    },
+}
+
+impl PacketType {
+    /// Whether this is a handshake packet a client sends, padded to
+    /// [`MIN_HANDSHAKE_REQUEST_SIZE`].
+    pub fn is_handshake_request(&self) -> bool {
+        matches!(
+            self,
+            PacketType::ConnectionRequest { .. }
+                | PacketType::ConnectionRequestWithCookie { .. }
+                | PacketType::ConnectionResponse { .. }
+        )
+    }
+
+    /// Whether this is part of the connection handshake. Handshake packets
+    /// precede the session keys and are never sealed.
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            PacketType::ConnectionRequest { .. }
+                | PacketType::ConnectionChallenge { .. }
+                | PacketType::ConnectionResponse { .. }
+                | PacketType::ConnectionAccept
+                | PacketType::ConnectionDeny { .. }
+                | PacketType::ConnectionCookie { .. }
+                | PacketType::ConnectionRequestWithCookie { .. }
+        )
+    }
 }
 
 /// A complete packet: header, type discriminant, and optional payload bytes.
@@ -104,8 +224,17 @@ impl Packet {
     /// Serializes the packet into a byte vector.
     pub fn serialize(&self) -> io::Result<Vec<u8>> {
         let mut buffer = BitBuffer::new();
-
         self.header.bit_serialize(&mut buffer)?;
+        self.finish_serialize(buffer)
+    }
+
+    /// Serializes the packet type and payload without the header, for
+    /// sealing under a session cipher.
+    pub fn serialize_body(&self) -> io::Result<Vec<u8>> {
+        self.finish_serialize(BitBuffer::new())
+    }
+
+    fn finish_serialize(&self, mut buffer: BitBuffer) -> io::Result<Vec<u8>> {
         self.packet_type.bit_serialize(&mut buffer)?;
 
         let padding = (8 - BitWrite::bit_pos(&buffer) % 8) % 8;
@@ -127,9 +256,34 @@ impl Packet {
         }
 
         let mut buffer = BitBuffer::from_bytes(data.to_vec());
+        let header = PacketHeader::bit_deserialize(&mut buffer)?;
+        Self::finish_deserialize(header, buffer, data)
+    }
 
+    /// Reads only the header and type of a serialized packet, leaving the
+    /// payload untouched, to triage datagrams before full deserialization.
+    pub fn deserialize_type(data: &[u8]) -> io::Result<(PacketHeader, PacketType)> {
+        let mut buffer = BitBuffer::from_bytes(data[..data.len().min(MAX_TYPED_HEADER)].to_vec());
         let header = PacketHeader::bit_deserialize(&mut buffer)?;
         let packet_type = PacketType::bit_deserialize(&mut buffer)?;
+        Ok((header, packet_type))
+    }
+
+    /// Deserializes a body produced by [`Packet::serialize_body`], attaching
+    /// the header it was sent under.
+    pub fn deserialize_body(header: PacketHeader, data: &[u8]) -> io::Result<Self> {
+        if data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty packet"));
+        }
+        Self::finish_deserialize(header, BitBuffer::from_bytes(data.to_vec()), data)
+    }
+
+    fn finish_deserialize(
+        header: PacketHeader,
+        mut buffer: BitBuffer,
+        data: &[u8],
+    ) -> io::Result<Self> {
+        let packet_type = PacketType::bit_deserialize(&mut buffer)?;
 
         while !BitRead::bit_pos(&buffer).is_multiple_of(8) {
             buffer.read_bit()?;
@@ -157,6 +311,9 @@ pub mod disconnect_reason {
     pub const KICKED: u8 = 2;
     pub const SERVER_FULL: u8 = 3;
     pub const PROTOCOL_MISMATCH: u8 = 4;
+    pub const VERSION_MISMATCH: u8 = 5;
+    pub const BANNED: u8 = 6;
+    pub const FLOODING: u8 = 7;
 }
 
 /// Well-known connection-deny reason codes sent in [`PacketType::ConnectionDeny`].
@@ -166,4 +323,8 @@ pub mod deny_reason {
     pub const INVALID_PROTOCOL: u8 = 2;
     pub const BANNED: u8 = 3;
     pub const INVALID_CHALLENGE: u8 = 4;
+    pub const VERSION_MISMATCH: u8 = 5;
+    /// Codes from here up are never sent by gbnet itself and are free for
+    /// application-defined denials.
+    pub const FIRST_APPLICATION: u8 = 64;
 }
@@ -1,5 +1,6 @@
 //! Reliable packet delivery with Jacobson/Karels RTT estimation, adaptive RTO,
 //! fast retransmit, and bounded in-flight tracking.
+use crate::clock::{system_clock, SharedClock};
 use crate::stats::ReliabilityStats;
 use crate::util::{sequence_diff, sequence_greater_than};
 use smallvec::SmallVec;
@@ -9,6 +10,9 @@ use std::time::{Duration, Instant};
 /// Result of processing ACKs: (acked channel pairs, fast retransmit candidates).
 pub type AckResult = (SmallVec<[(u8, u16); 8]>, SmallVec<[(u8, u16); 4]>);
 
+/// `(channel_id, channel_sequence)` of every reliable message carried by a packet.
+pub type PacketMessages = SmallVec<[(u8, u16); 4]>;
+
 pub const INITIAL_RTO_MILLIS: u64 = 100;
 pub const ACK_BITS_WINDOW: u16 = 64;
 pub const RTT_ALPHA: f64 = 0.125;
@@ -46,12 +50,13 @@ pub struct ReliableEndpoint {
     packets_evicted: u64,
     bytes_sent: u64,
     bytes_acked: u64,
+
+    clock: SharedClock,
 }
 
 #[derive(Debug, Clone)]
 struct SentPacketRecord {
-    channel_id: u8,
-    channel_sequence: u16,
+    messages: PacketMessages,
     send_time: Instant,
     size: usize,
     nack_count: u8,
@@ -80,6 +85,7 @@ impl ReliableEndpoint {
             packets_evicted: 0,
             bytes_sent: 0,
             bytes_acked: 0,
+            clock: system_clock(),
         }
     }
 
@@ -88,6 +94,12 @@ impl ReliableEndpoint {
         self
     }
 
+    /// Use `clock` to measure RTT samples against recorded send times.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Gets the next sequence number for outgoing packets.
     pub fn next_sequence(&mut self) -> u16 {
         let seq = self.local_sequence;
@@ -95,7 +107,7 @@ impl ReliableEndpoint {
         seq
     }
 
-    /// Records a packet as sent for reliability tracking.
+    /// Records a packet carrying a single reliable message as sent.
     pub fn on_packet_sent(
         &mut self,
         sequence: u16,
@@ -103,6 +115,20 @@ impl ReliableEndpoint {
         channel_id: u8,
         channel_sequence: u16,
         size: usize,
+    ) {
+        let mut messages = PacketMessages::new();
+        messages.push((channel_id, channel_sequence));
+        self.on_packet_sent_with_messages(sequence, send_time, messages, size);
+    }
+
+    /// Records a packet as sent; acking or NACKing it applies to every
+    /// message in `messages`.
+    pub fn on_packet_sent_with_messages(
+        &mut self,
+        sequence: u16,
+        send_time: Instant,
+        messages: PacketMessages,
+        size: usize,
     ) {
         if self.sent_packets.len() >= self.max_in_flight {
             self.evict_worst_in_flight();
@@ -111,8 +137,7 @@ impl ReliableEndpoint {
         self.sent_packets.insert(
             sequence,
             SentPacketRecord {
-                channel_id,
-                channel_sequence,
+                messages,
                 send_time,
                 size,
                 nack_count: 0,
@@ -188,8 +213,8 @@ impl ReliableEndpoint {
 
         // Process acks
         for &seq in &acked_seqs {
-            if let Some(pair) = self.ack_single(seq) {
-                acked.push(pair);
+            if let Some(messages) = self.ack_single(seq) {
+                acked.extend(messages);
             }
         }
 
@@ -214,7 +239,7 @@ impl ReliableEndpoint {
             if let Some(record) = self.sent_packets.get_mut(&seq) {
                 record.nack_count = record.nack_count.saturating_add(1);
                 if record.nack_count == FAST_RETRANSMIT_THRESHOLD {
-                    fast_retransmit.push((record.channel_id, record.channel_sequence));
+                    fast_retransmit.extend(record.messages.iter().copied());
                 }
             }
         }
@@ -222,9 +247,14 @@ impl ReliableEndpoint {
         (acked, fast_retransmit)
     }
 
-    fn ack_single(&mut self, sequence: u16) -> Option<(u8, u16)> {
+    fn ack_single(&mut self, sequence: u16) -> Option<PacketMessages> {
         if let Some(record) = self.sent_packets.remove(&sequence) {
-            let rtt_sample = record.send_time.elapsed().as_secs_f64() * 1000.0;
+            let rtt_sample = self
+                .clock
+                .now()
+                .saturating_duration_since(record.send_time)
+                .as_secs_f64()
+                * 1000.0;
             self.update_rtt(rtt_sample);
 
             self.total_packets_acked += 1;
@@ -232,7 +262,7 @@ impl ReliableEndpoint {
 
             self.record_loss_sample(false);
 
-            Some((record.channel_id, record.channel_sequence))
+            Some(record.messages)
         } else {
             None
         }
@@ -539,6 +569,19 @@ mod tests {
         assert!(acked.contains(&(2, 5)));
     }
 
+    #[test]
+    fn test_ack_covers_every_message_in_packet() {
+        let mut endpoint = ReliableEndpoint::new(256);
+        let now = Instant::now();
+
+        let messages: PacketMessages = [(0, 1), (4, 9), (200, 3)].into_iter().collect();
+        endpoint.on_packet_sent_with_messages(5, now, messages, 300);
+
+        let (acked, _) = endpoint.process_acks(5, 0);
+        assert_eq!(acked.as_slice(), &[(0, 1), (4, 9), (200, 3)]);
+        assert_eq!(endpoint.packets_in_flight(), 0);
+    }
+
     #[test]
     fn test_eviction_does_not_inflate_loss() {
         let mut endpoint = ReliableEndpoint::new(256).with_max_in_flight(4);
@@ -568,4 +611,19 @@ mod tests {
             "Evictions should not affect packet loss percentage"
         );
     }
+
+    #[test]
+    fn test_rtt_sample_uses_clock() {
+        use crate::clock::Clock;
+
+        let clock = crate::clock::ManualClock::new();
+        let mut endpoint = ReliableEndpoint::new(256).with_clock(clock.shared());
+
+        let seq = endpoint.next_sequence();
+        endpoint.on_packet_sent(seq, clock.now(), 0, 0, 100);
+        clock.advance(Duration::from_millis(80));
+        endpoint.process_acks(seq, 0);
+
+        assert_eq!(endpoint.srtt_ms(), 80.0);
+    }
 }
@@ -1,9 +1,12 @@
-//! CRC32C integrity, connect-token authentication, rate limiting, and optional AES-256-GCM encryption.
+//! CRC32C integrity, connect-token authentication, rate limiting, address
+//! filtering, rotating handshake cookie secrets, and optional AES-256-GCM
+//! session encryption with replay protection.
 
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 
+use crate::clock::{system_clock, SharedClock};
 use crate::config::DEFAULT_MAX_TRACKED_TOKENS;
 
 /// CRC-32C (Castagnoli) polynomial used for packet integrity (iSCSI standard).
@@ -52,59 +55,333 @@ pub fn validate_and_strip_crc32(data: &[u8]) -> Option<&[u8]> {
     }
 }
 
+/// Bytes in the private key shared by the token backend and game servers.
+pub const CONNECT_TOKEN_KEY_BYTES: usize = 32;
+/// Bytes in a connect token nonce.
+pub const CONNECT_TOKEN_NONCE_BYTES: usize = 12;
+/// Maximum user data a connect token can carry.
+pub const MAX_CONNECT_TOKEN_USER_DATA: usize = 256;
+/// Maximum server addresses a connect token can list.
+pub const MAX_CONNECT_TOKEN_SERVERS: usize = 8;
+#[cfg(feature = "encryption")]
+const CONNECT_TOKEN_TAG_BYTES: usize = 16;
+
+/// Seconds since the Unix epoch from the system wall clock. Connect tokens
+/// travel between machines, so their timestamps cannot use [`Instant`].
+pub fn unix_time_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 /// Connect token for netcode.io-style authentication.
-#[derive(Debug, Clone)]
+///
+/// A backend that has authenticated the player generates the token with
+/// [`ConnectToken::generate`] and hands [`ConnectToken::to_bytes`] to the
+/// client over a secure channel. The client presents it in its connection
+/// request; only servers holding the private key can open the sealed part,
+/// so clients cannot forge or alter the client id, server list, or user data.
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConnectToken {
+    pub protocol_id: u32,
+    /// Unix time the token was generated.
+    pub create_timestamp: u64,
+    /// Unix time after which servers reject the token.
+    pub expire_timestamp: u64,
+    pub nonce: [u8; CONNECT_TOKEN_NONCE_BYTES],
+    /// Servers the client may connect to, in preference order.
+    pub server_addresses: Vec<SocketAddr>,
+    /// [`ConnectTokenData`] sealed with the private key.
+    pub sealed_data: Vec<u8>,
+}
+
+/// The sealed contents of a [`ConnectToken`], visible only to servers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectTokenData {
     pub client_id: u64,
     pub server_addresses: Vec<SocketAddr>,
-    pub create_time: Instant,
-    pub expire_duration: Duration,
     pub user_data: Vec<u8>,
-    pub token_data: Vec<u8>,
 }
 
 impl ConnectToken {
-    pub fn new(
+    /// Generate a token valid for `expire_secs` seconds, sealing the client
+    /// id, server list, and user data with `private_key`.
+    pub fn generate(
+        private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+        protocol_id: u32,
         client_id: u64,
         server_addresses: Vec<SocketAddr>,
         expire_secs: u64,
-        user_data: Vec<u8>,
-    ) -> Self {
-        let mut token_data = Vec::new();
-        token_data.extend_from_slice(&client_id.to_le_bytes());
-        token_data.extend_from_slice(&expire_secs.to_le_bytes());
-        token_data.extend_from_slice(&(user_data.len() as u32).to_le_bytes());
-        token_data.extend_from_slice(&user_data);
+        user_data: &[u8],
+    ) -> Result<Self, TokenError> {
+        if server_addresses.is_empty()
+            || server_addresses.len() > MAX_CONNECT_TOKEN_SERVERS
+            || user_data.len() > MAX_CONNECT_TOKEN_USER_DATA
+        {
+            return Err(TokenError::Invalid);
+        }
+        let create_timestamp = unix_time_secs();
+        let mut token = Self {
+            protocol_id,
+            create_timestamp,
+            expire_timestamp: create_timestamp.saturating_add(expire_secs),
+            nonce: rand::random(),
+            server_addresses,
+            sealed_data: Vec::new(),
+        };
+        let data = ConnectTokenData {
+            client_id,
+            server_addresses: token.server_addresses.clone(),
+            user_data: user_data.to_vec(),
+        };
+        token.sealed_data = seal_token_data(private_key, &token, &data.to_bytes())?;
+        Ok(token)
+    }
 
-        Self {
+    /// Open the sealed part with `private_key`, failing if the token was
+    /// forged or tampered with.
+    pub fn open(
+        &self,
+        private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    ) -> Result<ConnectTokenData, TokenError> {
+        let plaintext = open_token_data(private_key, self)?;
+        ConnectTokenData::from_bytes(&plaintext).ok_or(TokenError::Invalid)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        unix_time_secs() >= self.expire_timestamp
+    }
+
+    /// Serialize for delivery to the client and inclusion in connection requests.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.sealed_data.len());
+        out.extend_from_slice(&self.protocol_id.to_le_bytes());
+        out.extend_from_slice(&self.create_timestamp.to_le_bytes());
+        out.extend_from_slice(&self.expire_timestamp.to_le_bytes());
+        out.extend_from_slice(&self.nonce);
+        write_token_addresses(&mut out, &self.server_addresses);
+        out.extend_from_slice(&(self.sealed_data.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.sealed_data);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TokenError> {
+        let mut reader = TokenReader::new(data);
+        let token = (|| {
+            let protocol_id = u32::from_le_bytes(reader.take()?);
+            let create_timestamp = u64::from_le_bytes(reader.take()?);
+            let expire_timestamp = u64::from_le_bytes(reader.take()?);
+            let nonce = reader.take()?;
+            let server_addresses = reader.addresses()?;
+            let sealed_len = u16::from_le_bytes(reader.take()?) as usize;
+            let sealed_data = reader.bytes(sealed_len)?.to_vec();
+            Some(Self {
+                protocol_id,
+                create_timestamp,
+                expire_timestamp,
+                nonce,
+                server_addresses,
+                sealed_data,
+            })
+        })();
+        match token {
+            Some(token) if reader.is_empty() => Ok(token),
+            _ => Err(TokenError::Invalid),
+        }
+    }
+
+    /// Public fields authenticated alongside the sealed data, so they cannot
+    /// be altered without invalidating the token.
+    #[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+    fn associated_data(&self) -> [u8; 20] {
+        let mut aad = [0u8; 20];
+        aad[..4].copy_from_slice(&self.protocol_id.to_le_bytes());
+        aad[4..12].copy_from_slice(&self.create_timestamp.to_le_bytes());
+        aad[12..].copy_from_slice(&self.expire_timestamp.to_le_bytes());
+        aad
+    }
+}
+
+impl ConnectTokenData {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.user_data.len());
+        out.extend_from_slice(&self.client_id.to_le_bytes());
+        write_token_addresses(&mut out, &self.server_addresses);
+        out.extend_from_slice(&(self.user_data.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.user_data);
+        out
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let mut reader = TokenReader::new(data);
+        let client_id = u64::from_le_bytes(reader.take()?);
+        let server_addresses = reader.addresses()?;
+        let user_data_len = u16::from_le_bytes(reader.take()?) as usize;
+        if user_data_len > MAX_CONNECT_TOKEN_USER_DATA {
+            return None;
+        }
+        let user_data = reader.bytes(user_data_len)?.to_vec();
+        reader.is_empty().then_some(Self {
             client_id,
             server_addresses,
-            create_time: Instant::now(),
-            expire_duration: Duration::from_secs(expire_secs),
             user_data,
-            token_data,
+        })
+    }
+}
+
+const TOKEN_ADDRESS_V4: u8 = 4;
+const TOKEN_ADDRESS_V6: u8 = 6;
+
+fn write_token_addresses(out: &mut Vec<u8>, addresses: &[SocketAddr]) {
+    out.push(addresses.len() as u8);
+    for addr in addresses {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                out.push(TOKEN_ADDRESS_V4);
+                out.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                out.push(TOKEN_ADDRESS_V6);
+                out.extend_from_slice(&ip.octets());
+            }
         }
+        out.extend_from_slice(&addr.port().to_le_bytes());
     }
+}
 
-    pub fn is_expired(&self) -> bool {
-        self.create_time.elapsed() > self.expire_duration
+/// Bounds-checked cursor over serialized token bytes.
+struct TokenReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TokenReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    fn addresses(&mut self) -> Option<Vec<SocketAddr>> {
+        let count = self.take::<1>()?[0] as usize;
+        if count > MAX_CONNECT_TOKEN_SERVERS {
+            return None;
+        }
+        let mut addresses = Vec::with_capacity(count);
+        for _ in 0..count {
+            let ip = match self.take::<1>()?[0] {
+                TOKEN_ADDRESS_V4 => IpAddr::from(self.take::<4>()?),
+                TOKEN_ADDRESS_V6 => IpAddr::from(self.take::<16>()?),
+                _ => return None,
+            };
+            let port = u16::from_le_bytes(self.take()?);
+            addresses.push(SocketAddr::new(ip, port));
+        }
+        Some(addresses)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 }
 
+#[cfg(feature = "encryption")]
+fn token_key(
+    private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+) -> Result<ring::aead::LessSafeKey, TokenError> {
+    let unbound = ring::aead::UnboundKey::new(&ring::aead::CHACHA20_POLY1305, private_key)
+        .map_err(|_| TokenError::Invalid)?;
+    Ok(ring::aead::LessSafeKey::new(unbound))
+}
+
+/// Seal token data with ChaCha20-Poly1305 under the token's nonce.
+#[cfg(feature = "encryption")]
+fn seal_token_data(
+    private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    token: &ConnectToken,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, TokenError> {
+    let key = token_key(private_key)?;
+    let mut in_out = plaintext.to_vec();
+    in_out.reserve(CONNECT_TOKEN_TAG_BYTES);
+    key.seal_in_place_append_tag(
+        ring::aead::Nonce::assume_unique_for_key(token.nonce),
+        ring::aead::Aad::from(token.associated_data()),
+        &mut in_out,
+    )
+    .map_err(|_| TokenError::Invalid)?;
+    Ok(in_out)
+}
+
+#[cfg(feature = "encryption")]
+fn open_token_data(
+    private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    token: &ConnectToken,
+) -> Result<Vec<u8>, TokenError> {
+    let key = token_key(private_key)?;
+    let mut in_out = token.sealed_data.clone();
+    let plaintext = key
+        .open_in_place(
+            ring::aead::Nonce::assume_unique_for_key(token.nonce),
+            ring::aead::Aad::from(token.associated_data()),
+            &mut in_out,
+        )
+        .map_err(|_| TokenError::Invalid)?;
+    Ok(plaintext.to_vec())
+}
+
+/// Stub for when the encryption feature is not enabled.
+#[cfg(not(feature = "encryption"))]
+fn seal_token_data(
+    _private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    _token: &ConnectToken,
+    _plaintext: &[u8],
+) -> Result<Vec<u8>, TokenError> {
+    Err(TokenError::FeatureNotEnabled)
+}
+
+/// Stub for when the encryption feature is not enabled.
+#[cfg(not(feature = "encryption"))]
+fn open_token_data(
+    _private_key: &[u8; CONNECT_TOKEN_KEY_BYTES],
+    _token: &ConnectToken,
+) -> Result<Vec<u8>, TokenError> {
+    Err(TokenError::FeatureNotEnabled)
+}
+
 /// Server-side connect token validator.
+///
+/// Opens tokens with the private key, checks protocol id, expiry, and that
+/// this server is listed, and rejects a token already used from a different
+/// address. The same client may present its token again, e.g. when resending
+/// a connection request.
 #[derive(Debug)]
 pub struct TokenValidator {
-    used_tokens: HashMap<u64, Instant>,
-    token_lifetime: Duration,
+    private_key: [u8; CONNECT_TOKEN_KEY_BYTES],
+    protocol_id: u32,
+    /// Nonce of each used token → (address it was used from, expire timestamp).
+    used_tokens: HashMap<[u8; CONNECT_TOKEN_NONCE_BYTES], (SocketAddr, u64)>,
     max_tracked_tokens: usize,
     tokens_evicted: u64,
 }
 
 impl TokenValidator {
-    pub fn new(token_lifetime: Duration) -> Self {
+    pub fn new(private_key: [u8; CONNECT_TOKEN_KEY_BYTES], protocol_id: u32) -> Self {
         Self {
+            private_key,
+            protocol_id,
             used_tokens: HashMap::new(),
-            token_lifetime,
             max_tracked_tokens: DEFAULT_MAX_TRACKED_TOKENS,
             tokens_evicted: 0,
         }
@@ -115,17 +392,23 @@ impl TokenValidator {
         self
     }
 
-    /// Validate a connect token. Returns client_id if valid.
-    pub fn validate(&mut self, token: &ConnectToken) -> Result<u64, TokenError> {
-        if token.is_expired() {
-            return Err(TokenError::Expired);
-        }
-
-        if self.used_tokens.contains_key(&token.client_id) {
-            return Err(TokenError::Replayed);
+    /// Validate a token presented by `from` to the server at `server_addr`,
+    /// returning its sealed contents, and record it as used by `from`.
+    ///
+    /// A server bound to an unspecified address matches any listed address
+    /// with the same port.
+    pub fn validate(
+        &mut self,
+        token: &ConnectToken,
+        from: SocketAddr,
+        server_addr: SocketAddr,
+    ) -> Result<ConnectTokenData, TokenError> {
+        let data = self.check(token, from, server_addr)?;
+        if self.used_tokens.contains_key(&token.nonce) {
+            return Ok(data);
         }
-
-        self.used_tokens.insert(token.client_id, Instant::now());
+        self.used_tokens
+            .insert(token.nonce, (from, token.expire_timestamp));
 
         if self.used_tokens.len() > self.max_tracked_tokens {
             self.cleanup();
@@ -134,7 +417,7 @@ impl TokenValidator {
                 let oldest_key = self
                     .used_tokens
                     .iter()
-                    .min_by_key(|(_, instant)| **instant)
+                    .min_by_key(|(_, (_, expires))| *expires)
                     .map(|(&k, _)| k);
                 if let Some(key) = oldest_key {
                     self.used_tokens.remove(&key);
@@ -145,13 +428,43 @@ impl TokenValidator {
             }
         }
 
-        Ok(token.client_id)
+        Ok(data)
     }
 
-    fn cleanup(&mut self) {
-        let lifetime = self.token_lifetime;
-        self.used_tokens
-            .retain(|_, created| created.elapsed() < lifetime);
+    /// Like [`TokenValidator::validate`] but without recording the token as
+    /// used, for requests from an address that has not yet proven it can
+    /// receive at it.
+    pub fn check(
+        &self,
+        token: &ConnectToken,
+        from: SocketAddr,
+        server_addr: SocketAddr,
+    ) -> Result<ConnectTokenData, TokenError> {
+        if token.protocol_id != self.protocol_id {
+            return Err(TokenError::Invalid);
+        }
+        if token.is_expired() {
+            return Err(TokenError::Expired);
+        }
+        let data = token.open(&self.private_key)?;
+        let listed = data.server_addresses.iter().any(|addr| {
+            *addr == server_addr
+                || (server_addr.ip().is_unspecified() && addr.port() == server_addr.port())
+        });
+        if !listed {
+            return Err(TokenError::WrongServer);
+        }
+
+        match self.used_tokens.get(&token.nonce) {
+            Some((used_by, _)) if *used_by != from => Err(TokenError::Replayed),
+            _ => Ok(data),
+        }
+    }
+
+    /// Forget used tokens that have expired; they can no longer be replayed.
+    pub fn cleanup(&mut self) {
+        let now = unix_time_secs();
+        self.used_tokens.retain(|_, (_, expires)| now < *expires);
     }
 
     pub fn tokens_evicted(&self) -> u64 {
@@ -165,6 +478,10 @@ pub enum TokenError {
     Expired,
     Replayed,
     Invalid,
+    /// The token does not list the server it was presented to.
+    WrongServer,
+    #[cfg(not(feature = "encryption"))]
+    FeatureNotEnabled,
 }
 
 impl std::fmt::Display for TokenError {
@@ -173,53 +490,360 @@ impl std::fmt::Display for TokenError {
             TokenError::Expired => write!(f, "Token expired"),
             TokenError::Replayed => write!(f, "Token replayed"),
             TokenError::Invalid => write!(f, "Invalid token"),
+            TokenError::WrongServer => write!(f, "Token not valid for this server"),
+            #[cfg(not(feature = "encryption"))]
+            TokenError::FeatureNotEnabled => write!(f, "Encryption feature not enabled"),
         }
     }
 }
 
 impl std::error::Error for TokenError {}
 
-/// Rate limiter for connection requests per source IP.
-/// Keys on IP address (not socket address) to prevent port-rotation bypass.
+/// Rate limiter for connection requests.
+///
+/// Each request is charged to its source IP (not socket address, so rotating
+/// ports does not help), to the subnet containing that IP and to a
+/// server-wide budget, and is refused if any of them is spent. Varying
+/// addresses within one subnet, or spreading a flood over many subnets,
+/// therefore cannot buy more handshake work than the configured budgets.
 #[derive(Debug)]
 pub struct ConnectionRateLimiter {
     requests: HashMap<IpAddr, Vec<Instant>>,
+    subnet_requests: HashMap<IpRange, Vec<Instant>>,
+    global_requests: VecDeque<Instant>,
     max_requests_per_second: usize,
+    max_subnet_requests_per_second: usize,
+    max_global_requests_per_second: usize,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
     window: Duration,
+    clock: SharedClock,
 }
 
 impl ConnectionRateLimiter {
+    /// A limiter allowing `max_requests_per_second` per IP, with no subnet
+    /// or server-wide budget.
     pub fn new(max_requests_per_second: usize) -> Self {
         Self {
             requests: HashMap::new(),
+            subnet_requests: HashMap::new(),
+            global_requests: VecDeque::new(),
             max_requests_per_second,
+            max_subnet_requests_per_second: 0,
+            max_global_requests_per_second: 0,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
             window: Duration::from_secs(1),
+            clock: system_clock(),
         }
     }
 
-    /// Returns true if the request should be allowed.
+    /// A limiter with the per-IP, subnet and server-wide budgets of `config`.
+    pub fn from_config(config: &crate::NetworkConfig) -> Self {
+        Self::new(config.rate_limit_per_second)
+            .with_subnet_limit(
+                config.subnet_rate_limit_per_second,
+                config.rate_limit_ipv4_prefix,
+                config.rate_limit_ipv6_prefix,
+            )
+            .with_global_limit(config.handshake_rate_limit_per_second)
+    }
+
+    /// Also allow at most `max_per_second` requests from each IPv4 subnet of
+    /// `ipv4_prefix` bits and each IPv6 subnet of `ipv6_prefix` bits. 0 means
+    /// unlimited; prefixes longer than the address are clamped to it.
+    pub fn with_subnet_limit(
+        mut self,
+        max_per_second: usize,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+    ) -> Self {
+        self.max_subnet_requests_per_second = max_per_second;
+        self.ipv4_prefix = ipv4_prefix.min(32);
+        self.ipv6_prefix = ipv6_prefix.min(128);
+        self
+    }
+
+    /// Also allow at most `max_per_second` requests from all sources
+    /// together. 0 means unlimited.
+    pub fn with_global_limit(mut self, max_per_second: usize) -> Self {
+        self.max_global_requests_per_second = max_per_second;
+        self
+    }
+
+    /// Use `clock` for the request windows.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns true if the request should be allowed. Refused requests are
+    /// not charged to any budget.
     pub fn allow(&mut self, addr: SocketAddr) -> bool {
-        let now = Instant::now();
+        let now = self.clock.now();
         let window = self.window;
+        let fresh = |t: &Instant| now.duration_since(*t) < window;
 
-        let timestamps = self.requests.entry(addr.ip()).or_default();
-        timestamps.retain(|t| now.duration_since(*t) < window);
+        while self.global_requests.front().is_some_and(|t| !fresh(t)) {
+            self.global_requests.pop_front();
+        }
+        if self.max_global_requests_per_second > 0
+            && self.global_requests.len() >= self.max_global_requests_per_second
+        {
+            return false;
+        }
 
+        let ip = addr.ip().to_canonical();
+        let subnet = self.subnet_of(ip);
+        let timestamps = self.requests.entry(ip).or_default();
+        timestamps.retain(fresh);
         if timestamps.len() >= self.max_requests_per_second {
-            false
-        } else {
-            timestamps.push(now);
-            true
+            return false;
+        }
+
+        if let Some(subnet) = subnet {
+            let subnet_timestamps = self.subnet_requests.entry(subnet).or_default();
+            subnet_timestamps.retain(fresh);
+            if subnet_timestamps.len() >= self.max_subnet_requests_per_second {
+                return false;
+            }
+            subnet_timestamps.push(now);
+        }
+        timestamps.push(now);
+        self.global_requests.push_back(now);
+        true
+    }
+
+    /// The subnet `ip` is charged to, or `None` without a subnet budget.
+    fn subnet_of(&self, ip: IpAddr) -> Option<IpRange> {
+        if self.max_subnet_requests_per_second == 0 {
+            return None;
         }
+        let prefix_len = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        IpRange::new(ip, prefix_len).ok()
     }
 
     pub fn cleanup(&mut self) {
-        let now = Instant::now();
+        let now = self.clock.now();
         let window = self.window;
         self.requests.retain(|_, timestamps| {
             timestamps.retain(|t| now.duration_since(*t) < window);
             !timestamps.is_empty()
         });
+        self.subnet_requests.retain(|_, timestamps| {
+            timestamps.retain(|t| now.duration_since(*t) < window);
+            !timestamps.is_empty()
+        });
+    }
+}
+
+/// A CIDR block of IPv4 or IPv6 addresses, e.g. `10.0.0.0/8` or
+/// `2001:db8::/32`. A single address is a block of prefix length 32 or 128.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// The block of `prefix_len` leading bits of `addr`; host bits are
+    /// cleared. Fails if `prefix_len` exceeds the address width.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, IpRangeError> {
+        let addr = addr.to_canonical();
+        let width = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > width {
+            return Err(IpRangeError::InvalidPrefix);
+        }
+        let network = match addr {
+            IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & prefix_mask_v4(prefix_len)).into(),
+            IpAddr::V6(v6) => Ipv6Addr::from(u128::from(v6) & prefix_mask_v6(prefix_len)).into(),
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `ip` falls inside this block. IPv4-mapped IPv6 addresses match
+    /// IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & prefix_mask_v4(self.prefix_len) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & prefix_mask_v6(self.prefix_len) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        Self {
+            network: addr,
+            prefix_len,
+        }
+    }
+}
+
+impl std::str::FromStr for IpRange {
+    type Err = IpRangeError;
+
+    /// Parses `addr/prefix` or a bare address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse().map_err(|_| IpRangeError::InvalidAddress)?;
+                let prefix = prefix.parse().map_err(|_| IpRangeError::InvalidPrefix)?;
+                IpRange::new(addr, prefix)
+            }
+            None => s
+                .parse::<IpAddr>()
+                .map(IpRange::from)
+                .map_err(|_| IpRangeError::InvalidAddress),
+        }
+    }
+}
+
+impl std::fmt::Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+fn prefix_mask_v4(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn prefix_mask_v6(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+/// Errors returned when building or parsing an [`IpRange`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpRangeError {
+    InvalidAddress,
+    /// The prefix length is not a number or exceeds the address width.
+    InvalidPrefix,
+}
+
+impl std::fmt::Display for IpRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpRangeError::InvalidAddress => write!(f, "Invalid IP address"),
+            IpRangeError::InvalidPrefix => write!(f, "Invalid prefix length"),
+        }
+    }
+}
+
+impl std::error::Error for IpRangeError {}
+
+#[derive(Debug)]
+struct Ban {
+    range: IpRange,
+    expires_at: Option<Instant>,
+}
+
+/// Source address filter: a ban list of [`IpRange`]s with optional expiry,
+/// and an allow list that, once non-empty, blocks every address outside it.
+#[derive(Debug)]
+pub struct AddressFilter {
+    bans: Vec<Ban>,
+    allowed: Vec<IpRange>,
+    clock: SharedClock,
+}
+
+impl Default for AddressFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressFilter {
+    pub fn new() -> Self {
+        Self {
+            bans: Vec::new(),
+            allowed: Vec::new(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Use `clock` for ban expiry.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Ban `range`, for `duration` or until [`AddressFilter::unban`] if
+    /// `None`. Banning a range again replaces its expiry.
+    pub fn ban(&mut self, range: IpRange, duration: Option<Duration>) {
+        let expires_at = duration.map(|d| self.clock.now() + d);
+        self.bans.retain(|ban| ban.range != range);
+        self.bans.push(Ban { range, expires_at });
+    }
+
+    /// Lift the ban on exactly `range`. Returns false if it was not banned.
+    pub fn unban(&mut self, range: IpRange) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.range != range);
+        self.bans.len() != before
+    }
+
+    /// Add `range` to the allow list.
+    pub fn allow(&mut self, range: IpRange) {
+        if !self.allowed.contains(&range) {
+            self.allowed.push(range);
+        }
+    }
+
+    /// Remove exactly `range` from the allow list. Returns false if it was
+    /// not listed.
+    pub fn disallow(&mut self, range: IpRange) -> bool {
+        let before = self.allowed.len();
+        self.allowed.retain(|allowed| *allowed != range);
+        self.allowed.len() != before
+    }
+
+    /// Whether `ip` is covered by an unexpired ban.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = self.clock.now();
+        self.bans
+            .iter()
+            .any(|ban| ban.range.contains(ip) && ban.expires_at.is_none_or(|at| now < at))
+    }
+
+    /// Whether traffic from `ip` must be dropped: it is banned, or an allow
+    /// list is set and does not cover it.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|range| range.contains(ip)) {
+            return true;
+        }
+        self.is_banned(ip)
+    }
+
+    /// Forget expired bans.
+    pub fn cleanup(&mut self) {
+        let now = self.clock.now();
+        self.bans
+            .retain(|ban| ban.expires_at.is_none_or(|at| now < at));
     }
 }
 
@@ -301,6 +925,176 @@ pub fn cookie_from_u64_pair(high: u64, low: u64) -> [u8; COOKIE_SIZE] {
     cookie
 }
 
+/// Draw a 32-byte secret from the operating system's CSPRNG.
+pub fn random_secret() -> Option<[u8; 32]> {
+    use rand::TryRngCore;
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.try_fill_bytes(&mut secret).ok()?;
+    Some(secret)
+}
+
+/// Rotating secrets keying stateless handshake cookies.
+///
+/// A new secret takes over at every multiple of the rotation interval in
+/// wall-clock time, and the one before it stays valid for one cookie window
+/// so cookies issued just before a rotation still validate. Secrets are drawn
+/// from the OS CSPRNG, or derived from a shared secret and the rotation epoch
+/// so that servers behind one address accept each other's cookies.
+#[derive(Debug)]
+pub struct CookieSecrets {
+    shared: Option<[u8; 32]>,
+    window_secs: u64,
+    rotation_secs: u64,
+    epoch: u64,
+    current: [u8; 32],
+    previous: Option<[u8; 32]>,
+}
+
+impl CookieSecrets {
+    /// Secrets for the rotation epoch containing `now_secs`. Returns `None`
+    /// if the OS CSPRNG fails, or if a shared secret is given without the
+    /// `encryption` feature.
+    pub fn new(
+        shared: Option<[u8; 32]>,
+        window: Duration,
+        rotation: Duration,
+        now_secs: u64,
+    ) -> Option<Self> {
+        let rotation_secs = rotation.as_secs().max(1);
+        let epoch = now_secs / rotation_secs;
+        let current = epoch_secret(shared.as_ref(), epoch)?;
+        let previous = shared_previous_secret(shared.as_ref(), epoch);
+        Some(Self {
+            shared,
+            window_secs: window.as_secs().max(1),
+            rotation_secs,
+            epoch,
+            current,
+            previous,
+        })
+    }
+
+    /// Move to the rotation epoch containing `now_secs`, if it changed.
+    /// Returns false if a fresh secret could not be drawn, keeping the
+    /// current one.
+    pub fn rotate(&mut self, now_secs: u64) -> bool {
+        let epoch = now_secs / self.rotation_secs;
+        if epoch == self.epoch {
+            return true;
+        }
+        let Some(current) = epoch_secret(self.shared.as_ref(), epoch) else {
+            return false;
+        };
+        self.previous = if epoch == self.epoch + 1 {
+            Some(self.current)
+        } else {
+            shared_previous_secret(self.shared.as_ref(), epoch)
+        };
+        self.current = current;
+        self.epoch = epoch;
+        true
+    }
+
+    /// The cookie for `addr` at `now_secs`.
+    pub fn generate(&self, addr: &SocketAddr, now_secs: u64) -> [u8; COOKIE_SIZE] {
+        generate_cookie(addr, now_secs / self.window_secs, &self.current)
+    }
+
+    /// Whether `cookie` was issued to `addr` within the last cookie window,
+    /// under the current secret or, for one window after a rotation, the
+    /// previous one.
+    pub fn validate(&self, cookie: &[u8; COOKIE_SIZE], addr: &SocketAddr, now_secs: u64) -> bool {
+        if validate_cookie(cookie, addr, now_secs, &self.current, self.window_secs) {
+            return true;
+        }
+        let since_rotation = now_secs.saturating_sub(self.epoch * self.rotation_secs);
+        match self.previous {
+            Some(ref previous) if since_rotation < self.window_secs => {
+                validate_cookie(cookie, addr, now_secs, previous, self.window_secs)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The cookie secret for rotation `epoch`: derived from `shared` when given,
+/// otherwise fresh from the OS CSPRNG.
+fn epoch_secret(shared: Option<&[u8; 32]>, epoch: u64) -> Option<[u8; 32]> {
+    match shared {
+        Some(shared) => derive_cookie_secret(shared, epoch),
+        None => random_secret(),
+    }
+}
+
+/// The secret of the epoch before `epoch`, which only a shared secret can
+/// recreate.
+fn shared_previous_secret(shared: Option<&[u8; 32]>, epoch: u64) -> Option<[u8; 32]> {
+    derive_cookie_secret(shared?, epoch.checked_sub(1)?)
+}
+
+#[cfg(feature = "encryption")]
+fn derive_cookie_secret(shared: &[u8; 32], epoch: u64) -> Option<[u8; 32]> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, shared);
+    let mut context = ring::hmac::Context::with_key(&key);
+    context.update(b"gbnet cookie secret");
+    context.update(&epoch.to_le_bytes());
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(context.sign().as_ref());
+    Some(secret)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn derive_cookie_secret(_shared: &[u8; 32], _epoch: u64) -> Option<[u8; 32]> {
+    None
+}
+
+/// Packet counters remembered for replay detection; anything older than the
+/// window is rejected outright.
+pub const REPLAY_WINDOW_SIZE: usize = 256;
+
+/// Sliding-window replay detection over 64-bit packet counters.
+///
+/// Check with [`ReplayWindow::is_replayed`] before authenticating a packet and
+/// [`ReplayWindow::mark`] only after it authenticates, so forged counters
+/// cannot poison the window.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    most_recent: u64,
+    /// Counter last accepted in each slot; `u64::MAX` marks an empty slot.
+    received: [u64; REPLAY_WINDOW_SIZE],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            most_recent: 0,
+            received: [u64::MAX; REPLAY_WINDOW_SIZE],
+        }
+    }
+
+    /// Returns true if `counter` was already accepted or is too old to tell.
+    pub fn is_replayed(&self, counter: u64) -> bool {
+        if counter == u64::MAX
+            || counter.saturating_add(REPLAY_WINDOW_SIZE as u64) <= self.most_recent
+        {
+            return true;
+        }
+        self.received[counter as usize % REPLAY_WINDOW_SIZE] == counter
+    }
+
+    /// Record `counter` as accepted.
+    pub fn mark(&mut self, counter: u64) {
+        self.most_recent = self.most_recent.max(counter);
+        self.received[counter as usize % REPLAY_WINDOW_SIZE] = counter;
+    }
+}
+
 /// AES-256-GCM authenticated encryption (requires `encryption` feature).
 /// Nonce is derived from the packet sequence number for replay protection.
 #[cfg(feature = "encryption")]
@@ -320,10 +1114,14 @@ impl EncryptionState {
     pub fn new(key_bytes: &[u8; 32]) -> Result<Self, EncryptionError> {
         let unbound = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, key_bytes)
             .map_err(|_| EncryptionError::InvalidKey)?;
-        Ok(Self {
+        Ok(Self::from_unbound(unbound))
+    }
+
+    fn from_unbound(unbound: ring::aead::UnboundKey) -> Self {
+        Self {
             key: ring::aead::LessSafeKey::new(unbound),
             connection_salt: 0,
-        })
+        }
     }
 
     /// Set the connection salt (should be `client_salt ^ server_salt` after handshake).
@@ -333,6 +1131,21 @@ impl EncryptionState {
 
     /// Encrypt payload using AES-256-GCM with sequence-derived nonce.
     pub fn encrypt(&self, payload: &[u8], sequence: u64) -> Result<Vec<u8>, EncryptionError> {
+        self.seal(payload, sequence, &[])
+    }
+
+    /// Decrypt payload using AES-256-GCM with sequence-derived nonce.
+    pub fn decrypt(&self, ciphertext: &[u8], sequence: u64) -> Result<Vec<u8>, EncryptionError> {
+        self.open(ciphertext, sequence, &[])
+    }
+
+    /// Encrypt `payload` and authenticate it together with `aad`.
+    pub fn seal(
+        &self,
+        payload: &[u8],
+        sequence: u64,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
         let nonce = self.make_nonce(sequence);
         let nonce = ring::aead::Nonce::try_assume_unique_for_key(&nonce)
             .map_err(|_| EncryptionError::NonceError)?;
@@ -341,14 +1154,19 @@ impl EncryptionState {
         in_out.reserve(AES_GCM_TAG_LEN);
 
         self.key
-            .seal_in_place_append_tag(nonce, ring::aead::Aad::empty(), &mut in_out)
+            .seal_in_place_append_tag(nonce, ring::aead::Aad::from(aad), &mut in_out)
             .map_err(|_| EncryptionError::EncryptFailed)?;
 
         Ok(in_out)
     }
 
-    /// Decrypt payload using AES-256-GCM with sequence-derived nonce.
-    pub fn decrypt(&self, ciphertext: &[u8], sequence: u64) -> Result<Vec<u8>, EncryptionError> {
+    /// Decrypt `ciphertext`, failing unless it and `aad` are authentic.
+    pub fn open(
+        &self,
+        ciphertext: &[u8],
+        sequence: u64,
+        aad: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
         if ciphertext.len() < AES_GCM_TAG_LEN {
             return Err(EncryptionError::DecryptFailed);
         }
@@ -360,7 +1178,7 @@ impl EncryptionState {
         let mut in_out = ciphertext.to_vec();
         let plaintext = self
             .key
-            .open_in_place(nonce, ring::aead::Aad::empty(), &mut in_out)
+            .open_in_place(nonce, ring::aead::Aad::from(aad), &mut in_out)
             .map_err(|_| EncryptionError::DecryptFailed)?;
 
         Ok(plaintext.to_vec())
@@ -377,6 +1195,367 @@ impl EncryptionState {
     }
 }
 
+/// Which end of a connection a [`PacketCipher`] protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRole {
+    Client,
+    Server,
+}
+
+#[cfg(feature = "encryption")]
+const CLIENT_TO_SERVER_INFO: &[u8] = b"gbnet client to server";
+#[cfg(feature = "encryption")]
+const SERVER_TO_CLIENT_INFO: &[u8] = b"gbnet server to client";
+#[cfg(feature = "encryption")]
+const CLIENT_TO_SERVER_MAC_INFO: &[u8] = b"gbnet client to server mac";
+#[cfg(feature = "encryption")]
+const SERVER_TO_CLIENT_MAC_INFO: &[u8] = b"gbnet server to client mac";
+
+/// Bytes of HMAC-SHA256 kept as the tag of an authenticated packet.
+pub const MAC_TAG_BYTES: usize = 16;
+
+/// How one direction of a session protects packets.
+#[cfg(feature = "encryption")]
+enum DirectionKey {
+    Seal(Box<EncryptionState>),
+    Mac(ring::hmac::Key),
+}
+
+/// Per-connection packet protection for an established session.
+///
+/// Each direction has its own key, derived with HKDF-SHA256 from the session
+/// secret and both handshake salts, so no two connections or directions
+/// share a key. Packets are either sealed with AES-256-GCM or, when only
+/// authentication is wanted, sent in the clear followed by a truncated
+/// HMAC-SHA256 tag. Nonces and MACs cover a 64-bit packet counter that never
+/// wraps: once exhausted the cipher refuses to seal. Received counters pass
+/// through a [`ReplayWindow`].
+#[cfg(feature = "encryption")]
+pub struct PacketCipher {
+    send: DirectionKey,
+    recv: DirectionKey,
+    next_counter: u64,
+    replay: ReplayWindow,
+}
+
+#[cfg(feature = "encryption")]
+impl PacketCipher {
+    /// A cipher that encrypts and authenticates with AES-256-GCM.
+    pub fn new(
+        secret: &[u8],
+        client_salt: u64,
+        server_salt: u64,
+        role: SessionRole,
+    ) -> Result<Self, EncryptionError> {
+        Self::derive(secret, client_salt, server_salt, role, false)
+    }
+
+    /// A cipher that leaves payloads readable and appends a truncated
+    /// HMAC-SHA256 tag.
+    pub fn authenticating(
+        secret: &[u8],
+        client_salt: u64,
+        server_salt: u64,
+        role: SessionRole,
+    ) -> Result<Self, EncryptionError> {
+        Self::derive(secret, client_salt, server_salt, role, true)
+    }
+
+    fn derive(
+        secret: &[u8],
+        client_salt: u64,
+        server_salt: u64,
+        role: SessionRole,
+        mac: bool,
+    ) -> Result<Self, EncryptionError> {
+        let mut salt = [0u8; 16];
+        salt[..8].copy_from_slice(&client_salt.to_le_bytes());
+        salt[8..].copy_from_slice(&server_salt.to_le_bytes());
+        let prk = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &salt).extract(secret);
+        let derive = |info: &[u8]| -> Result<DirectionKey, EncryptionError> {
+            let info = [info];
+            if mac {
+                let okm = prk
+                    .expand(&info, ring::hmac::HMAC_SHA256)
+                    .map_err(|_| EncryptionError::InvalidKey)?;
+                return Ok(DirectionKey::Mac(okm.into()));
+            }
+            let okm = prk
+                .expand(&info, &ring::aead::AES_256_GCM)
+                .map_err(|_| EncryptionError::InvalidKey)?;
+            Ok(DirectionKey::Seal(Box::new(EncryptionState::from_unbound(
+                okm.into(),
+            ))))
+        };
+        let (client_to_server, server_to_client) = if mac {
+            (
+                derive(CLIENT_TO_SERVER_MAC_INFO)?,
+                derive(SERVER_TO_CLIENT_MAC_INFO)?,
+            )
+        } else {
+            (
+                derive(CLIENT_TO_SERVER_INFO)?,
+                derive(SERVER_TO_CLIENT_INFO)?,
+            )
+        };
+        let (send, recv) = match role {
+            SessionRole::Client => (client_to_server, server_to_client),
+            SessionRole::Server => (server_to_client, client_to_server),
+        };
+        Ok(Self {
+            send,
+            recv,
+            next_counter: 0,
+            replay: ReplayWindow::new(),
+        })
+    }
+
+    /// Protect `plaintext`, authenticating `aad` with it. Returns the packet
+    /// counter the receiver needs to open it.
+    pub fn seal(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<(u64, Vec<u8>), EncryptionError> {
+        let counter = self.next_counter;
+        self.next_counter = counter
+            .checked_add(1)
+            .ok_or(EncryptionError::CounterExhausted)?;
+        let sealed = match &self.send {
+            DirectionKey::Seal(state) => state.seal(plaintext, counter, aad)?,
+            DirectionKey::Mac(key) => {
+                let mut packet = plaintext.to_vec();
+                packet.extend_from_slice(&mac_tag(key, counter, aad, plaintext));
+                packet
+            }
+        };
+        Ok((counter, sealed))
+    }
+
+    /// Decrypt a packet sealed by the peer under `counter`, rejecting
+    /// forgeries and counters already received.
+    pub fn open(
+        &mut self,
+        counter: u64,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        if self.replay.is_replayed(counter) {
+            return Err(EncryptionError::Replayed);
+        }
+        let plaintext = match &self.recv {
+            DirectionKey::Seal(state) => state.open(ciphertext, counter, aad)?,
+            DirectionKey::Mac(key) => {
+                let split = ciphertext
+                    .len()
+                    .checked_sub(MAC_TAG_BYTES)
+                    .ok_or(EncryptionError::DecryptFailed)?;
+                let (plaintext, tag) = ciphertext.split_at(split);
+                let expected = mac_tag(key, counter, aad, plaintext);
+                let difference = tag
+                    .iter()
+                    .zip(expected.iter())
+                    .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+                if difference != 0 {
+                    return Err(EncryptionError::DecryptFailed);
+                }
+                plaintext.to_vec()
+            }
+        };
+        self.replay.mark(counter);
+        Ok(plaintext)
+    }
+
+    /// Packets sealed so far.
+    pub fn packets_sealed(&self) -> u64 {
+        self.next_counter
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let algorithm = match self.send {
+            DirectionKey::Seal(_) => "AES-256-GCM",
+            DirectionKey::Mac(_) => "HMAC-SHA256",
+        };
+        f.debug_struct("PacketCipher")
+            .field("algorithm", &algorithm)
+            .field("next_counter", &self.next_counter)
+            .finish()
+    }
+}
+
+/// Truncated HMAC-SHA256 over the packet counter, header and body.
+#[cfg(feature = "encryption")]
+fn mac_tag(
+    key: &ring::hmac::Key,
+    counter: u64,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> [u8; MAC_TAG_BYTES] {
+    let mut context = ring::hmac::Context::with_key(key);
+    context.update(&counter.to_le_bytes());
+    context.update(aad);
+    context.update(plaintext);
+    let mut tag = [0u8; MAC_TAG_BYTES];
+    tag.copy_from_slice(&context.sign().as_ref()[..MAC_TAG_BYTES]);
+    tag
+}
+
+/// Bytes in an X25519 key share.
+pub const KEY_SHARE_BYTES: usize = 32;
+/// Bytes in a server identity public key (Ed25519).
+pub const SERVER_IDENTITY_KEY_BYTES: usize = 32;
+/// Bytes in a server identity signature over a key share (Ed25519).
+pub const KEY_SHARE_SIGNATURE_BYTES: usize = 64;
+
+#[cfg(feature = "encryption")]
+const KEY_SHARE_SIGNATURE_CONTEXT: &[u8] = b"gbnet key share";
+
+/// One side's ephemeral X25519 key for a session key exchange.
+///
+/// The public half travels in the challenge (server) or response (client);
+/// the private half is consumed by [`KeyShare::agree`], so every session's
+/// secret is forgotten once its cipher is derived.
+pub struct KeyShare {
+    #[cfg(feature = "encryption")]
+    private: ring::agreement::EphemeralPrivateKey,
+    public: [u8; KEY_SHARE_BYTES],
+}
+
+impl KeyShare {
+    #[cfg(feature = "encryption")]
+    pub fn generate() -> Result<Self, EncryptionError> {
+        let rng = ring::rand::SystemRandom::new();
+        let private =
+            ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+                .map_err(|_| EncryptionError::InvalidKey)?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| EncryptionError::InvalidKey)?
+            .as_ref()
+            .try_into()
+            .map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(Self { private, public })
+    }
+
+    /// Stub for when the encryption feature is not enabled.
+    #[cfg(not(feature = "encryption"))]
+    pub fn generate() -> Result<Self, EncryptionError> {
+        Err(EncryptionError::FeatureNotEnabled)
+    }
+
+    pub fn public_key(&self) -> &[u8; KEY_SHARE_BYTES] {
+        &self.public
+    }
+
+    /// Complete the exchange with the peer's public share, returning the
+    /// session secret. A pre-shared `psk` is mixed in, so a peer without it
+    /// derives a different secret.
+    #[cfg(feature = "encryption")]
+    pub fn agree(
+        self,
+        peer_public: &[u8],
+        psk: Option<&[u8; 32]>,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let peer = ring::agreement::UnparsedPublicKey::new(&ring::agreement::X25519, peer_public);
+        ring::agreement::agree_ephemeral(self.private, &peer, |shared| {
+            let mut secret = shared.to_vec();
+            if let Some(psk) = psk {
+                secret.extend_from_slice(psk);
+            }
+            secret
+        })
+        .map_err(|_| EncryptionError::InvalidKey)
+    }
+}
+
+impl std::fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyShare")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// What a server identity signs: the key share bound to the handshake it was
+/// issued in.
+#[cfg(feature = "encryption")]
+fn key_share_message(protocol_id: u32, server_salt: u64, share: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(KEY_SHARE_SIGNATURE_CONTEXT.len() + 12 + share.len());
+    message.extend_from_slice(KEY_SHARE_SIGNATURE_CONTEXT);
+    message.extend_from_slice(&protocol_id.to_le_bytes());
+    message.extend_from_slice(&server_salt.to_le_bytes());
+    message.extend_from_slice(share);
+    message
+}
+
+/// A server's long-term Ed25519 identity. It signs each challenge's key
+/// share so clients pinning its public key can detect a man in the middle.
+#[cfg(feature = "encryption")]
+pub struct ServerIdentity {
+    key_pair: ring::signature::Ed25519KeyPair,
+}
+
+#[cfg(feature = "encryption")]
+impl ServerIdentity {
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Self, EncryptionError> {
+        let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(Self { key_pair })
+    }
+
+    /// The public key clients pin.
+    pub fn public_key(&self) -> [u8; SERVER_IDENTITY_KEY_BYTES] {
+        use ring::signature::KeyPair;
+        let mut public = [0u8; SERVER_IDENTITY_KEY_BYTES];
+        public.copy_from_slice(self.key_pair.public_key().as_ref());
+        public
+    }
+
+    pub fn sign_key_share(
+        &self,
+        protocol_id: u32,
+        server_salt: u64,
+        share: &[u8; KEY_SHARE_BYTES],
+    ) -> [u8; KEY_SHARE_SIGNATURE_BYTES] {
+        let signature = self
+            .key_pair
+            .sign(&key_share_message(protocol_id, server_salt, share));
+        let mut bytes = [0u8; KEY_SHARE_SIGNATURE_BYTES];
+        bytes.copy_from_slice(signature.as_ref());
+        bytes
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for ServerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerIdentity")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+/// Check a challenge key share against the signature of a pinned server
+/// identity.
+#[cfg(feature = "encryption")]
+pub fn verify_key_share(
+    pinned: &[u8; SERVER_IDENTITY_KEY_BYTES],
+    protocol_id: u32,
+    server_salt: u64,
+    share: &[u8],
+    signature: &[u8],
+) -> bool {
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, pinned)
+        .verify(
+            &key_share_message(protocol_id, server_salt, share),
+            signature,
+        )
+        .is_ok()
+}
+
 #[cfg(feature = "encryption")]
 impl std::fmt::Debug for EncryptionState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -393,6 +1572,10 @@ pub enum EncryptionError {
     NonceError,
     EncryptFailed,
     DecryptFailed,
+    /// The packet counter was already received or fell behind the replay window.
+    Replayed,
+    /// The 64-bit packet counter ran out; the session must not send again.
+    CounterExhausted,
     #[cfg(not(feature = "encryption"))]
     FeatureNotEnabled,
 }
@@ -404,6 +1587,8 @@ impl std::fmt::Display for EncryptionError {
             EncryptionError::NonceError => write!(f, "Nonce generation error"),
             EncryptionError::EncryptFailed => write!(f, "Encryption failed"),
             EncryptionError::DecryptFailed => write!(f, "Decryption failed (authentication)"),
+            EncryptionError::Replayed => write!(f, "Packet replayed"),
+            EncryptionError::CounterExhausted => write!(f, "Packet counter exhausted"),
             #[cfg(not(feature = "encryption"))]
             EncryptionError::FeatureNotEnabled => {
                 write!(f, "Encryption feature not enabled")
@@ -474,36 +1659,149 @@ mod tests {
         assert!(validate_and_strip_crc32(&data).is_none());
     }
 
+    const TOKEN_KEY: [u8; CONNECT_TOKEN_KEY_BYTES] = [0x5au8; CONNECT_TOKEN_KEY_BYTES];
+    const TOKEN_PROTOCOL: u32 = 0x1234;
+
+    #[cfg(feature = "encryption")]
+    fn server_addr() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn client_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn token(client_id: u64) -> ConnectToken {
+        ConnectToken::generate(
+            &TOKEN_KEY,
+            TOKEN_PROTOCOL,
+            client_id,
+            vec![server_addr()],
+            60,
+            b"player",
+        )
+        .unwrap()
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_connect_token_roundtrip() {
+        let token = token(42);
+        let parsed = ConnectToken::from_bytes(&token.to_bytes()).unwrap();
+        assert_eq!(parsed, token);
+
+        let data = parsed.open(&TOKEN_KEY).unwrap();
+        assert_eq!(data.client_id, 42);
+        assert_eq!(data.server_addresses, vec![server_addr()]);
+        assert_eq!(data.user_data, b"player");
+
+        let bytes = token.to_bytes();
+        assert!(ConnectToken::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_connect_token_tamper_rejected() {
+        let token = token(42);
+        assert!(matches!(
+            token.open(&[0u8; CONNECT_TOKEN_KEY_BYTES]),
+            Err(TokenError::Invalid)
+        ));
+
+        let mut extended = token.clone();
+        extended.expire_timestamp += 3600;
+        assert!(extended.open(&TOKEN_KEY).is_err());
+
+        let mut flipped = token;
+        flipped.sealed_data[0] ^= 0x01;
+        assert!(flipped.open(&TOKEN_KEY).is_err());
+    }
+
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_connect_token_expiry() {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
-        let token = ConnectToken {
-            client_id: 42,
-            server_addresses: vec![addr],
-            create_time: Instant::now() - Duration::from_secs(10),
-            expire_duration: Duration::from_secs(5),
-            user_data: vec![],
-            token_data: vec![],
-        };
-        assert!(token.is_expired());
+        let mut validator = TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL);
+        let expired =
+            ConnectToken::generate(&TOKEN_KEY, TOKEN_PROTOCOL, 42, vec![server_addr()], 0, &[])
+                .unwrap();
+        assert!(expired.is_expired());
+        assert!(matches!(
+            validator.validate(&expired, client_addr(1), server_addr()),
+            Err(TokenError::Expired)
+        ));
+        assert!(!token(42).is_expired());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_token_validator_checks_server_and_protocol() {
+        let token = token(42);
+        let other_server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4321);
+        let wildcard = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1234);
 
-        let fresh_token = ConnectToken::new(42, vec![addr], 60, vec![]);
-        assert!(!fresh_token.is_expired());
+        let mut validator = TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL);
+        assert!(matches!(
+            validator.validate(&token, client_addr(1), other_server),
+            Err(TokenError::WrongServer)
+        ));
+        assert!(validator.validate(&token, client_addr(1), wildcard).is_ok());
+
+        let mut other_protocol = TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL + 1);
+        assert!(matches!(
+            other_protocol.validate(&token, client_addr(1), server_addr()),
+            Err(TokenError::Invalid)
+        ));
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_token_replay_rejection() {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
-        let mut validator = TokenValidator::new(Duration::from_secs(60));
+        let mut validator = TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL);
+        let token = token(42);
+
+        assert!(validator
+            .validate(&token, client_addr(1), server_addr())
+            .is_ok());
+        // The same client may resend its request with the same token.
+        assert!(validator
+            .validate(&token, client_addr(1), server_addr())
+            .is_ok());
+        assert!(matches!(
+            validator.validate(&token, client_addr(2), server_addr()),
+            Err(TokenError::Replayed)
+        ));
+    }
 
-        let token = ConnectToken::new(42, vec![addr], 60, vec![]);
-        assert!(validator.validate(&token).is_ok());
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_token_check_does_not_record_use() {
+        let mut validator = TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL);
+        let token = token(42);
+
+        assert!(validator
+            .check(&token, client_addr(1), server_addr())
+            .is_ok());
+        assert!(validator
+            .validate(&token, client_addr(2), server_addr())
+            .is_ok());
         assert!(matches!(
-            validator.validate(&token),
+            validator.check(&token, client_addr(1), server_addr()),
             Err(TokenError::Replayed)
         ));
     }
 
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn test_connect_token_requires_feature() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+        assert!(matches!(
+            ConnectToken::generate(&TOKEN_KEY, TOKEN_PROTOCOL, 42, vec![addr], 60, &[]),
+            Err(TokenError::FeatureNotEnabled)
+        ));
+    }
+
     #[cfg(feature = "encryption")]
     #[test]
     fn test_encryption_roundtrip() {
@@ -534,6 +1832,124 @@ mod tests {
         assert!(state.decrypt(&enc1, 2).is_err());
     }
 
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_packet_cipher_keys_per_direction_and_connection() {
+        let key = [0x42u8; 32];
+        let mut client = PacketCipher::new(&key, 1, 2, SessionRole::Client).unwrap();
+        let mut server = PacketCipher::new(&key, 1, 2, SessionRole::Server).unwrap();
+        let mut other = PacketCipher::new(&key, 1, 3, SessionRole::Server).unwrap();
+
+        let (counter, sealed) = client.seal(b"move north", b"header").unwrap();
+        assert_eq!(counter, 0);
+        assert!(other.open(counter, &sealed, b"header").is_err());
+        assert!(client.open(counter, &sealed, b"header").is_err());
+        assert!(server.open(counter, &sealed, b"tampered").is_err());
+        assert_eq!(
+            server.open(counter, &sealed, b"header").unwrap(),
+            b"move north"
+        );
+
+        let (counter, sealed) = server.seal(b"ok", &[]).unwrap();
+        assert_eq!(client.open(counter, &sealed, &[]).unwrap(), b"ok");
+        assert_eq!(client.packets_sealed(), 1);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_packet_cipher_rejects_replay() {
+        let key = [0x42u8; 32];
+        let mut client = PacketCipher::new(&key, 1, 2, SessionRole::Client).unwrap();
+        let mut server = PacketCipher::new(&key, 1, 2, SessionRole::Server).unwrap();
+
+        let first = client.seal(b"a", &[]).unwrap();
+        let second = client.seal(b"b", &[]).unwrap();
+        assert!(server.open(second.0, &second.1, &[]).is_ok());
+        assert!(server.open(first.0, &first.1, &[]).is_ok());
+        assert!(matches!(
+            server.open(second.0, &second.1, &[]),
+            Err(EncryptionError::Replayed)
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_key_share_agreement_derives_shared_secret() {
+        let psk = [0x42u8; 32];
+        let client_share = KeyShare::generate().unwrap();
+        let server_share = KeyShare::generate().unwrap();
+        let client_public = *client_share.public_key();
+        let server_public = *server_share.public_key();
+
+        let client = client_share.agree(&server_public, Some(&psk)).unwrap();
+        let server = server_share.agree(&client_public, Some(&psk)).unwrap();
+        assert_eq!(client, server);
+
+        // A peer without the pre-shared key derives a different secret.
+        let outsider = KeyShare::generate()
+            .unwrap()
+            .agree(&client_public, None)
+            .unwrap();
+        assert_ne!(outsider, client);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_authenticating_cipher_leaves_payload_readable() {
+        let key = [0x42u8; 32];
+        let mut client = PacketCipher::authenticating(&key, 1, 2, SessionRole::Client).unwrap();
+        let mut server = PacketCipher::authenticating(&key, 1, 2, SessionRole::Server).unwrap();
+
+        let (counter, sealed) = client.seal(b"move north", b"header").unwrap();
+        assert_eq!(sealed.len(), b"move north".len() + MAC_TAG_BYTES);
+        assert_eq!(&sealed[..10], b"move north");
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(server.open(counter, &tampered, b"header").is_err());
+        assert!(server.open(counter, &sealed, b"other").is_err());
+        assert!(server.open(counter, &sealed[..4], b"header").is_err());
+        assert_eq!(
+            server.open(counter, &sealed, b"header").unwrap(),
+            b"move north"
+        );
+        assert!(matches!(
+            server.open(counter, &sealed, b"header"),
+            Err(EncryptionError::Replayed)
+        ));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_server_identity_signs_key_share() {
+        let identity = ServerIdentity::from_seed(&[7u8; 32]).unwrap();
+        let pinned = identity.public_key();
+        let share = *KeyShare::generate().unwrap().public_key();
+        let signature = identity.sign_key_share(0x1234, 99, &share);
+
+        assert!(verify_key_share(&pinned, 0x1234, 99, &share, &signature));
+        assert!(!verify_key_share(&pinned, 0x1234, 100, &share, &signature));
+        let impostor = ServerIdentity::from_seed(&[8u8; 32]).unwrap();
+        let forged = impostor.sign_key_share(0x1234, 99, &share);
+        assert!(!verify_key_share(&pinned, 0x1234, 99, &share, &forged));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        for counter in [0, 5, 3] {
+            assert!(!window.is_replayed(counter));
+            window.mark(counter);
+            assert!(window.is_replayed(counter));
+        }
+        assert!(!window.is_replayed(4));
+
+        window.mark(5 + REPLAY_WINDOW_SIZE as u64);
+        assert!(window.is_replayed(4), "counter behind the window accepted");
+        assert!(!window.is_replayed(6 + REPLAY_WINDOW_SIZE as u64));
+        assert!(window.is_replayed(u64::MAX));
+    }
+
     #[test]
     fn test_rate_limiter() {
         let mut limiter = ConnectionRateLimiter::new(3);
@@ -545,20 +1961,175 @@ mod tests {
         assert!(!limiter.allow(addr)); // 4th request blocked
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
     fn test_token_table_bounded() {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
         let mut validator =
-            TokenValidator::new(Duration::from_secs(60)).with_max_tracked_tokens(10);
+            TokenValidator::new(TOKEN_KEY, TOKEN_PROTOCOL).with_max_tracked_tokens(10);
 
         // Insert more than max_tracked_tokens
         for i in 0..20u64 {
-            let token = ConnectToken::new(i, vec![addr], 60, vec![]);
-            let _ = validator.validate(&token);
+            let _ = validator.validate(&token(i), client_addr(i as u16), server_addr());
         }
 
         // Should be bounded
         assert!(validator.used_tokens.len() <= 10);
         assert!(validator.tokens_evicted() > 0);
     }
+
+    #[test]
+    fn test_ip_range_contains() {
+        let v4: IpRange = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(v4.to_string(), "10.1.0.0/16");
+        assert!(v4.contains("10.1.200.9".parse().unwrap()));
+        assert!(!v4.contains("10.2.0.1".parse().unwrap()));
+        assert!(v4.contains("::ffff:10.1.0.7".parse().unwrap()));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+
+        let any: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.168.1.1".parse().unwrap()));
+        let single: IpRange = "192.168.1.1".parse().unwrap();
+        assert_eq!(single.prefix_len(), 32);
+        assert!(!single.contains("192.168.1.2".parse().unwrap()));
+
+        assert_eq!(
+            "10.0.0.0/33".parse::<IpRange>(),
+            Err(IpRangeError::InvalidPrefix)
+        );
+        assert_eq!(
+            "10.0.0/8".parse::<IpRange>(),
+            Err(IpRangeError::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn test_address_filter_bans_expire_and_allow_list_blocks_others() {
+        let clock = crate::clock::ManualClock::new();
+        let mut filter = AddressFilter::new().with_clock(clock.shared());
+        let inside: IpAddr = "10.0.5.5".parse().unwrap();
+        let outside: IpAddr = "10.1.0.1".parse().unwrap();
+
+        filter.ban(
+            "10.0.0.0/16".parse().unwrap(),
+            Some(Duration::from_secs(60)),
+        );
+        assert!(filter.is_blocked(inside));
+        assert!(!filter.is_blocked(outside));
+        clock.advance(Duration::from_secs(60));
+        assert!(!filter.is_blocked(inside));
+        filter.cleanup();
+        assert!(!filter.unban("10.0.0.0/16".parse().unwrap()));
+
+        filter.ban(IpRange::from(inside), None);
+        assert!(filter.unban(IpRange::from(inside)));
+        assert!(!filter.is_blocked(inside));
+
+        filter.allow("10.0.0.0/16".parse().unwrap());
+        assert!(!filter.is_blocked(inside));
+        assert!(filter.is_blocked(outside));
+        assert!(filter.disallow("10.0.0.0/16".parse().unwrap()));
+        assert!(!filter.is_blocked(outside));
+    }
+
+    #[test]
+    fn test_cookie_secret_rotation_keeps_previous_for_one_window() {
+        let window = Duration::from_secs(5);
+        let rotation = Duration::from_secs(60);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1235);
+        let mut secrets = CookieSecrets::new(None, window, rotation, 170).unwrap();
+
+        let cookie = secrets.generate(&addr, 178);
+        assert!(secrets.validate(&cookie, &addr, 179));
+        assert!(!secrets.validate(&cookie, &other, 179));
+
+        let unrotated = secrets.generate(&addr, 181);
+        assert!(secrets.rotate(181));
+        assert_ne!(secrets.generate(&addr, 181), unrotated);
+        assert!(secrets.validate(&cookie, &addr, 181));
+        assert!(!secrets.validate(&cookie, &addr, 186));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_shared_cookie_secret_accepted_across_servers() {
+        let window = Duration::from_secs(5);
+        let rotation = Duration::from_secs(60);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+        let issuer = CookieSecrets::new(Some([3u8; 32]), window, rotation, 178).unwrap();
+        let cookie = issuer.generate(&addr, 178);
+
+        let peer = CookieSecrets::new(Some([3u8; 32]), window, rotation, 179).unwrap();
+        assert!(peer.validate(&cookie, &addr, 179));
+        let late_peer = CookieSecrets::new(Some([3u8; 32]), window, rotation, 181).unwrap();
+        assert!(late_peer.validate(&cookie, &addr, 181));
+        let stranger = CookieSecrets::new(Some([4u8; 32]), window, rotation, 179).unwrap();
+        assert!(!stranger.validate(&cookie, &addr, 179));
+    }
+
+    #[test]
+    fn test_rate_limiter_window_with_manual_clock() {
+        let clock = crate::clock::ManualClock::new();
+        let mut limiter = ConnectionRateLimiter::new(1).with_clock(clock.shared());
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+
+        assert!(limiter.allow(addr));
+        assert!(!limiter.allow(addr));
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow(addr));
+    }
+
+    #[test]
+    fn test_rate_limiter_subnet_budget() {
+        let clock = crate::clock::ManualClock::new();
+        let mut limiter = ConnectionRateLimiter::new(2)
+            .with_subnet_limit(3, 24, 64)
+            .with_clock(clock.shared());
+        let v4 = |host: u8| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, host)), 1234);
+        let v6 = |host: u16| {
+            SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, host)),
+                1234,
+            )
+        };
+
+        assert!(limiter.allow(v4(1)));
+        assert!(limiter.allow(v4(1)));
+        assert!(!limiter.allow(v4(1)), "per-IP budget still applies");
+        assert!(limiter.allow(v4(2)));
+        assert!(!limiter.allow(v4(3)), "rotating hosts within the /24");
+        assert!(limiter.allow(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)),
+            1234
+        )));
+
+        for host in 1..=3 {
+            assert!(limiter.allow(v6(host)));
+        }
+        assert!(!limiter.allow(v6(4)), "rotating addresses within the /64");
+
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow(v4(3)));
+        assert!(limiter.allow(v6(4)));
+    }
+
+    #[test]
+    fn test_rate_limiter_global_budget() {
+        let mut limiter = ConnectionRateLimiter::new(10)
+            .with_subnet_limit(10, 24, 64)
+            .with_global_limit(4);
+        let allowed = (0..8u8)
+            .filter(|&net| {
+                limiter.allow(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(10, net, 0, 1)),
+                    1234,
+                ))
+            })
+            .count();
+        assert_eq!(allowed, 4);
+    }
 }
@@ -8,18 +8,41 @@ use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
 use crate::{
-    congestion,
+    clock::{system_clock, SharedClock},
+    config::{FloodPolicy, MAX_THROTTLED_BACKLOG},
+    congestion::{self, BandwidthLimiter},
     connection::{Connection, ConnectionState, DisconnectReason},
-    packet::{deny_reason, disconnect_reason, Packet, PacketHeader, PacketType},
-    security::{self, ConnectionRateLimiter},
+    packet::{
+        deny_reason, disconnect_reason, Packet, PacketType, MAX_ACCEPT_PAYLOAD, MAX_CONNECT_DATA,
+        MAX_DENY_PAYLOAD, MIN_HANDSHAKE_REQUEST_SIZE, PROTOCOL_VERSION,
+    },
+    security::{
+        self, AddressFilter, ConnectToken, ConnectTokenData, ConnectionRateLimiter, CookieSecrets,
+        IpRange, KeyShare, SessionRole, TokenError, TokenValidator,
+    },
     socket::{SocketError, UdpSocket},
-    wire, NetworkConfig, NetworkStats,
+    stats::ServerStats,
+    transport::Transport,
+    wire::{self, DatagramLink},
+    NetworkConfig, NetworkStats,
 };
 
+#[cfg(feature = "encryption")]
+use crate::security::ServerIdentity;
+
 /// Events emitted by [`NetServer::update`].
 #[derive(Debug)]
 pub enum ServerEvent {
-    ClientConnected(SocketAddr),
+    /// A client completed the handshake. When the server requires connect
+    /// tokens, `client_id` and `user_data` come from the client's token.
+    /// `connect_data` is what the client passed to
+    /// [`crate::NetClient::connect_with_data`], empty if nothing.
+    ClientConnected {
+        addr: SocketAddr,
+        client_id: Option<u64>,
+        user_data: Vec<u8>,
+        connect_data: Vec<u8>,
+    },
     ClientDisconnected(SocketAddr, DisconnectReason),
     Message {
         addr: SocketAddr,
@@ -30,30 +53,79 @@ pub enum ServerEvent {
         old_addr: SocketAddr,
         new_addr: SocketAddr,
     },
+    /// A client completed the handshake and waits for
+    /// [`NetServer::accept_connection`] or [`NetServer::deny_connection`].
+    /// Only emitted with [`NetworkConfig::manual_admission`]; undecided
+    /// requests are dropped after [`NetworkConfig::connection_timeout`].
+    ConnectionRequest {
+        addr: SocketAddr,
+        client_id: Option<u64>,
+        user_data: Vec<u8>,
+        connect_data: Vec<u8>,
+    },
 }
 
 struct PendingConnection {
     server_salt: u64,
     created_at: Instant,
+    token: Option<ConnectTokenData>,
+    /// Server key share when sessions are encrypted.
+    key_share: Option<KeyShare>,
+    /// Challenge payload, kept so resent challenges carry the same share.
+    challenge_payload: Vec<u8>,
+}
+
+/// A client that completed the handshake, held until the application admits
+/// or denies it.
+struct AwaitingAdmission {
+    conn: Connection,
+    client_id: Option<u64>,
+    user_data: Vec<u8>,
+    connect_data: Vec<u8>,
+    created_at: Instant,
 }
 
-/// A game server that listens for client connections over UDP.
-///
-/// Call [`NetServer::update`] once per game tick to process packets,
-/// send keepalives, and collect events.
 /// Minimum interval between migrations for the same connection.
 const MIGRATION_COOLDOWN: Duration = Duration::from_secs(5);
 
-pub struct NetServer {
-    socket: UdpSocket,
+/// A game server that listens for client connections over UDP.
+///
+/// Call [`NetServer::update`] once per game tick to process packets,
+/// send keepalives, and collect events. The server runs over any
+/// [`Transport`]; [`NetServer::bind`] uses a [`UdpSocket`].
+pub struct NetServer<T: Transport = UdpSocket> {
+    transport: T,
+    link: DatagramLink,
     connections: HashMap<SocketAddr, Connection>,
     pending: HashMap<SocketAddr, PendingConnection>,
+    /// Handshakes awaiting the application's decision under
+    /// [`NetworkConfig::manual_admission`].
+    awaiting: HashMap<SocketAddr, AwaitingAdmission>,
+    /// Events raised between updates, returned by the next
+    /// [`NetServer::update`].
+    queued_events: Vec<ServerEvent>,
     disconnecting: HashMap<SocketAddr, Connection>,
     config: NetworkConfig,
     rate_limiter: ConnectionRateLimiter,
-    cookie_secret: [u8; 32],
+    /// Per-IP budget for [`deny_reason::BANNED`] replies, apart from
+    /// `rate_limiter` so banned ranges cannot spend the subnet and
+    /// server-wide handshake budgets.
+    banned_reply_limiter: ConnectionRateLimiter,
+    /// Banned and allowed source ranges, checked before deserialization.
+    address_filter: AddressFilter,
+    server_stats: ServerStats,
+    /// Present when [`NetworkConfig::connect_token_key`] is set.
+    token_validator: Option<TokenValidator>,
+    cookie_secrets: CookieSecrets,
+    /// Signs challenge key shares when [`NetworkConfig::server_identity_key`]
+    /// is set.
+    #[cfg(feature = "encryption")]
+    identity: Option<ServerIdentity>,
     /// Tracks last migration time per migration_token to rate-limit migrations.
     migration_cooldowns: HashMap<u64, Instant>,
+    /// Server-wide egress budget shared among connections each tick.
+    bandwidth_limiter: Option<BandwidthLimiter>,
+    clock: SharedClock,
 }
 
 impl NetServer {
@@ -63,56 +135,137 @@ impl NetServer {
             return Err(SocketError::Other(e.to_string()));
         }
         let socket = UdpSocket::bind(addr)?;
-        let rate_limit = config.rate_limit_per_second;
-        let mut cookie_secret = [0u8; 32];
-        for (i, byte) in cookie_secret.iter_mut().enumerate() {
-            *byte = random::<u8>().wrapping_add(i as u8);
+        Self::with_transport(socket, config)
+    }
+
+    /// Bind a server on `port` that accepts both IPv6 and IPv4 clients.
+    /// IPv4 clients are reported with plain IPv4 addresses.
+    pub fn bind_dual_stack(port: u16, config: NetworkConfig) -> Result<Self, SocketError> {
+        if let Err(e) = config.validate() {
+            return Err(SocketError::Other(e.to_string()));
         }
+        let socket = UdpSocket::bind_dual_stack(port)?;
+        Self::with_transport(socket, config)
+    }
+}
+
+impl<T: Transport> NetServer<T> {
+    /// Create a server that sends and receives through an existing transport.
+    pub fn with_transport(transport: T, config: NetworkConfig) -> Result<Self, SocketError> {
+        if let Err(e) = config.validate() {
+            return Err(SocketError::Other(e.to_string()));
+        }
+        let cookie_secrets = CookieSecrets::new(
+            config.cookie_secret,
+            config.cookie_window,
+            config.cookie_secret_rotation,
+            security::unix_time_secs(),
+        )
+        .ok_or_else(|| SocketError::Other("failed to draw cookie secret".to_string()))?;
         Ok(Self {
-            socket,
+            transport,
+            link: DatagramLink::from_config(&config),
             connections: HashMap::new(),
             pending: HashMap::new(),
+            awaiting: HashMap::new(),
+            queued_events: Vec::new(),
             disconnecting: HashMap::new(),
             config: config.clone(),
-            rate_limiter: ConnectionRateLimiter::new(rate_limit),
-            cookie_secret,
+            rate_limiter: ConnectionRateLimiter::from_config(&config),
+            banned_reply_limiter: ConnectionRateLimiter::new(config.rate_limit_per_second),
+            address_filter: AddressFilter::new(),
+            server_stats: ServerStats::default(),
+            token_validator: config.connect_token_key.map(|key| {
+                TokenValidator::new(key, config.protocol_id)
+                    .with_max_tracked_tokens(config.max_tracked_tokens)
+            }),
+            cookie_secrets,
+            #[cfg(feature = "encryption")]
+            identity: match config.server_identity_key {
+                Some(seed) => Some(
+                    ServerIdentity::from_seed(&seed)
+                        .map_err(|e| SocketError::Other(e.to_string()))?,
+                ),
+                None => None,
+            },
             migration_cooldowns: HashMap::new(),
+            bandwidth_limiter: congestion::bandwidth_limiter(
+                config.server_bandwidth_bytes_per_sec,
+                config.mtu,
+            ),
+            clock: system_clock(),
         })
     }
 
+    /// Drive all server and connection timers from `clock` instead of the
+    /// system clock, e.g. for fixed-timestep simulation or replay.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.link.set_clock(clock.clone());
+        self.rate_limiter =
+            ConnectionRateLimiter::from_config(&self.config).with_clock(clock.clone());
+        self.banned_reply_limiter =
+            ConnectionRateLimiter::new(self.config.rate_limit_per_second).with_clock(clock.clone());
+        self.address_filter = std::mem::take(&mut self.address_filter).with_clock(clock.clone());
+        self.bandwidth_limiter = self
+            .bandwidth_limiter
+            .take()
+            .map(|limiter| limiter.with_clock(clock.clone()));
+        self.clock = clock;
+        self
+    }
+
     /// Process incoming packets, send keepalives, and return events.
     /// Call this once per game tick.
     pub fn update(&mut self) -> Vec<ServerEvent> {
-        let mut events = Vec::new();
-
-        let mut incoming: Vec<(SocketAddr, Packet)> = Vec::new();
-        loop {
-            match self.socket.recv_from() {
-                Ok((data, addr)) => {
-                    let validated = match security::validate_and_strip_crc32(data) {
-                        Some(valid) => valid.to_vec(),
-                        None => continue,
-                    };
-                    let packet = match Packet::deserialize(&validated) {
-                        Ok(p) => p,
-                        Err(_) => continue,
-                    };
-                    if packet.header.protocol_id != self.config.protocol_id {
-                        continue;
-                    }
-                    if let Some(conn) = self.connections.get_mut(&addr) {
-                        conn.record_bytes_received(validated.len());
+        let mut events = std::mem::take(&mut self.queued_events);
+
+        for (addr, packet, datagram_len) in self.release_throttled() {
+            self.handle_server_packet(addr, packet, datagram_len, &mut events);
+        }
+
+        let mut flooded = Vec::new();
+        for (data, addr) in self.link.receive(&mut self.transport) {
+            if self.address_filter.is_blocked(addr.ip()) {
+                self.reject_blocked(addr, &data);
+                continue;
+            }
+            let Some(validated) = security::validate_and_strip_crc32(&data) else {
+                continue;
+            };
+            let packet = match Packet::deserialize(validated) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            if packet.header.protocol_id != self.config.protocol_id {
+                continue;
+            }
+            // Packets from a connected client are opened before they are
+            // charged, so spoofed datagrams cannot spend its inbound budget.
+            let packet = if let Some(conn) = self.connections.get_mut(&addr) {
+                let Some(packet) = conn.open_packet(packet) else {
+                    conn.stats.packets_rejected += 1;
+                    continue;
+                };
+                conn.record_bytes_received(validated.len());
+                if !conn.throttled.is_empty() || !conn.admit_inbound(data.len()) {
+                    match self.config.flood_policy {
+                        FloodPolicy::Throttle if conn.throttled.len() < MAX_THROTTLED_BACKLOG => {
+                            conn.stats.flood_packets_deferred += 1;
+                            conn.throttled.push_back((packet, data.len()));
+                        }
+                        FloodPolicy::Kick => flooded.push(addr),
+                        _ => conn.stats.flood_packets_dropped += 1,
                     }
-                    incoming.push((addr, packet));
+                    continue;
                 }
-                Err(SocketError::WouldBlock) => break,
-                Err(_) => break,
-            }
+                packet
+            } else {
+                packet
+            };
+            self.handle_server_packet(addr, packet, data.len(), &mut events);
         }
 
-        for (addr, packet) in incoming {
-            self.handle_server_packet(addr, packet, &mut events);
-        }
+        self.allocate_bandwidth();
 
         let mut disconnected = Vec::new();
         let addrs: Vec<SocketAddr> = self.connections.keys().copied().collect();
@@ -126,29 +279,31 @@ impl NetServer {
                 continue;
             }
 
-            let packets = conn.drain_send_queue();
-            for packet in packets {
-                if let Ok(data) = packet.serialize() {
-                    let mut data_with_crc = data;
-                    security::append_crc32(&mut data_with_crc);
-                    let byte_len = data_with_crc.len();
-                    if let Err(e) = self.socket.send_to(&data_with_crc, addr) {
-                        log::warn!("Failed to send to {}: {:?}", addr, e);
-                        if let Some(conn) = self.connections.get_mut(&addr) {
-                            conn.stats.send_errors += 1;
-                        }
-                    } else if let Some(conn) = self.connections.get_mut(&addr) {
-                        conn.record_bytes_sent(byte_len);
-                    }
-                }
-            }
+            let datagrams = conn.drain_datagrams();
+            self.queue_datagrams(addr, datagrams);
 
             let Some(conn) = self.connections.get_mut(&addr) else {
                 continue;
             };
             let max_channels = conn.channel_count();
-            for ch in 0..max_channels as u8 {
-                while let Some(data) = conn.receive(ch) {
+            let per_tick = self.config.max_messages_per_channel_per_tick;
+            for ch in (0..max_channels).map(|ch| ch as u8) {
+                if per_tick > 0 && conn.pending_receive(ch) > per_tick {
+                    let keep = match self.config.flood_policy {
+                        FloodPolicy::Drop => per_tick,
+                        FloodPolicy::Throttle => per_tick + MAX_THROTTLED_BACKLOG,
+                        FloodPolicy::Kick => {
+                            flooded.push(addr);
+                            break;
+                        }
+                    };
+                    conn.stats.flood_messages_dropped += conn.truncate_received(ch, keep) as u64;
+                }
+                let limit = if per_tick == 0 { usize::MAX } else { per_tick };
+                for _ in 0..limit {
+                    let Some(data) = conn.receive(ch) else {
+                        break;
+                    };
                     events.push(ServerEvent::Message {
                         addr,
                         channel: ch,
@@ -163,23 +318,44 @@ impl NetServer {
             events.push(ServerEvent::ClientDisconnected(addr, reason));
         }
 
-        let mut finished_disconnecting = Vec::new();
-        for (addr, conn) in &mut self.disconnecting {
-            let _ = conn.update(&mut self.socket);
-            if conn.state() == ConnectionState::Disconnected {
-                finished_disconnecting.push(*addr);
-            }
+        for addr in flooded {
+            self.kick_flooding(addr, &mut events);
         }
-        for addr in finished_disconnecting {
-            self.disconnecting.remove(&addr);
+
+        let disconnecting: Vec<SocketAddr> = self.disconnecting.keys().copied().collect();
+        for addr in disconnecting {
+            let Some(conn) = self.disconnecting.get_mut(&addr) else {
+                continue;
+            };
+            let _ = conn.update_tick();
+            let datagrams = conn.drain_datagrams();
+            let finished = conn.state() == ConnectionState::Disconnected;
+            self.queue_datagrams(addr, datagrams);
+            if finished {
+                self.disconnecting.remove(&addr);
+            }
         }
 
         let timeout = self.config.connection_request_timeout;
-        self.pending.retain(|_, p| p.created_at.elapsed() < timeout);
+        let now = self.clock.now();
+        self.pending
+            .retain(|_, p| now.duration_since(p.created_at) < timeout);
+        let admission_timeout = self.config.connection_timeout;
+        self.awaiting
+            .retain(|_, a| now.duration_since(a.created_at) < admission_timeout);
         self.rate_limiter.cleanup();
+        self.banned_reply_limiter.cleanup();
+        self.address_filter.cleanup();
+        if !self.cookie_secrets.rotate(security::unix_time_secs()) {
+            log::warn!("Failed to rotate cookie secret");
+        }
+        if let Some(validator) = self.token_validator.as_mut() {
+            validator.cleanup();
+        }
         self.migration_cooldowns
-            .retain(|_, last| last.elapsed() < MIGRATION_COOLDOWN);
+            .retain(|_, last| now.duration_since(*last) < MIGRATION_COOLDOWN);
 
+        self.flush();
         events
     }
 
@@ -220,21 +396,126 @@ impl NetServer {
 
     /// Disconnect a client with the given reason code.
     pub fn disconnect(&mut self, addr: SocketAddr, reason: u8) {
-        if let Some(mut conn) = self.connections.remove(&addr) {
-            let _ = conn.disconnect(reason);
-            let _ = conn.update(&mut self.socket);
-            self.disconnecting.insert(addr, conn);
-        }
+        self.begin_disconnect(addr, reason);
+        self.flush();
     }
 
     /// Shut down the server, disconnecting all clients gracefully.
     pub fn shutdown(&mut self) {
         let addrs: Vec<SocketAddr> = self.connections.keys().copied().collect();
         for addr in addrs {
-            self.disconnect(addr, disconnect_reason::REQUESTED);
+            self.begin_disconnect(addr, disconnect_reason::REQUESTED);
+        }
+        self.flush();
+    }
+
+    fn begin_disconnect(&mut self, addr: SocketAddr, reason: u8) {
+        if let Some(mut conn) = self.connections.remove(&addr) {
+            let _ = conn.disconnect(reason);
+            let datagrams = conn.drain_datagrams();
+            self.disconnecting.insert(addr, conn);
+            self.queue_datagrams(addr, datagrams);
         }
     }
 
+    /// Admit a client held by [`NetworkConfig::manual_admission`]. Its
+    /// [`ServerEvent::ClientConnected`] is returned by the next
+    /// [`NetServer::update`]. Returns false if `addr` has no request awaiting
+    /// a decision, or if the server has filled up since, in which case the
+    /// client is denied with [`deny_reason::SERVER_FULL`].
+    pub fn accept_connection(&mut self, addr: SocketAddr) -> bool {
+        self.accept_connection_with_data(addr, &[])
+    }
+
+    /// Like [`NetServer::accept_connection`], sending the client up to
+    /// [`MAX_ACCEPT_PAYLOAD`] bytes of `payload` (longer payloads are
+    /// truncated), e.g. its assigned player id. The payload is protected
+    /// like the session, and read with [`crate::NetClient::accept_payload`].
+    pub fn accept_connection_with_data(&mut self, addr: SocketAddr, payload: &[u8]) -> bool {
+        let Some(awaiting) = self.awaiting.remove(&addr) else {
+            return false;
+        };
+        if self.connections.len() >= self.config.max_clients {
+            self.send_raw(
+                addr,
+                PacketType::ConnectionDeny {
+                    reason: deny_reason::SERVER_FULL,
+                },
+            );
+            self.flush();
+            return false;
+        }
+        let event = self.admit(addr, awaiting, payload);
+        self.queued_events.push(event);
+        self.flush();
+        true
+    }
+
+    /// Refuse a client held by [`NetworkConfig::manual_admission`], sending
+    /// it `reason` and up to [`MAX_DENY_PAYLOAD`] bytes of `payload` (longer
+    /// payloads are truncated). The deny is not encrypted. Codes from
+    /// [`deny_reason::FIRST_APPLICATION`] up are reserved for applications.
+    /// Returns false if `addr` has no request awaiting a decision.
+    pub fn deny_connection(&mut self, addr: SocketAddr, reason: u8, payload: &[u8]) -> bool {
+        if self.awaiting.remove(&addr).is_none() {
+            return false;
+        }
+        let payload = payload[..payload.len().min(MAX_DENY_PAYLOAD)].to_vec();
+        self.send_raw_with_payload(addr, PacketType::ConnectionDeny { reason }, payload);
+        self.flush();
+        true
+    }
+
+    /// Ban `range`, an address or CIDR block, for `duration` or until
+    /// [`NetServer::unban`] if `None`. Its datagrams are dropped before
+    /// deserialization, connection requests are denied with
+    /// [`deny_reason::BANNED`], and connected clients inside it are
+    /// disconnected with [`disconnect_reason::BANNED`].
+    pub fn ban(&mut self, range: impl Into<IpRange>, duration: Option<Duration>) {
+        self.address_filter.ban(range.into(), duration);
+        self.drop_blocked();
+    }
+
+    /// Lift the ban on exactly `range`. Returns false if it was not banned.
+    pub fn unban(&mut self, range: impl Into<IpRange>) -> bool {
+        self.address_filter.unban(range.into())
+    }
+
+    /// Add `range` to the allow list. Once the list is non-empty, sources
+    /// outside every allowed range are treated as banned.
+    pub fn allow_range(&mut self, range: impl Into<IpRange>) {
+        self.address_filter.allow(range.into());
+        self.drop_blocked();
+    }
+
+    /// Remove exactly `range` from the allow list. Returns false if it was
+    /// not listed.
+    pub fn disallow_range(&mut self, range: impl Into<IpRange>) -> bool {
+        let removed = self.address_filter.disallow(range.into());
+        self.drop_blocked();
+        removed
+    }
+
+    /// Whether traffic from `ip` is currently dropped by the ban or allow
+    /// list.
+    pub fn is_blocked(&self, ip: std::net::IpAddr) -> bool {
+        self.address_filter.is_blocked(ip)
+    }
+
+    /// Set a client's weight in the server-wide bandwidth budget
+    /// ([`NetworkConfig::server_bandwidth_bytes_per_sec`]). A client with
+    /// weight 2 gets twice the share of a client with weight 1 when both have
+    /// traffic waiting; weight 0 sends nothing while the budget applies.
+    pub fn set_client_weight(&mut self, addr: SocketAddr, weight: u32) {
+        if let Some(conn) = self.connections.get_mut(&addr) {
+            conn.bandwidth_weight = weight;
+        }
+    }
+
+    pub fn client_weight(&self, addr: SocketAddr) -> Option<u32> {
+        self.connections.get(&addr).map(|c| c.bandwidth_weight)
+    }
+
     pub fn connections(&self) -> impl Iterator<Item = (&SocketAddr, &Connection)> {
         self.connections.iter()
     }
@@ -243,41 +524,67 @@ impl NetServer {
         self.connections.get(&addr).map(|c| c.stats())
     }
 
+    /// Counters for traffic the server dropped before it reached a
+    /// connection.
+    pub fn server_stats(&self) -> &ServerStats {
+        &self.server_stats
+    }
+
     pub fn client_count(&self) -> usize {
         self.connections.len()
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr, SocketError> {
-        self.socket.local_addr()
+        self.transport.local_addr()
+    }
+
+    /// Returns the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the underlying transport mutably.
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
     }
 
-    /// Attempt to migrate an existing connection to a new address.
-    /// Returns the old address if migration succeeded.
-    fn try_migrate(&mut self, new_addr: SocketAddr, header: &PacketHeader) -> Option<SocketAddr> {
+    /// Attempt to migrate an existing connection to `new_addr`, the source of
+    /// the data packet `packet`. Returns the old address and the packet as
+    /// opened by the migrated connection if migration succeeded.
+    fn try_migrate(
+        &mut self,
+        new_addr: SocketAddr,
+        packet: &Packet,
+    ) -> Option<(SocketAddr, Packet)> {
         if !self.config.enable_connection_migration {
             return None;
         }
 
-        let now = Instant::now();
+        let now = self.clock.now();
+        let cooldowns = &self.migration_cooldowns;
 
         // Find a connection whose sequence range matches the incoming packet
-        let old_addr = self.connections.iter().find_map(|(addr, conn)| {
+        let (old_addr, opened) = self.connections.iter_mut().find_map(|(addr, conn)| {
             if conn.state() != ConnectionState::Connected {
                 return None;
             }
             // Check sequence is plausible (within reasonable window)
-            let seq_diff = crate::util::sequence_diff(header.sequence, conn.remote_sequence).abs();
+            let seq_diff =
+                crate::util::sequence_diff(packet.header.sequence, conn.remote_sequence).abs();
             if seq_diff > conn.config().max_sequence_distance as i32 {
                 return None;
             }
             // Check migration cooldown
             let token = conn.migration_token();
-            if let Some(last) = self.migration_cooldowns.get(&token) {
+            if let Some(last) = cooldowns.get(&token) {
                 if now.duration_since(*last) < MIGRATION_COOLDOWN {
                     return None;
                 }
             }
-            Some(*addr)
+            // On an encrypted session only the holder of the session keys
+            // can produce a packet that opens.
+            let opened = conn.open_packet(packet.clone())?;
+            is_data_packet(&opened.packet_type).then_some((*addr, opened))
         })?;
 
         // Perform migration
@@ -287,144 +594,152 @@ impl NetServer {
         self.migration_cooldowns.insert(token, now);
         self.connections.insert(new_addr, conn);
 
-        Some(old_addr)
+        Some((old_addr, opened))
     }
 
+    /// Check the connect token carried in a connection request payload,
+    /// recording it as used by `addr` when `record` is set. Returns
+    /// `Ok(None)` when the server does not require tokens.
+    fn validate_connect_token(
+        &mut self,
+        addr: SocketAddr,
+        payload: &[u8],
+        record: bool,
+    ) -> Result<Option<ConnectTokenData>, TokenError> {
+        let Some(validator) = self.token_validator.as_mut() else {
+            return Ok(None);
+        };
+        let result = ConnectToken::from_bytes(payload).and_then(|token| {
+            let server_addr = self
+                .transport
+                .local_addr()
+                .map_err(|_| TokenError::Invalid)?;
+            if record {
+                validator.validate(&token, addr, server_addr)
+            } else {
+                validator.check(&token, addr, server_addr)
+            }
+        });
+        match result {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                log::debug!("Rejecting connection request from {}: {}", addr, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Handle a packet that arrived in a datagram of `datagram_len` bytes.
     fn handle_server_packet(
         &mut self,
         addr: SocketAddr,
         packet: Packet,
+        datagram_len: usize,
         events: &mut Vec<ServerEvent>,
     ) {
         match packet.packet_type {
-            PacketType::ConnectionRequest => {
-                if !self.rate_limiter.allow(addr) {
+            PacketType::ConnectionRequest {
+                protocol_version,
+                app_version,
+            } => {
+                let Some(payload) = handshake_payload(datagram_len, &packet.payload) else {
                     return;
-                }
-
-                if self.connections.contains_key(&addr) {
-                    self.send_raw(addr, PacketType::ConnectionAccept);
+                };
+                // Charged only once the request is well formed, so undersized
+                // spoofs cannot spend the handshake budgets.
+                if !self.rate_limiter.allow(addr) {
                     return;
                 }
 
-                if self.config.enable_stateless_cookie {
-                    // Respond with a cookie instead of allocating state immediately
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs()
-                        / crate::config::DEFAULT_COOKIE_WINDOW_SECS;
-                    let cookie = security::generate_cookie(&addr, timestamp, &self.cookie_secret);
-                    let (high, low) = security::cookie_to_u64_pair(&cookie);
-                    self.send_raw(
+                if protocol_version != PROTOCOL_VERSION
+                    || !self
+                        .config
+                        .version_policy
+                        .accepts(self.config.app_version, app_version)
+                {
+                    log::debug!(
+                        "Denying {}: protocol version {}, app version {}",
                         addr,
-                        PacketType::ConnectionCookie {
-                            cookie_high: high,
-                            cookie_low: low,
-                        },
+                        protocol_version,
+                        app_version
                     );
-                    return;
-                }
-
-                if let Some(pending) = self.pending.get(&addr) {
                     self.send_raw(
                         addr,
-                        PacketType::ConnectionChallenge {
-                            server_salt: pending.server_salt,
+                        PacketType::ConnectionDeny {
+                            reason: deny_reason::VERSION_MISMATCH,
                         },
                     );
                     return;
                 }
 
-                if self.pending.len() >= self.config.max_pending {
+                if self.connections.contains_key(&addr) {
+                    self.send_accept(addr);
                     return;
                 }
-                if self.connections.len() >= self.config.max_clients {
+
+                // With cookies on, the address is unproven until the cookie
+                // comes back, so the token is only recorded then.
+                let record = !self.config.enable_stateless_cookie;
+                let Ok(token) = self.validate_connect_token(addr, payload, record) else {
+                    return;
+                };
+
+                if self.config.enable_stateless_cookie {
+                    // Respond with a cookie instead of allocating state immediately
+                    let cookie = self
+                        .cookie_secrets
+                        .generate(&addr, security::unix_time_secs());
+                    let (high, low) = security::cookie_to_u64_pair(&cookie);
                     self.send_raw(
                         addr,
-                        PacketType::ConnectionDeny {
-                            reason: deny_reason::SERVER_FULL,
+                        PacketType::ConnectionCookie {
+                            cookie_high: high,
+                            cookie_low: low,
                         },
                     );
                     return;
                 }
 
-                let server_salt: u64 = random();
-                self.send_raw(addr, PacketType::ConnectionChallenge { server_salt });
-                self.pending.insert(
-                    addr,
-                    PendingConnection {
-                        server_salt,
-                        created_at: Instant::now(),
-                    },
-                );
+                self.send_challenge(addr, token);
             }
             PacketType::ConnectionRequestWithCookie {
                 cookie_high,
                 cookie_low,
             } => {
+                let Some(payload) = handshake_payload(datagram_len, &packet.payload) else {
+                    return;
+                };
                 if !self.rate_limiter.allow(addr) {
                     return;
                 }
 
                 if self.connections.contains_key(&addr) {
-                    self.send_raw(addr, PacketType::ConnectionAccept);
+                    self.send_accept(addr);
                     return;
                 }
 
                 // Validate the cookie
                 let cookie = security::cookie_from_u64_pair(cookie_high, cookie_low);
-                let current_timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs();
-                if !security::validate_cookie(
-                    &cookie,
-                    &addr,
-                    current_timestamp,
-                    &self.cookie_secret,
-                    crate::config::DEFAULT_COOKIE_WINDOW_SECS,
-                ) {
-                    return;
-                }
-
-                // Cookie valid — proceed to salt challenge
-                if let Some(pending) = self.pending.get(&addr) {
-                    self.send_raw(
-                        addr,
-                        PacketType::ConnectionChallenge {
-                            server_salt: pending.server_salt,
-                        },
-                    );
+                if !self
+                    .cookie_secrets
+                    .validate(&cookie, &addr, security::unix_time_secs())
+                {
                     return;
                 }
 
-                if self.pending.len() >= self.config.max_pending {
+                let Ok(token) = self.validate_connect_token(addr, payload, true) else {
                     return;
-                }
-                if self.connections.len() >= self.config.max_clients {
-                    self.send_raw(
-                        addr,
-                        PacketType::ConnectionDeny {
-                            reason: deny_reason::SERVER_FULL,
-                        },
-                    );
-                    return;
-                }
+                };
 
-                let server_salt: u64 = random();
-                self.send_raw(addr, PacketType::ConnectionChallenge { server_salt });
-                self.pending.insert(
-                    addr,
-                    PendingConnection {
-                        server_salt,
-                        created_at: Instant::now(),
-                    },
-                );
+                // Cookie valid — proceed to salt challenge
+                self.send_challenge(addr, token);
             }
             PacketType::ConnectionResponse { client_salt } => {
+                let Some(payload) = handshake_payload(datagram_len, &packet.payload) else {
+                    return;
+                };
                 if self.connections.contains_key(&addr) {
-                    self.send_raw(addr, PacketType::ConnectionAccept);
+                    self.send_accept(addr);
                     return;
                 }
 
@@ -433,51 +748,110 @@ impl NetServer {
                         self.send_raw(
                             addr,
                             PacketType::ConnectionDeny {
-                                reason: crate::packet::deny_reason::INVALID_CHALLENGE,
+                                reason: deny_reason::INVALID_CHALLENGE,
                             },
                         );
                         return;
                     }
-                    self.send_raw(addr, PacketType::ConnectionAccept);
-
-                    let local_addr = self.socket.local_addr().unwrap_or(addr);
-                    let mut conn = Connection::new(self.config.clone(), local_addr, addr);
-                    conn.set_state(ConnectionState::Connected);
-                    conn.touch_recv_time();
-                    self.connections.insert(addr, conn);
-                    events.push(ServerEvent::ClientConnected(addr));
+                    let local_addr = self.transport.local_addr().unwrap_or(addr);
+                    let mut conn = Connection::new(self.config.clone(), local_addr, addr)
+                        .with_clock(self.clock.clone());
+                    conn.client_salt = client_salt;
+                    conn.set_server_salt(pending.server_salt);
+                    conn.key_share = pending.key_share;
+                    let sealed_data = conn.split_key_share(payload);
+                    let connect_data = if conn.establish_session(SessionRole::Server) {
+                        conn.open_handshake_data(sealed_data)
+                            .filter(|data| data.len() <= MAX_CONNECT_DATA)
+                    } else {
+                        None
+                    };
+                    let Some(connect_data) = connect_data else {
+                        log::debug!("Denying {}: session key exchange failed", addr);
+                        self.send_raw(
+                            addr,
+                            PacketType::ConnectionDeny {
+                                reason: deny_reason::INVALID_CHALLENGE,
+                            },
+                        );
+                        return;
+                    };
+                    let (client_id, user_data) = match pending.token {
+                        Some(token) => (Some(token.client_id), token.user_data),
+                        None => (None, Vec::new()),
+                    };
+                    let admission = AwaitingAdmission {
+                        conn,
+                        client_id,
+                        user_data,
+                        connect_data,
+                        created_at: self.clock.now(),
+                    };
+                    if self.config.manual_admission {
+                        events.push(ServerEvent::ConnectionRequest {
+                            addr,
+                            client_id,
+                            user_data: admission.user_data.clone(),
+                            connect_data: admission.connect_data.clone(),
+                        });
+                        self.awaiting.insert(addr, admission);
+                        return;
+                    }
+                    events.push(self.admit(addr, admission, &[]));
                 }
             }
+            _ => self.handle_session_packet(addr, packet, events),
+        }
+    }
+
+    /// Handle a session packet. Packets from a connected client arrive
+    /// already opened by [`NetServer::update`]; any other address may be a
+    /// client that migrated.
+    fn handle_session_packet(
+        &mut self,
+        addr: SocketAddr,
+        packet: Packet,
+        events: &mut Vec<ServerEvent>,
+    ) {
+        let packet = if self.connections.contains_key(&addr) {
+            packet
+        } else if is_data_packet(&packet.packet_type)
+            || matches!(
+                packet.packet_type,
+                PacketType::Encrypted { .. } | PacketType::Authenticated { .. }
+            )
+        {
+            let Some((old_addr, packet)) = self.try_migrate(addr, &packet) else {
+                return;
+            };
+            events.push(ServerEvent::ClientMigrated {
+                old_addr,
+                new_addr: addr,
+            });
+            packet
+        } else {
+            return;
+        };
+
+        match packet.packet_type {
             PacketType::Disconnect { reason } => {
-                if self.connections.remove(&addr).is_some() {
-                    self.send_raw(
-                        addr,
-                        PacketType::Disconnect {
-                            reason: disconnect_reason::REQUESTED,
-                        },
-                    );
-                    events.push(ServerEvent::ClientDisconnected(
-                        addr,
-                        DisconnectReason::from(reason),
-                    ));
-                }
-            }
-            PacketType::Payload {
-                channel,
-                is_fragment,
-            } => {
-                let effective_addr = if self.connections.contains_key(&addr) {
-                    addr
-                } else if let Some(old_addr) = self.try_migrate(addr, &packet.header) {
-                    events.push(ServerEvent::ClientMigrated {
-                        old_addr,
-                        new_addr: addr,
-                    });
-                    addr
-                } else {
+                let Some(mut conn) = self.connections.remove(&addr) else {
                     return;
                 };
-                let Some(conn) = self.connections.get_mut(&effective_addr) else {
+                conn.queue_control(PacketType::Disconnect {
+                    reason: disconnect_reason::REQUESTED,
+                });
+                let datagrams = conn.drain_datagrams();
+                self.queue_datagrams(addr, datagrams);
+                events.push(ServerEvent::ClientDisconnected(
+                    addr,
+                    DisconnectReason::from(reason),
+                ));
+            }
+            PacketType::Payload { .. }
+            | PacketType::BatchedPayload { .. }
+            | PacketType::MultiPayload => {
+                let Some(conn) = self.connections.get_mut(&addr) else {
                     return;
                 };
                 if packet.payload.len() > conn.config().default_channel_config.max_message_size {
@@ -485,32 +859,36 @@ impl NetServer {
                 }
                 conn.touch_recv_time();
                 conn.process_incoming_header(&packet.header);
-                if is_fragment {
-                    if let Some(assembled) =
-                        conn.fragment_assembler.process_fragment(&packet.payload)
-                    {
-                        conn.receive_payload_direct(channel, assembled);
-                    }
-                } else {
-                    conn.receive_payload_direct(channel, packet.payload);
-                }
-            }
-            PacketType::BatchedPayload { channel } => {
-                if let Some(conn) = self.connections.get_mut(&addr) {
-                    conn.touch_recv_time();
-                    conn.process_incoming_header(&packet.header);
-                    if let Some(messages) = congestion::unbatch_messages(&packet.payload) {
-                        for msg in messages {
-                            conn.receive_payload_direct(channel, msg);
+                match packet.packet_type {
+                    PacketType::Payload {
+                        channel,
+                        is_fragment: true,
+                    } => {
+                        if !conn.fragment_assembler.has_room_for(&packet.payload) {
+                            conn.stats.flood_fragments_dropped += 1;
+                            if self.config.flood_policy == FloodPolicy::Kick {
+                                self.kick_flooding(addr, events);
+                            }
+                        } else if let Some(assembled) =
+                            conn.fragment_assembler.process_fragment(&packet.payload)
+                        {
+                            conn.receive_payload_direct(channel, assembled);
                         }
                     }
+                    PacketType::Payload { channel, .. } => {
+                        conn.receive_payload_direct(channel, packet.payload);
+                    }
+                    PacketType::BatchedPayload { channel } => {
+                        conn.receive_batched_payload(channel, &packet.payload);
+                    }
+                    _ => conn.receive_multi_payload(&packet.payload),
                 }
             }
             PacketType::MtuProbe { probe_size } => {
                 if let Some(conn) = self.connections.get_mut(&addr) {
                     conn.touch_recv_time();
                     conn.process_incoming_header(&packet.header);
-                    self.send_raw(addr, PacketType::MtuProbeAck { probe_size });
+                    conn.queue_control(PacketType::MtuProbeAck { probe_size });
                 }
             }
             PacketType::MtuProbeAck { probe_size } => {
@@ -520,7 +898,10 @@ impl NetServer {
                     conn.mtu_discovery.on_probe_success(probe_size as usize);
                 }
             }
-            PacketType::KeepAlive | PacketType::AckOnly => {
+            PacketType::KeepAlive
+            | PacketType::AckOnly
+            | PacketType::RekeyRequest { .. }
+            | PacketType::RekeyResponse { .. } => {
                 if let Some(conn) = self.connections.get_mut(&addr) {
                     conn.touch_recv_time();
                     conn.process_incoming_header(&packet.header);
@@ -530,18 +911,296 @@ impl NetServer {
         }
     }
 
-    fn send_raw(&mut self, addr: SocketAddr, packet_type: PacketType) {
-        wire::send_raw_packet(
-            &mut self.socket,
+    /// Divide the server egress budget available this tick among connections
+    /// by weighted max-min fairness over what each one has waiting to send.
+    fn allocate_bandwidth(&mut self) {
+        let Some(ref mut limiter) = self.bandwidth_limiter else {
+            return;
+        };
+        limiter.refill();
+        let available = limiter.available();
+
+        let senders: Vec<(SocketAddr, u32, usize)> = self
+            .connections
+            .iter()
+            .map(|(addr, conn)| (*addr, conn.bandwidth_weight, conn.send_demand()))
+            .collect();
+        let demands: Vec<(u32, usize)> = senders.iter().map(|&(_, w, d)| (w, d)).collect();
+        let shares = congestion::weighted_fair_shares(available, &demands);
+        for ((addr, _, demand), share) in senders.into_iter().zip(shares) {
+            if let Some(conn) = self.connections.get_mut(&addr) {
+                conn.grant_bandwidth(share, demand);
+            }
+        }
+    }
+
+    /// Queue datagrams encoded by a connection for `addr`.
+    fn queue_datagrams(&mut self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        for data in datagrams {
+            self.queue_datagram(data, addr);
+        }
+    }
+
+    /// Take the throttled packets the inbound budgets now admit, oldest
+    /// first, so they are handled ahead of this tick's new arrivals.
+    fn release_throttled(&mut self) -> Vec<(SocketAddr, Packet, usize)> {
+        let mut released = Vec::new();
+        for (addr, conn) in self.connections.iter_mut() {
+            while let Some(len) = conn.throttled.front().map(|(_, len)| *len) {
+                if !conn.admit_inbound(len) {
+                    break;
+                }
+                if let Some((packet, len)) = conn.throttled.pop_front() {
+                    released.push((*addr, packet, len));
+                }
+            }
+        }
+        released
+    }
+
+    /// Disconnect a client that exceeded its inbound limits under
+    /// [`FloodPolicy::Kick`].
+    fn kick_flooding(&mut self, addr: SocketAddr, events: &mut Vec<ServerEvent>) {
+        if !self.connections.contains_key(&addr) {
+            return;
+        }
+        self.begin_disconnect(addr, disconnect_reason::FLOODING);
+        events.push(ServerEvent::ClientDisconnected(
+            addr,
+            DisconnectReason::Flooding,
+        ));
+    }
+
+    /// Count a datagram from a blocked source, answering connection requests
+    /// with [`deny_reason::BANNED`] as far as the per-IP reply budget allows.
+    fn reject_blocked(&mut self, addr: SocketAddr, data: &[u8]) {
+        self.server_stats.packets_blocked += 1;
+        if data.len() < MIN_HANDSHAKE_REQUEST_SIZE {
+            return;
+        }
+        let Some((header, packet_type)) = security::validate_and_strip_crc32(data)
+            .and_then(|validated| Packet::deserialize_type(validated).ok())
+        else {
+            return;
+        };
+        if header.protocol_id != self.config.protocol_id
+            || !matches!(
+                packet_type,
+                PacketType::ConnectionRequest { .. }
+                    | PacketType::ConnectionRequestWithCookie { .. }
+            )
+        {
+            return;
+        }
+        self.server_stats.connection_requests_blocked += 1;
+        if self.banned_reply_limiter.allow(addr) {
+            self.send_raw(
+                addr,
+                PacketType::ConnectionDeny {
+                    reason: deny_reason::BANNED,
+                },
+            );
+        }
+    }
+
+    /// Disconnect clients and drop handshakes whose address the filter now
+    /// blocks.
+    fn drop_blocked(&mut self) {
+        let filter = &self.address_filter;
+        self.pending.retain(|addr, _| !filter.is_blocked(addr.ip()));
+        self.awaiting
+            .retain(|addr, _| !filter.is_blocked(addr.ip()));
+        let blocked: Vec<SocketAddr> = self
+            .connections
+            .keys()
+            .filter(|addr| filter.is_blocked(addr.ip()))
+            .copied()
+            .collect();
+        for addr in blocked {
+            self.begin_disconnect(addr, disconnect_reason::BANNED);
+        }
+        self.flush();
+    }
+
+    /// Accept a client that completed the handshake.
+    fn admit(
+        &mut self,
+        addr: SocketAddr,
+        admission: AwaitingAdmission,
+        accept_payload: &[u8],
+    ) -> ServerEvent {
+        let AwaitingAdmission {
+            mut conn,
+            client_id,
+            user_data,
+            connect_data,
+            ..
+        } = admission;
+        let accept_payload = &accept_payload[..accept_payload.len().min(MAX_ACCEPT_PAYLOAD)];
+        conn.accept_payload = conn.seal_handshake_data(accept_payload).unwrap_or_default();
+        conn.set_state(ConnectionState::Connected);
+        conn.touch_recv_time();
+        self.connections.insert(addr, conn);
+        self.send_accept(addr);
+        ServerEvent::ClientConnected {
+            addr,
+            client_id,
+            user_data,
+            connect_data,
+        }
+    }
+
+    /// Send `addr` the accept of its connection, with the payload it was
+    /// admitted with.
+    fn send_accept(&mut self, addr: SocketAddr) {
+        let payload = self
+            .connections
+            .get(&addr)
+            .map(|conn| conn.accept_payload.clone())
+            .unwrap_or_default();
+        self.send_raw_with_payload(addr, PacketType::ConnectionAccept, payload);
+    }
+
+    /// Send `addr` its salt challenge, creating the pending connection on the
+    /// first request. With session keys the challenge carries the server's
+    /// key share, signed by the server identity if one is configured.
+    fn send_challenge(&mut self, addr: SocketAddr, token: Option<ConnectTokenData>) {
+        if let Some(pending) = self.pending.get(&addr) {
+            let server_salt = pending.server_salt;
+            let payload = pending.challenge_payload.clone();
+            self.send_raw_with_payload(
+                addr,
+                PacketType::ConnectionChallenge { server_salt },
+                payload,
+            );
+            return;
+        }
+        if self.awaiting.contains_key(&addr) {
+            return;
+        }
+
+        if self.pending.len() + self.awaiting.len() >= self.config.max_pending {
+            return;
+        }
+        if self.connections.len() >= self.config.max_clients {
+            self.send_raw(
+                addr,
+                PacketType::ConnectionDeny {
+                    reason: deny_reason::SERVER_FULL,
+                },
+            );
+            return;
+        }
+
+        let server_salt: u64 = random();
+        let key_share = if self.config.protection.uses_session_keys() {
+            match KeyShare::generate() {
+                Ok(share) => Some(share),
+                Err(e) => {
+                    log::warn!("Failed to generate key share for {}: {}", addr, e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let challenge_payload = self.challenge_payload(server_salt, key_share.as_ref());
+        self.send_raw_with_payload(
+            addr,
+            PacketType::ConnectionChallenge { server_salt },
+            challenge_payload.clone(),
+        );
+        self.pending.insert(
             addr,
-            self.config.protocol_id,
-            0,
-            packet_type,
+            PendingConnection {
+                server_salt,
+                created_at: self.clock.now(),
+                token,
+                key_share,
+                challenge_payload,
+            },
         );
     }
+
+    /// The key share a challenge carries, followed by its identity signature.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables, unused_mut))]
+    fn challenge_payload(&self, server_salt: u64, key_share: Option<&KeyShare>) -> Vec<u8> {
+        let Some(share) = key_share else {
+            return Vec::new();
+        };
+        let mut payload = share.public_key().to_vec();
+        #[cfg(feature = "encryption")]
+        if let Some(identity) = &self.identity {
+            payload.extend_from_slice(&identity.sign_key_share(
+                self.config.protocol_id,
+                server_salt,
+                share.public_key(),
+            ));
+        }
+        payload
+    }
+
+    fn send_raw(&mut self, addr: SocketAddr, packet_type: PacketType) {
+        self.send_raw_with_payload(addr, packet_type, Vec::new());
+    }
+
+    fn send_raw_with_payload(
+        &mut self,
+        addr: SocketAddr,
+        packet_type: PacketType,
+        payload: Vec<u8>,
+    ) {
+        if let Some(data) =
+            wire::encode_raw_packet_with_payload(self.config.protocol_id, 0, packet_type, payload)
+        {
+            self.queue_datagram(data, addr);
+        }
+    }
+
+    /// Queue a datagram on the link, charging it to the server egress budget.
+    fn queue_datagram(&mut self, data: Vec<u8>, addr: SocketAddr) {
+        if let Some(ref mut limiter) = self.bandwidth_limiter {
+            limiter.consume(data.len());
+        }
+        self.link.send(data, addr);
+    }
+
+    /// Write all queued datagrams, batching where the transport supports it.
+    fn flush(&mut self) {
+        for (data, addr) in self.link.flush(&mut self.transport) {
+            if let Some(ref mut limiter) = self.bandwidth_limiter {
+                limiter.refund(data.len());
+            }
+            if let Some(conn) = self
+                .connections
+                .get_mut(&addr)
+                .or_else(|| self.disconnecting.get_mut(&addr))
+            {
+                conn.record_send_failure(&data);
+            }
+        }
+    }
+}
+
+/// The payload of a client handshake packet that arrived in a datagram of
+/// `datagram_len` bytes, or `None` if the datagram is under
+/// [`MIN_HANDSHAKE_REQUEST_SIZE`] and must be dropped unanswered.
+fn handshake_payload(datagram_len: usize, payload: &[u8]) -> Option<&[u8]> {
+    if datagram_len < MIN_HANDSHAKE_REQUEST_SIZE {
+        return None;
+    }
+    wire::unpad_handshake_payload(payload)
+}
+
+/// Whether `packet_type` carries channel messages.
+fn is_data_packet(packet_type: &PacketType) -> bool {
+    matches!(
+        packet_type,
+        PacketType::Payload { .. } | PacketType::BatchedPayload { .. } | PacketType::MultiPayload
+    )
 }
 
-impl Drop for NetServer {
+impl<T: Transport> Drop for NetServer<T> {
     fn drop(&mut self) {
         self.shutdown();
     }
@@ -1,11 +1,12 @@
 //! Network condition simulator for testing: packet loss, latency, jitter,
 //! duplicates, reordering, and bandwidth limiting.
-use rand::Rng;
-use std::collections::VecDeque;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
-use crate::config::SimulationConfig;
+use crate::clock::{system_clock, SharedClock};
+use crate::config::{NetworkCondition, SimulationConfig};
 
 #[derive(Debug)]
 struct DelayedPacket {
@@ -14,107 +15,159 @@ struct DelayedPacket {
     deliver_at: Instant,
 }
 
+/// Conditions in effect at a point in time after applying the script.
+#[derive(Debug, Clone, Copy)]
+struct ActiveConditions {
+    packet_loss: f32,
+    latency_ms: u32,
+    bandwidth_limit_bytes_per_sec: usize,
+}
+
 /// Simulates network conditions (loss, latency, jitter, duplicates, reordering).
+///
+/// With [`SimulationConfig::seed`] set and a [`crate::clock::ManualClock`]
+/// supplied via [`NetworkSimulator::with_clock`], the exact sequence of
+/// dropped, delayed, and duplicated packets is reproducible.
 #[derive(Debug)]
 pub struct NetworkSimulator {
     config: SimulationConfig,
-    delayed_packets: VecDeque<DelayedPacket>,
+    delayed_packets: Vec<DelayedPacket>,
     token_bucket_tokens: f64,
     last_token_refill: Instant,
+    rng: StdRng,
+    clock: SharedClock,
+    started_at: Instant,
 }
 
 impl NetworkSimulator {
     pub fn new(config: SimulationConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        let clock = system_clock();
+        let now = clock.now();
         Self {
+            token_bucket_tokens: config.bandwidth_limit_bytes_per_sec as f64,
             config,
-            delayed_packets: VecDeque::new(),
-            token_bucket_tokens: 0.0,
-            last_token_refill: Instant::now(),
+            delayed_packets: Vec::new(),
+            last_token_refill: now,
+            rng,
+            clock,
+            started_at: now,
         }
     }
 
+    /// Use `clock` for delays, bandwidth refill, and the condition script.
+    /// Restarts the script timeline at the clock's current time.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        let now = clock.now();
+        self.clock = clock;
+        self.last_token_refill = now;
+        self.started_at = now;
+        self
+    }
+
     /// Process an outgoing packet through the simulator.
     /// Returns packets ready for immediate delivery, or buffers them for delayed delivery.
     pub fn process_send(&mut self, data: &[u8], addr: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
-        let mut rng = rand::rng();
+        let now = self.clock.now();
+        let active = self.active_conditions(now);
         let mut result = Vec::new();
 
-        if self.config.packet_loss > 0.0 && rng.random::<f32>() < self.config.packet_loss {
+        if active.packet_loss > 0.0 && self.rng.random::<f32>() < active.packet_loss {
             return result;
         }
 
-        if self.config.bandwidth_limit_bytes_per_sec > 0 {
-            self.refill_tokens();
+        if active.bandwidth_limit_bytes_per_sec > 0 {
+            self.refill_tokens(now, active.bandwidth_limit_bytes_per_sec);
             if self.token_bucket_tokens < data.len() as f64 {
                 return result; // Over bandwidth
             }
             self.token_bucket_tokens -= data.len() as f64;
         }
 
-        let base_latency = self.config.latency_ms as f64;
+        let base_latency = active.latency_ms as f64;
         let jitter = if self.config.jitter_ms > 0 {
-            rng.random_range(0.0..self.config.jitter_ms as f64)
+            self.rng.random_range(0.0..self.config.jitter_ms as f64)
         } else {
             0.0
         };
         let delay_ms = base_latency + jitter;
 
         let extra = if self.config.out_of_order_chance > 0.0
-            && rng.random::<f32>() < self.config.out_of_order_chance
+            && self.rng.random::<f32>() < self.config.out_of_order_chance
         {
-            rng.random_range(0.0..50.0)
+            self.rng.random_range(0.0..50.0)
         } else {
             0.0
         };
 
         let total_delay = Duration::from_millis((delay_ms + extra) as u64);
-        let deliver_at = Instant::now() + total_delay;
 
         if total_delay.is_zero() {
             result.push((data.to_vec(), addr));
         } else {
-            self.delayed_packets.push_back(DelayedPacket {
+            self.delayed_packets.push(DelayedPacket {
                 data: data.to_vec(),
                 addr,
-                deliver_at,
+                deliver_at: now + total_delay,
             });
         }
 
-        if self.config.duplicate_chance > 0.0 && rng.random::<f32>() < self.config.duplicate_chance
+        if self.config.duplicate_chance > 0.0
+            && self.rng.random::<f32>() < self.config.duplicate_chance
         {
-            let dup_delay = Duration::from_millis((delay_ms + rng.random_range(0.0..20.0)) as u64);
-            self.delayed_packets.push_back(DelayedPacket {
+            let dup_delay =
+                Duration::from_millis((delay_ms + self.rng.random_range(0.0..20.0)) as u64);
+            self.delayed_packets.push(DelayedPacket {
                 data: data.to_vec(),
                 addr,
-                deliver_at: Instant::now() + dup_delay,
+                deliver_at: now + dup_delay,
             });
         }
 
         result
     }
 
-    /// Retrieve packets that are ready for delivery.
+    /// Retrieve packets that are ready for delivery, in delivery-time order.
     pub fn receive_ready(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
-        let now = Instant::now();
-        let mut ready = Vec::new();
-
-        while let Some(front) = self.delayed_packets.front() {
-            if front.deliver_at <= now {
-                let pkt = self.delayed_packets.pop_front().unwrap();
-                ready.push((pkt.data, pkt.addr));
-            } else {
-                break;
+        let now = self.clock.now();
+        let (mut ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.delayed_packets)
+            .into_iter()
+            .partition(|p| p.deliver_at <= now);
+        self.delayed_packets = pending;
+        ready.sort_by_key(|p| p.deliver_at);
+        ready.into_iter().map(|p| (p.data, p.addr)).collect()
+    }
+
+    fn active_conditions(&self, now: Instant) -> ActiveConditions {
+        let mut active = ActiveConditions {
+            packet_loss: self.config.packet_loss,
+            latency_ms: self.config.latency_ms,
+            bandwidth_limit_bytes_per_sec: self.config.bandwidth_limit_bytes_per_sec,
+        };
+        let elapsed = now.saturating_duration_since(self.started_at);
+        for scheduled in self.config.script.iter().filter(|s| s.is_active(elapsed)) {
+            match scheduled.condition {
+                NetworkCondition::LossBurst(p) => active.packet_loss = active.packet_loss.max(p),
+                NetworkCondition::LatencySpike(ms) => {
+                    active.latency_ms = active.latency_ms.saturating_add(ms)
+                }
+                NetworkCondition::Outage => active.packet_loss = 1.0,
+                NetworkCondition::Bandwidth(limit) => active.bandwidth_limit_bytes_per_sec = limit,
             }
         }
-
-        ready
+        active
     }
 
-    fn refill_tokens(&mut self) {
-        let elapsed = self.last_token_refill.elapsed().as_secs_f64();
-        self.last_token_refill = Instant::now();
-        self.token_bucket_tokens += elapsed * self.config.bandwidth_limit_bytes_per_sec as f64;
-        let max = self.config.bandwidth_limit_bytes_per_sec as f64;
+    fn refill_tokens(&mut self, now: Instant, limit: usize) {
+        let elapsed = now
+            .saturating_duration_since(self.last_token_refill)
+            .as_secs_f64();
+        self.last_token_refill = now;
+        self.token_bucket_tokens += elapsed * limit as f64;
+        let max = limit as f64;
         if self.token_bucket_tokens > max {
             self.token_bucket_tokens = max;
         }
@@ -130,6 +183,7 @@ pub use crate::stats::{assess_connection_quality, ConnectionQuality};
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
     use std::net::{IpAddr, Ipv4Addr};
 
     fn test_addr() -> SocketAddr {
@@ -202,6 +256,8 @@ mod tests {
             duplicate_chance: 0.1,
             out_of_order_chance: 0.2,
             bandwidth_limit_bytes_per_sec: 10000,
+            seed: None,
+            script: Vec::new(),
         };
         let mut sim = NetworkSimulator::new(config);
 
@@ -213,4 +269,119 @@ mod tests {
         std::thread::sleep(Duration::from_millis(20));
         let _ = sim.receive_ready();
     }
+
+    fn run_trace(config: SimulationConfig) -> Vec<(u8, u64)> {
+        let clock = ManualClock::new();
+        let mut sim = NetworkSimulator::new(config).with_clock(clock.shared());
+        let mut trace = Vec::new();
+        for i in 0..200u8 {
+            for (data, _) in sim.process_send(&[i], test_addr()) {
+                trace.push((data[0], clock.elapsed().as_millis() as u64));
+            }
+            clock.advance(Duration::from_millis(5));
+            for (data, _) in sim.receive_ready() {
+                trace.push((data[0], clock.elapsed().as_millis() as u64));
+            }
+        }
+        trace
+    }
+
+    #[test]
+    fn test_simulator_seeded_replay_is_identical() {
+        let config = SimulationConfig {
+            packet_loss: 0.2,
+            latency_ms: 10,
+            jitter_ms: 20,
+            duplicate_chance: 0.1,
+            out_of_order_chance: 0.2,
+            ..Default::default()
+        }
+        .with_seed(42);
+
+        let first = run_trace(config.clone());
+        assert_eq!(first, run_trace(config.clone()));
+        assert_ne!(first, run_trace(config.with_seed(43)));
+    }
+
+    #[test]
+    fn test_simulator_delivers_in_deadline_order() {
+        let clock = ManualClock::new();
+        let config = SimulationConfig {
+            latency_ms: 100,
+            ..Default::default()
+        }
+        .with_condition(
+            Duration::ZERO,
+            Duration::from_millis(1),
+            NetworkCondition::LatencySpike(200),
+        );
+        let mut sim = NetworkSimulator::new(config).with_clock(clock.shared());
+
+        assert!(sim.process_send(b"slow", test_addr()).is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert!(sim.process_send(b"fast", test_addr()).is_empty());
+
+        clock.advance(Duration::from_millis(100));
+        let ready = sim.receive_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, b"fast");
+
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(sim.receive_ready()[0].0, b"slow");
+    }
+
+    #[test]
+    fn test_simulator_scripted_outage_and_loss_burst() {
+        let clock = ManualClock::new();
+        let config = SimulationConfig::default()
+            .with_seed(7)
+            .with_condition(
+                Duration::from_millis(100),
+                Duration::from_millis(100),
+                NetworkCondition::Outage,
+            )
+            .with_condition(
+                Duration::from_millis(300),
+                Duration::from_millis(100),
+                NetworkCondition::LossBurst(1.0),
+            );
+        let mut sim = NetworkSimulator::new(config).with_clock(clock.shared());
+
+        let mut delivered = Vec::new();
+        for _ in 0..10 {
+            delivered.push(!sim.process_send(b"x", test_addr()).is_empty());
+            clock.advance(Duration::from_millis(50));
+        }
+        assert_eq!(
+            delivered,
+            [true, true, false, false, true, true, false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn test_simulator_scripted_bandwidth_change() {
+        let clock = ManualClock::new();
+        let config = SimulationConfig {
+            bandwidth_limit_bytes_per_sec: 1000,
+            ..Default::default()
+        }
+        .with_condition(
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            NetworkCondition::Bandwidth(100),
+        );
+        let mut sim = NetworkSimulator::new(config).with_clock(clock.shared());
+
+        let packet = [0u8; 200];
+        assert_eq!(sim.process_send(&packet, test_addr()).len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(
+            sim.process_send(&packet, test_addr()).is_empty(),
+            "200 bytes exceeds the throttled 100 B/s budget"
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sim.process_send(&packet, test_addr()).len(), 1);
+    }
 }
@@ -1,13 +1,54 @@
 //! Platform-agnostic non-blocking UDP socket wrapper with statistics tracking.
 use std::io::{Error as IoError, ErrorKind};
-use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
 use std::time::{Duration, Instant};
 
 use crate::stats::SocketStats;
+use crate::transport::Transport;
 
 /// Maximum size of a single UDP datagram.
 const MAX_UDP_PACKET_SIZE: usize = 65536;
 
+/// Default size of each slot used by batched receives. Matches
+/// [`UdpSocket::recv_from`] so batching never drops a datagram that a single
+/// receive would accept.
+pub const DEFAULT_BATCH_SLOT_SIZE: usize = MAX_UDP_PACKET_SIZE;
+
+/// Unspecified address of the same family as `peer`, for binding a socket
+/// that will talk to it.
+pub fn unspecified_addr_for(peer: &SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+/// Whether a socket bound to `local` can exchange datagrams with `peer`. An
+/// unspecified IPv6 address binds dual-stack and reaches IPv4 peers through
+/// IPv4-mapped addresses.
+pub(crate) fn bind_reaches(local: &SocketAddr, peer: &SocketAddr) -> bool {
+    match (local.ip(), peer.ip().to_canonical()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => true,
+        (IpAddr::V6(ip), IpAddr::V4(_)) => ip.is_unspecified(),
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+    }
+}
+
+/// Collapses IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to plain IPv4 so a
+/// peer has one address regardless of which socket family received it.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Maps an IPv4 destination to its IPv4-mapped form when sending from an
+/// IPv6 socket, which cannot address IPv4 peers directly.
+fn addr_for_family(addr: SocketAddr, ipv6: bool) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ipv6 => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        _ => addr,
+    }
+}
+
 /// Errors that can occur during socket operations.
 #[derive(Debug)]
 pub enum SocketError {
@@ -49,25 +90,68 @@ impl From<IoError> for SocketError {
 }
 
 /// Non-blocking UDP socket with per-socket statistics.
+///
+/// Peer addresses are reported in canonical form: IPv4 peers reaching an
+/// IPv6 or dual-stack socket appear as plain IPv4 addresses, and IPv4
+/// destinations are mapped automatically when sending from one.
+///
+/// On Linux the [`Transport`] batch methods use `recvmmsg`/`sendmmsg` to move
+/// many datagrams per system call; elsewhere they fall back to one call per
+/// datagram.
 pub struct UdpSocket {
     socket: StdUdpSocket,
     recv_buffer: Vec<u8>,
     stats: SocketStats,
+    ipv6: bool,
+    batch_slot_size: usize,
+    #[cfg(target_os = "linux")]
+    batch_buffers: Vec<Vec<u8>>,
 }
 
 impl UdpSocket {
     /// Creates a new UDP socket bound to the specified address
     pub fn bind(addr: SocketAddr) -> Result<Self, SocketError> {
-        let socket = StdUdpSocket::bind(addr)?;
+        Self::from_std(StdUdpSocket::bind(addr)?)
+    }
+
+    /// Creates a socket bound to `[::]:port` that accepts both IPv6 and IPv4
+    /// peers. Port 0 picks a free port.
+    ///
+    /// On Unix the socket clears `IPV6_V6ONLY` explicitly; elsewhere it
+    /// relies on the platform default, which may be IPv6-only.
+    pub fn bind_dual_stack(port: u16) -> Result<Self, SocketError> {
+        #[cfg(unix)]
+        let socket = dual_stack::bind(port)?;
+        #[cfg(not(unix))]
+        let socket = StdUdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))?;
+        Self::from_std(socket)
+    }
+
+    fn from_std(socket: StdUdpSocket) -> Result<Self, SocketError> {
         socket.set_nonblocking(true)?;
+        let ipv6 = socket.local_addr()?.is_ipv6();
 
         Ok(Self {
             socket,
             recv_buffer: vec![0u8; MAX_UDP_PACKET_SIZE],
             stats: SocketStats::default(),
+            ipv6,
+            batch_slot_size: DEFAULT_BATCH_SLOT_SIZE,
+            #[cfg(target_os = "linux")]
+            batch_buffers: Vec::new(),
         })
     }
 
+    /// Sets the per-datagram buffer size for batched receives, trading memory
+    /// for the largest datagram accepted. Larger datagrams are dropped and
+    /// counted in [`SocketStats::datagrams_truncated`].
+    pub fn with_batch_slot_size(mut self, size: usize) -> Self {
+        self.batch_slot_size = size.clamp(1, MAX_UDP_PACKET_SIZE);
+        #[cfg(target_os = "linux")]
+        self.batch_buffers.clear();
+        self
+    }
+
     /// Returns the local address this socket is bound to
     pub fn local_addr(&self) -> Result<SocketAddr, SocketError> {
         Ok(self.socket.local_addr()?)
@@ -75,7 +159,9 @@ impl UdpSocket {
 
     /// Sends data to a specific address
     pub fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
-        let sent = self.socket.send_to(data, addr)?;
+        let sent = self
+            .socket
+            .send_to(data, addr_for_family(addr, self.ipv6))?;
         self.stats.bytes_sent += sent as u64;
         self.stats.packets_sent += 1;
         self.stats.last_send_time = Some(Instant::now());
@@ -89,7 +175,7 @@ impl UdpSocket {
                 self.stats.bytes_received += len as u64;
                 self.stats.packets_received += 1;
                 self.stats.last_receive_time = Some(Instant::now());
-                Ok((&self.recv_buffer[..len], addr))
+                Ok((&self.recv_buffer[..len], canonical_addr(addr)))
             }
             Err(e) => Err(e.into()),
         }
@@ -117,3 +203,307 @@ impl UdpSocket {
         self.stats = SocketStats::default();
     }
 }
+
+impl Transport for UdpSocket {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
+        UdpSocket::send_to(self, data, addr)
+    }
+
+    fn recv_from(&mut self) -> Result<(&[u8], SocketAddr), SocketError> {
+        UdpSocket::recv_from(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, SocketError> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn stats(&self) -> &SocketStats {
+        UdpSocket::stats(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(
+        &mut self,
+        out: &mut Vec<(Vec<u8>, SocketAddr)>,
+        max: usize,
+    ) -> Result<usize, SocketError> {
+        let slots = max.min(mmsg::MAX_BATCH);
+        if self.batch_buffers.len() < slots {
+            self.batch_buffers
+                .resize_with(slots, || vec![0u8; self.batch_slot_size]);
+        }
+        // Retry when every datagram in a batch was truncated, so `Ok(0)`
+        // keeps meaning the queue is empty.
+        loop {
+            let received = match mmsg::recv(&self.socket, &mut self.batch_buffers[..slots]) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(0),
+                Err(e) => return Err(e.into()),
+            };
+            if received.is_empty() {
+                return Ok(0);
+            }
+
+            let mut count = 0;
+            for (slot, len, addr, truncated) in received {
+                if truncated {
+                    self.stats.datagrams_truncated += 1;
+                    continue;
+                }
+                self.stats.bytes_received += len as u64;
+                self.stats.packets_received += 1;
+                out.push((
+                    self.batch_buffers[slot][..len].to_vec(),
+                    canonical_addr(addr),
+                ));
+                count += 1;
+            }
+            if count > 0 {
+                self.stats.last_receive_time = Some(Instant::now());
+                return Ok(count);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self, datagrams: &[(Vec<u8>, SocketAddr)]) -> Result<usize, SocketError> {
+        let mut sent = 0;
+        for chunk in datagrams.chunks(mmsg::MAX_BATCH) {
+            let lengths = match mmsg::send(&self.socket, chunk, self.ipv6) {
+                Ok(lengths) => lengths,
+                Err(e) if sent == 0 => return Err(e.into()),
+                Err(_) => break,
+            };
+            for len in &lengths {
+                self.stats.bytes_sent += *len as u64;
+                self.stats.packets_sent += 1;
+            }
+            if !lengths.is_empty() {
+                self.stats.last_send_time = Some(Instant::now());
+            }
+            sent += lengths.len();
+            if lengths.len() < chunk.len() {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Dual-stack socket creation; std cannot set `IPV6_V6ONLY` before binding.
+#[cfg(unix)]
+mod dual_stack {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv6Addr, UdpSocket};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::ptr;
+
+    pub(super) fn bind(port: u16) -> io::Result<UdpSocket> {
+        // SAFETY: plain socket creation; ownership of the fd moves into `OwnedFd`.
+        let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a freshly created, open descriptor we own.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw = fd.as_raw_fd();
+
+        let off: libc::c_int = 0;
+        // SAFETY: `off` outlives the call and the length matches its type.
+        let rc = unsafe {
+            libc::setsockopt(
+                raw,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                ptr::from_ref(&off).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: all-zero is a valid sockaddr_in6; the fields we need are set below.
+        let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sin6.sin6_port = port.to_be();
+        sin6.sin6_addr = libc::in6_addr {
+            s6_addr: Ipv6Addr::UNSPECIFIED.octets(),
+        };
+        // SAFETY: `sin6` is a valid sockaddr_in6 and the length matches it.
+        let rc = unsafe {
+            libc::bind(
+                raw,
+                ptr::from_ref(&sin6).cast(),
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(UdpSocket::from(fd))
+    }
+}
+
+/// `recvmmsg`/`sendmmsg` wrappers for batched datagram I/O.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    /// Maximum datagrams per system call.
+    pub(super) const MAX_BATCH: usize = 64;
+
+    /// Receives into `buffers`, returning `(slot, len, from, truncated)` per datagram.
+    pub(super) fn recv(
+        socket: &UdpSocket,
+        buffers: &mut [Vec<u8>],
+    ) -> io::Result<Vec<(usize, usize, SocketAddr, bool)>> {
+        let count = buffers.len().min(MAX_BATCH);
+        // SAFETY: all-zero is a valid bit pattern for these C structs.
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+
+        for i in 0..count {
+            iovecs[i] = libc::iovec {
+                iov_base: buffers[i].as_mut_ptr().cast(),
+                iov_len: buffers[i].len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+            hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: every header points at live, correctly sized buffers above.
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut result = Vec::with_capacity(received as usize);
+        for (i, msg) in msgs.iter().enumerate().take(received as usize) {
+            let Some(addr) = from_raw(&addrs[i]) else {
+                continue;
+            };
+            let truncated = msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+            result.push((i, msg.msg_len as usize, addr, truncated));
+        }
+        Ok(result)
+    }
+
+    /// Sends `datagrams`, returning the byte count of each one accepted.
+    pub(super) fn send(
+        socket: &UdpSocket,
+        datagrams: &[(Vec<u8>, SocketAddr)],
+        ipv6: bool,
+    ) -> io::Result<Vec<usize>> {
+        let count = datagrams.len().min(MAX_BATCH);
+        // SAFETY: all-zero is a valid bit pattern for these C structs.
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+
+        for (i, (data, addr)) in datagrams.iter().take(count).enumerate() {
+            let addr_len = to_raw(&super::addr_for_family(*addr, ipv6), &mut addrs[i]);
+            iovecs[i] = libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+            hdr.msg_namelen = addr_len;
+            hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+            hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: every header points at live buffers; the kernel only reads them.
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(msgs[..sent as usize]
+            .iter()
+            .map(|m| m.msg_len as usize)
+            .collect())
+    }
+
+    fn to_raw(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(v4) => {
+                let sin = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: v4.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(*v4.ip()).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in.
+                unsafe { ptr::write(ptr::from_mut(storage).cast(), sin) };
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(v6) => {
+                let sin6 = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: v6.port().to_be(),
+                    sin6_flowinfo: v6.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: v6.ip().octets(),
+                    },
+                    sin6_scope_id: v6.scope_id(),
+                };
+                // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in6.
+                unsafe { ptr::write(ptr::from_mut(storage).cast(), sin6) };
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_raw(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the kernel wrote a sockaddr_in for AF_INET.
+                let sin: libc::sockaddr_in = unsafe { ptr::read(ptr::from_ref(storage).cast()) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    ip,
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the kernel wrote a sockaddr_in6 for AF_INET6.
+                let sin6: libc::sockaddr_in6 = unsafe { ptr::read(ptr::from_ref(storage).cast()) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
@@ -43,6 +43,24 @@ pub struct NetworkStats {
     pub bandwidth_down: f32,
     pub send_errors: u64,
     pub connection_quality: ConnectionQuality,
+    /// Packets sent carrying more than one message.
+    pub batched_packets_sent: u64,
+    /// Messages sent inside those batched packets.
+    pub batched_messages_sent: u64,
+    /// Packets dropped for failing session authentication, replaying an
+    /// already-received counter, or arriving unsealed on an encrypted session.
+    pub packets_rejected: u64,
+    /// Session key switches completed by an in-band rekey.
+    pub rekeys: u64,
+    /// Packets discarded for exceeding the inbound packet or byte rate.
+    pub flood_packets_dropped: u64,
+    /// Packets over the inbound rate held for a later tick.
+    pub flood_packets_deferred: u64,
+    /// Messages discarded for exceeding the per-channel per-tick cap.
+    pub flood_messages_dropped: u64,
+    /// Fragments discarded because they would open a reassembly buffer over
+    /// the limit.
+    pub flood_fragments_dropped: u64,
 }
 
 impl Default for NetworkStats {
@@ -58,10 +76,40 @@ impl Default for NetworkStats {
             bandwidth_down: 0.0,
             send_errors: 0,
             connection_quality: ConnectionQuality::Good,
+            batched_packets_sent: 0,
+            batched_messages_sent: 0,
+            packets_rejected: 0,
+            rekeys: 0,
+            flood_packets_dropped: 0,
+            flood_packets_deferred: 0,
+            flood_messages_dropped: 0,
+            flood_fragments_dropped: 0,
         }
     }
 }
 
+impl NetworkStats {
+    /// Average number of messages per batched packet, or 0 if nothing was batched.
+    pub fn messages_per_batch(&self) -> f32 {
+        if self.batched_packets_sent == 0 {
+            0.0
+        } else {
+            self.batched_messages_sent as f32 / self.batched_packets_sent as f32
+        }
+    }
+}
+
+/// Server-wide counters for traffic that never reaches a connection.
+#[derive(Debug, Default, Clone)]
+pub struct ServerStats {
+    /// Datagrams dropped because their source is banned or outside the
+    /// allow list.
+    pub packets_blocked: u64,
+    /// Connection requests from blocked sources, answered with
+    /// `deny_reason::BANNED` while the rate limiter allows.
+    pub connection_requests_blocked: u64,
+}
+
 /// Per-channel message and buffer statistics.
 #[derive(Debug, Clone)]
 pub struct ChannelStats {
@@ -102,4 +150,6 @@ pub struct SocketStats {
     pub bytes_received: u64,
     pub last_receive_time: Option<Instant>,
     pub last_send_time: Option<Instant>,
+    /// Datagrams dropped because they exceeded the batched receive slot size.
+    pub datagrams_truncated: u64,
 }
@@ -1,10 +1,12 @@
 use crate::{
     channel::{Channel, ChannelError},
+    clock::ManualClock,
     config::{
-        ChannelConfig, ConfigError, DeliveryMode, NetworkConfig, DEFAULT_MTU, MAX_CHANNEL_COUNT,
-        MAX_MTU, MIN_MTU,
+        ChannelConfig, ConfigError, DeliveryMode, NetworkConfig, SimulationConfig, VersionPolicy,
+        DEFAULT_MTU, MAX_CHANNEL_COUNT, MAX_MTU, MIN_MTU,
     },
-    connection::{Connection, ConnectionError},
+    connection::{Connection, ConnectionError, ConnectionState},
+    delta::BaselineManager,
     packet::{sequence_diff, sequence_greater_than, Packet, PacketHeader, PacketType},
     reliability::{ReliableEndpoint, SequenceBuffer},
     socket::UdpSocket,
@@ -142,6 +144,39 @@ fn test_connection_states() {
     ));
 }
 
+#[test]
+fn test_connection_timeout_with_manual_clock() {
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default();
+    let timeout = config.connection_timeout;
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let remote = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+
+    let mut conn = Connection::new(config, local, remote).with_clock(clock.shared());
+    conn.set_state(ConnectionState::Connected);
+    conn.touch_recv_time();
+
+    clock.advance(timeout);
+    assert!(conn.update_tick().is_ok());
+
+    clock.advance(Duration::from_millis(1));
+    assert!(matches!(conn.update_tick(), Err(ConnectionError::Timeout)));
+    assert_eq!(conn.state(), ConnectionState::Disconnected);
+}
+
+#[test]
+fn test_baseline_expiry_with_manual_clock() {
+    let clock = ManualClock::new();
+    let mut baselines = BaselineManager::new(8, Duration::from_secs(2)).with_clock(clock.shared());
+
+    baselines.push_snapshot(1, 10u32);
+    clock.advance(Duration::from_secs(3));
+    baselines.push_snapshot(2, 20u32);
+
+    assert!(baselines.get_baseline(1).is_none());
+    assert_eq!(baselines.get_baseline(2), Some(&20));
+}
+
 #[test]
 fn test_config_defaults() {
     let config = NetworkConfig::default();
@@ -223,6 +258,59 @@ fn test_config_validation_too_many_channels() {
     ));
 }
 
+#[test]
+fn test_payload_channel_id_roundtrip() {
+    for channel in [0u8, 7, 8, 200, 255] {
+        let header = PacketHeader {
+            protocol_id: 1,
+            sequence: 0,
+            ack: 0,
+            ack_bits: 0,
+        };
+        let packet = Packet::new(
+            header.clone(),
+            PacketType::Payload {
+                channel,
+                is_fragment: true,
+            },
+        );
+        let decoded = Packet::deserialize(&packet.serialize().unwrap()).unwrap();
+        assert_eq!(
+            decoded.packet_type,
+            PacketType::Payload {
+                channel,
+                is_fragment: true
+            }
+        );
+
+        let batched = Packet::new(header, PacketType::BatchedPayload { channel });
+        let decoded = Packet::deserialize(&batched.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.packet_type, PacketType::BatchedPayload { channel });
+    }
+}
+
+#[test]
+fn test_version_policy_accepts() {
+    assert!(VersionPolicy::Exact.accepts(3, 3));
+    assert!(!VersionPolicy::Exact.accepts(3, 2));
+    assert!(VersionPolicy::AtLeast(2).accepts(3, 2));
+    assert!(!VersionPolicy::AtLeast(2).accepts(3, 1));
+    let range = VersionPolicy::Range { min: 2, max: 4 };
+    assert!(range.accepts(3, 4));
+    assert!(!range.accepts(3, 5));
+    assert!(VersionPolicy::Any.accepts(3, 0));
+}
+
+#[test]
+fn test_config_validation_inverted_version_range() {
+    let config =
+        NetworkConfig::default().with_version_policy(VersionPolicy::Range { min: 5, max: 1 });
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::InvalidVersionPolicy)
+    ));
+}
+
 #[test]
 fn test_config_validation_zero_packet_buffer() {
     let config = NetworkConfig {
@@ -279,6 +367,76 @@ fn test_config_validation_zero_max_clients() {
     ));
 }
 
+#[test]
+fn test_config_validation_invalid_simulation() {
+    let config = NetworkConfig::default().with_incoming_simulation(SimulationConfig {
+        packet_loss: 1.5,
+        ..Default::default()
+    });
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::InvalidSimulation)
+    ));
+}
+
+#[test]
+fn test_config_validation_cookie_window_within_rotation() {
+    for config in [
+        NetworkConfig::default().with_cookie_window(Duration::ZERO),
+        NetworkConfig::default()
+            .with_cookie_window(Duration::from_secs(30))
+            .with_cookie_secret_rotation(Duration::from_secs(10)),
+    ] {
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidCookieTiming)
+        ));
+    }
+}
+
+#[test]
+fn test_config_validation_rate_limit_prefixes() {
+    for config in [
+        NetworkConfig::default().with_rate_limit_prefixes(33, 64),
+        NetworkConfig::default().with_rate_limit_prefixes(24, 129),
+    ] {
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRateLimitPrefix)
+        ));
+    }
+    assert!(NetworkConfig::default()
+        .with_rate_limit_prefixes(32, 128)
+        .validate()
+        .is_ok());
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn test_config_validation_protection_requires_feature() {
+    for config in [
+        NetworkConfig::default().with_encryption(),
+        NetworkConfig::default().with_authentication(),
+    ] {
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::ProtectionRequiresFeature)
+        ));
+    }
+}
+
+#[test]
+fn test_config_validation_keys_require_protection() {
+    let config = NetworkConfig {
+        pinned_server_key: Some([0x2b; 32]),
+        ..Default::default()
+    };
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::KeyRequiresProtection)
+    ));
+}
+
 // ─── Packet roundtrip tests ────────────────────────────────────────────────
 
 #[test]
@@ -305,10 +463,14 @@ fn test_packet_roundtrip_connection_request() {
         ack: 0,
         ack_bits: 0,
     };
-    let packet = Packet::new(header, PacketType::ConnectionRequest);
+    let request = PacketType::ConnectionRequest {
+        protocol_version: crate::packet::PROTOCOL_VERSION,
+        app_version: 0xDEAD_BEEF,
+    };
+    let packet = Packet::new(header, request.clone());
     let data = packet.serialize().unwrap();
     let parsed = Packet::deserialize(&data).unwrap();
-    assert!(matches!(parsed.packet_type, PacketType::ConnectionRequest));
+    assert_eq!(parsed.packet_type, request);
 }
 
 #[test]
@@ -383,6 +545,31 @@ fn test_packet_roundtrip_disconnect() {
     }
 }
 
+#[test]
+fn test_packet_body_roundtrip_without_header() {
+    let header = PacketHeader {
+        protocol_id: 0x1234,
+        sequence: 7,
+        ack: 6,
+        ack_bits: 0b11,
+    };
+    let packet = Packet::new(
+        header.clone(),
+        PacketType::Payload {
+            channel: 200,
+            is_fragment: true,
+        },
+    )
+    .with_payload(vec![9, 8, 7]);
+    let body = packet.serialize_body().unwrap();
+    assert!(body.len() < packet.serialize().unwrap().len());
+
+    let parsed = Packet::deserialize_body(header, &body).unwrap();
+    assert_eq!(parsed.header.sequence, 7);
+    assert_eq!(parsed.packet_type, packet.packet_type);
+    assert_eq!(parsed.payload, vec![9, 8, 7]);
+}
+
 #[test]
 fn test_packet_protocol_id_mismatch_detected() {
     let header = PacketHeader {
@@ -436,3 +623,275 @@ fn test_reliable_channel_with_unreliable_message() {
         "reliable=false should skip pending ACK"
     );
 }
+
+#[test]
+fn test_socket_batch_roundtrip() {
+    use crate::transport::Transport;
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let mut sender = UdpSocket::bind(addr).unwrap();
+    let mut receiver = UdpSocket::bind(addr).unwrap();
+    let dest = receiver.local_addr().unwrap();
+
+    let datagrams: Vec<(Vec<u8>, SocketAddr)> = (0u8..10).map(|i| (vec![i; 100], dest)).collect();
+    assert_eq!(sender.send_batch(&datagrams).unwrap(), 10);
+    assert_eq!(sender.stats().packets_sent, 10);
+    assert_eq!(sender.stats().bytes_sent, 1000);
+
+    let mut out = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while out.len() < 10 && Instant::now() < deadline {
+        receiver.recv_batch(&mut out, 64).unwrap();
+    }
+    assert_eq!(out.len(), 10);
+    for (i, (data, from)) in out.iter().enumerate() {
+        assert_eq!(data, &vec![i as u8; 100]);
+        assert_eq!(*from, sender.local_addr().unwrap());
+    }
+    assert_eq!(receiver.stats().packets_received, 10);
+    assert_eq!(receiver.stats().bytes_received, 1000);
+    assert_eq!(receiver.recv_batch(&mut out, 64).unwrap(), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_socket_batch_drops_truncated() {
+    use crate::transport::Transport;
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let mut sender = UdpSocket::bind(addr).unwrap();
+    let mut receiver = UdpSocket::bind(addr).unwrap().with_batch_slot_size(64);
+    let dest = receiver.local_addr().unwrap();
+
+    sender.send_to(&[1u8; 200], dest).unwrap();
+    sender.send_to(&[2u8; 32], dest).unwrap();
+
+    let mut out = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while out.is_empty() && Instant::now() < deadline {
+        receiver.recv_batch(&mut out, 64).unwrap();
+    }
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].0, vec![2u8; 32]);
+    assert_eq!(receiver.stats().datagrams_truncated, 1);
+    assert_eq!(receiver.stats().packets_received, 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_socket_batch_receives_datagrams_larger_than_mtu() {
+    use crate::transport::Transport;
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let mut sender = UdpSocket::bind(addr).unwrap();
+    let mut receiver = UdpSocket::bind(addr).unwrap();
+    let dest = receiver.local_addr().unwrap();
+
+    sender.send_to(&[7u8; 8_000], dest).unwrap();
+
+    let mut out = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while out.is_empty() && Instant::now() < deadline {
+        receiver.recv_batch(&mut out, 64).unwrap();
+    }
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].0.len(), 8_000);
+    assert_eq!(receiver.stats().datagrams_truncated, 0);
+}
+
+/// A connection already in the connected state, driven by `clock`.
+fn connected_connection(config: NetworkConfig, clock: &ManualClock) -> Connection {
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let remote = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1234);
+    let mut conn = Connection::new(config, local, remote).with_clock(clock.shared());
+    conn.set_state(ConnectionState::Connected);
+    conn.touch_recv_time();
+    conn
+}
+
+fn batched_packets(packets: &[Packet]) -> Vec<&Packet> {
+    packets
+        .iter()
+        .filter(|p| matches!(p.packet_type, PacketType::BatchedPayload { .. }))
+        .collect()
+}
+
+#[test]
+fn test_same_channel_messages_sent_as_batched_payload() {
+    let clock = ManualClock::new();
+    let mut conn = connected_connection(NetworkConfig::default(), &clock);
+    for i in 0..20u8 {
+        conn.send(0, &[i; 8], true).unwrap();
+    }
+    conn.update_tick().unwrap();
+
+    let packets = conn.drain_send_queue();
+    let batched = batched_packets(&packets);
+    assert_eq!(batched.len(), 1);
+    assert_eq!(
+        batched[0].packet_type,
+        PacketType::BatchedPayload { channel: 0 }
+    );
+    assert_eq!(conn.stats().batched_packets_sent, 1);
+    assert_eq!(conn.stats().batched_messages_sent, 20);
+    assert_eq!(conn.stats().messages_per_batch(), 20.0);
+
+    let mut peer = connected_connection(NetworkConfig::default(), &clock);
+    peer.receive_batched_payload(0, &batched[0].payload);
+    for i in 0..20u8 {
+        assert_eq!(peer.receive(0), Some(vec![i; 8]));
+    }
+
+    // Acking the batched packet releases every reliable message in it.
+    assert_eq!(conn.channel_stats()[0].pending_ack_count, 20);
+    conn.process_incoming_header(&PacketHeader {
+        protocol_id: conn.config().protocol_id,
+        sequence: 0,
+        ack: batched[0].header.sequence,
+        ack_bits: 0,
+    });
+    assert_eq!(conn.channel_stats()[0].pending_ack_count, 0);
+}
+
+#[test]
+fn test_batched_reliable_messages_retransmitted_together() {
+    let clock = ManualClock::new();
+    let mut conn = connected_connection(NetworkConfig::default(), &clock);
+    for i in 0..5u8 {
+        conn.send(0, &[i; 8], true).unwrap();
+    }
+    conn.update_tick().unwrap();
+    assert_eq!(batched_packets(&conn.drain_send_queue()).len(), 1);
+
+    // The batch is lost; once the RTO passes every message goes out again.
+    clock.advance(Duration::from_secs(2));
+    conn.touch_recv_time();
+    conn.update_tick().unwrap();
+    let packets = conn.drain_send_queue();
+    let batched = batched_packets(&packets);
+    assert_eq!(batched.len(), 1);
+
+    let mut peer = connected_connection(NetworkConfig::default(), &clock);
+    peer.receive_batched_payload(0, &batched[0].payload);
+    for i in 0..5u8 {
+        assert_eq!(peer.receive(0), Some(vec![i; 8]));
+    }
+
+    conn.process_incoming_header(&PacketHeader {
+        protocol_id: conn.config().protocol_id,
+        sequence: 0,
+        ack: batched[0].header.sequence,
+        ack_bits: 0,
+    });
+    assert_eq!(conn.channel_stats()[0].pending_ack_count, 0);
+}
+
+#[test]
+fn test_batching_disabled_sends_one_packet_per_message() {
+    let clock = ManualClock::new();
+    let mut conn = connected_connection(NetworkConfig::default().with_batching(false), &clock);
+    for i in 0..5u8 {
+        conn.send(0, &[i; 8], true).unwrap();
+    }
+    conn.update_tick().unwrap();
+
+    let payloads = conn
+        .drain_send_queue()
+        .into_iter()
+        .filter(|p| matches!(p.packet_type, PacketType::Payload { .. }))
+        .count();
+    assert_eq!(payloads, 5);
+    assert_eq!(conn.stats().batched_packets_sent, 0);
+    assert_eq!(conn.stats().messages_per_batch(), 0.0);
+}
+
+/// Serializes the connection's queued packets and records them as sent,
+/// returning the bytes written.
+fn send_queued(conn: &mut Connection) -> usize {
+    let mut total = 0;
+    for packet in conn.drain_send_queue() {
+        let bytes = packet.serialize().unwrap().len();
+        conn.record_bytes_sent(bytes);
+        total += bytes;
+    }
+    total
+}
+
+#[test]
+fn test_bandwidth_cap_limits_bytes_per_second() {
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_max_bandwidth(4_000);
+    let mut conn = connected_connection(config, &clock);
+    for i in 0..200u8 {
+        conn.send(0, &[i; 100], true).unwrap();
+    }
+
+    let mut sent = 0;
+    for _ in 0..20 {
+        conn.touch_recv_time();
+        conn.update_tick().unwrap();
+        sent += send_queued(&mut conn);
+        clock.advance(Duration::from_millis(100));
+    }
+    // Two seconds at 4000 B/s plus the initial burst of one MTU.
+    assert!(sent <= 2 * 4_000 + DEFAULT_MTU, "sent {sent} bytes");
+    assert!(sent >= 4_000, "sent only {sent} bytes");
+    assert!(conn.channel_stats()[0].send_buffer_size > 0);
+}
+
+#[test]
+fn test_failed_sends_are_not_counted_as_sent() {
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_max_bandwidth(4_000);
+    let mut conn = connected_connection(config, &clock);
+    let (packets_before, bytes_before) = (conn.stats().packets_sent, conn.stats().bytes_sent);
+    for i in 0..3u8 {
+        conn.send(0, &[i; 100], false).unwrap();
+    }
+    conn.update_tick().unwrap();
+    let datagrams = conn.drain_datagrams();
+    assert!(!datagrams.is_empty());
+    assert!(conn.stats().bytes_sent > bytes_before);
+
+    for datagram in &datagrams {
+        conn.record_send_failure(datagram);
+    }
+    assert_eq!(conn.stats().packets_sent, packets_before);
+    assert_eq!(conn.stats().bytes_sent, bytes_before);
+    assert_eq!(conn.stats().send_errors, datagrams.len() as u64);
+
+    // Handshake datagrams were never recorded, so only the error counts.
+    let header = PacketHeader {
+        protocol_id: 0,
+        sequence: 0,
+        ack: 0,
+        ack_bits: 0,
+    };
+    let mut handshake = Packet::new(header, PacketType::ConnectionDeny { reason: 0 })
+        .serialize()
+        .unwrap();
+    handshake.extend_from_slice(&[0; 4]);
+    conn.record_send_failure(&handshake);
+    assert_eq!(conn.stats().packets_sent, packets_before);
+    assert_eq!(conn.stats().send_errors, datagrams.len() as u64 + 1);
+}
+
+#[test]
+fn test_bandwidth_cap_defers_lower_priority_channels() {
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default()
+        .with_max_bandwidth(1_000)
+        .with_channel_config(0, ChannelConfig::unreliable().with_priority(200))
+        .with_channel_config(1, ChannelConfig::unreliable().with_priority(10));
+    let mut conn = connected_connection(config, &clock);
+    for i in 0..20u8 {
+        conn.send(0, &[i; 100], false).unwrap();
+        conn.send(1, &[i; 100], false).unwrap();
+    }
+    conn.update_tick().unwrap();
+    send_queued(&mut conn);
+
+    let stats = conn.channel_stats();
+    assert!(stats[1].send_buffer_size < 20);
+    assert_eq!(stats[0].send_buffer_size, 20);
+}
@@ -0,0 +1,370 @@
+//! In-process loopback transport for deterministic tests.
+//!
+//! A [`MemoryNetwork`] is a shared hub of datagram queues keyed by virtual
+//! address. Every [`MemoryTransport`] bound on the hub can exchange datagrams
+//! with any other, so a [`crate::server::NetServer`] and many
+//! [`crate::client::NetClient`]s can run the full protocol without touching
+//! the OS network stack.
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use super::Transport;
+use crate::socket::SocketError;
+use crate::stats::SocketStats;
+
+/// First port handed out when binding to port 0.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Maximum datagrams queued per endpoint before new arrivals are dropped,
+/// mirroring a full OS receive buffer.
+pub const DEFAULT_MAX_QUEUED_DATAGRAMS: usize = 4096;
+
+/// Datagrams waiting for one bound endpoint.
+#[derive(Debug)]
+struct Inbox {
+    datagrams: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// Queue limit of the handle the endpoint was bound on.
+    max_queued: usize,
+}
+
+#[derive(Debug, Default)]
+struct Hub {
+    queues: HashMap<SocketAddr, Inbox>,
+    next_port: u16,
+    dropped: u64,
+}
+
+impl Hub {
+    fn allocate_port(&mut self, ip: IpAddr) -> Option<u16> {
+        let range = (u16::MAX - EPHEMERAL_PORT_START) as usize + 1;
+        for _ in 0..range {
+            if self.next_port < EPHEMERAL_PORT_START {
+                self.next_port = EPHEMERAL_PORT_START;
+            }
+            let port = self.next_port;
+            self.next_port = self.next_port.wrapping_add(1);
+            if !self.queues.contains_key(&SocketAddr::new(ip, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+/// Shared in-memory datagram hub. Cloning yields another handle to the same hub.
+#[derive(Debug, Clone)]
+pub struct MemoryNetwork {
+    hub: Arc<Mutex<Hub>>,
+    max_queued: usize,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self {
+            hub: Arc::new(Mutex::new(Hub::default())),
+            max_queued: DEFAULT_MAX_QUEUED_DATAGRAMS,
+        }
+    }
+
+    /// Sets the receive queue limit of transports bound through this handle
+    /// after this call. The limit belongs to the receiving endpoint, whoever
+    /// sends to it.
+    pub fn with_max_queued(mut self, max: usize) -> Self {
+        self.max_queued = max;
+        self
+    }
+
+    /// Bind a transport to a virtual address. An unspecified IP binds to the
+    /// loopback address of its family and port 0 allocates a free ephemeral port.
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemoryTransport, SocketError> {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        let mut hub = self.lock();
+        let port = if addr.port() == 0 {
+            hub.allocate_port(ip).ok_or_else(|| {
+                SocketError::Io(IoError::new(
+                    ErrorKind::AddrNotAvailable,
+                    "no free virtual ports",
+                ))
+            })?
+        } else {
+            addr.port()
+        };
+
+        let local_addr = SocketAddr::new(ip, port);
+        if hub.queues.contains_key(&local_addr) {
+            return Err(SocketError::Io(IoError::new(
+                ErrorKind::AddrInUse,
+                format!("virtual address {} already bound", local_addr),
+            )));
+        }
+        hub.queues.insert(
+            local_addr,
+            Inbox {
+                datagrams: VecDeque::new(),
+                max_queued: self.max_queued,
+            },
+        );
+
+        Ok(MemoryTransport {
+            network: self.clone(),
+            local_addr,
+            recv_buffer: Vec::new(),
+            stats: SocketStats::default(),
+        })
+    }
+
+    /// Number of datagrams currently queued for `addr`.
+    pub fn queued(&self, addr: SocketAddr) -> usize {
+        self.lock()
+            .queues
+            .get(&addr)
+            .map_or(0, |inbox| inbox.datagrams.len())
+    }
+
+    /// Total datagrams dropped because the destination was unbound or its queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Hub> {
+        self.hub
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Create a fresh hub with two bound endpoints.
+pub fn pair() -> (MemoryTransport, MemoryTransport) {
+    let network = MemoryNetwork::new();
+    let any: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let a = network.bind(any).expect("fresh hub has free ports");
+    let b = network.bind(any).expect("fresh hub has free ports");
+    (a, b)
+}
+
+/// A [`Transport`] endpoint bound on a [`MemoryNetwork`].
+///
+/// Dropping the transport unbinds its address; datagrams sent to it afterwards
+/// are silently discarded, as with UDP.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    local_addr: SocketAddr,
+    recv_buffer: Vec<u8>,
+    stats: SocketStats,
+}
+
+impl MemoryTransport {
+    /// Returns a handle to the hub this transport is bound on.
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
+        {
+            let mut hub = self.network.lock();
+            match hub.queues.get_mut(&addr) {
+                Some(inbox) if inbox.datagrams.len() < inbox.max_queued => {
+                    inbox.datagrams.push_back((data.to_vec(), self.local_addr));
+                }
+                _ => hub.dropped += 1,
+            }
+        }
+        self.stats.bytes_sent += data.len() as u64;
+        self.stats.packets_sent += 1;
+        self.stats.last_send_time = Some(Instant::now());
+        Ok(data.len())
+    }
+
+    fn recv_from(&mut self) -> Result<(&[u8], SocketAddr), SocketError> {
+        let next = self
+            .network
+            .lock()
+            .queues
+            .get_mut(&self.local_addr)
+            .and_then(|inbox| inbox.datagrams.pop_front());
+        match next {
+            Some((data, from)) => {
+                self.recv_buffer = data;
+                self.stats.bytes_received += self.recv_buffer.len() as u64;
+                self.stats.packets_received += 1;
+                self.stats.last_receive_time = Some(Instant::now());
+                Ok((&self.recv_buffer, from))
+            }
+            None => Err(SocketError::WouldBlock),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, SocketError> {
+        Ok(self.local_addr)
+    }
+
+    fn stats(&self) -> &SocketStats {
+        &self.stats
+    }
+
+    fn recv_batch(
+        &mut self,
+        out: &mut Vec<(Vec<u8>, SocketAddr)>,
+        max: usize,
+    ) -> Result<usize, SocketError> {
+        let mut hub = self.network.lock();
+        let Some(inbox) = hub.queues.get_mut(&self.local_addr) else {
+            return Ok(0);
+        };
+        let count = inbox.datagrams.len().min(max);
+        for (data, from) in inbox.datagrams.drain(..count) {
+            self.stats.bytes_received += data.len() as u64;
+            self.stats.packets_received += 1;
+            out.push((data, from));
+        }
+        if count > 0 {
+            self.stats.last_receive_time = Some(Instant::now());
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.lock().queues.remove(&self.local_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn any_addr() -> SocketAddr {
+        "0.0.0.0:0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_memory_pair_roundtrip() {
+        let (mut a, mut b) = pair();
+        let b_addr = b.local_addr().unwrap();
+        let a_addr = a.local_addr().unwrap();
+
+        a.send_to(b"ping", b_addr).unwrap();
+        let (data, from) = b.recv_from().unwrap();
+        assert_eq!(data, b"ping");
+        assert_eq!(from, a_addr);
+        assert!(matches!(b.recv_from(), Err(SocketError::WouldBlock)));
+
+        assert_eq!(a.stats().packets_sent, 1);
+        assert_eq!(b.stats().bytes_received, 4);
+    }
+
+    #[test]
+    fn test_memory_preserves_order() {
+        let (mut a, mut b) = pair();
+        let b_addr = b.local_addr().unwrap();
+        for i in 0u8..10 {
+            a.send_to(&[i], b_addr).unwrap();
+        }
+        for i in 0u8..10 {
+            assert_eq!(b.recv_from().unwrap().0, &[i]);
+        }
+    }
+
+    #[test]
+    fn test_memory_bind_allocates_distinct_ports() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(any_addr()).unwrap();
+        let b = network.bind(any_addr()).unwrap();
+        assert_ne!(a.local_addr().unwrap(), b.local_addr().unwrap());
+        assert!(a.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn test_memory_bind_ipv6_unspecified() {
+        let network = MemoryNetwork::new();
+        let a = network.bind("[::]:0".parse().unwrap()).unwrap();
+        assert_eq!(
+            a.local_addr().unwrap().ip(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn test_memory_address_in_use() {
+        let network = MemoryNetwork::new();
+        let addr: SocketAddr = "10.0.0.1:7777".parse().unwrap();
+        let first = network.bind(addr).unwrap();
+        assert!(network.bind(addr).is_err());
+
+        drop(first);
+        assert!(network.bind(addr).is_ok());
+    }
+
+    #[test]
+    fn test_memory_unbound_destination_dropped() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(any_addr()).unwrap();
+        let nowhere: SocketAddr = "10.9.9.9:9".parse().unwrap();
+        assert_eq!(a.send_to(b"lost", nowhere).unwrap(), 4);
+        assert_eq!(network.dropped(), 1);
+    }
+
+    #[test]
+    fn test_memory_recv_batch() {
+        let (mut a, mut b) = pair();
+        let b_addr = b.local_addr().unwrap();
+        for i in 0u8..5 {
+            a.send_to(&[i], b_addr).unwrap();
+        }
+
+        let mut out = Vec::new();
+        assert_eq!(b.recv_batch(&mut out, 3).unwrap(), 3);
+        assert_eq!(b.recv_batch(&mut out, 3).unwrap(), 2);
+        assert_eq!(b.recv_batch(&mut out, 3).unwrap(), 0);
+        let bytes: Vec<u8> = out.iter().map(|(d, _)| d[0]).collect();
+        assert_eq!(bytes, [0, 1, 2, 3, 4]);
+        assert_eq!(b.stats().packets_received, 5);
+    }
+
+    #[test]
+    fn test_memory_queue_limit() {
+        let network = MemoryNetwork::new().with_max_queued(2);
+        let mut a = network.bind(any_addr()).unwrap();
+        let b = network.bind(any_addr()).unwrap();
+        let b_addr = b.local_addr().unwrap();
+
+        for _ in 0..5 {
+            a.send_to(b"x", b_addr).unwrap();
+        }
+        assert_eq!(network.queued(b_addr), 2);
+        assert_eq!(network.dropped(), 3);
+    }
+
+    #[test]
+    fn test_memory_queue_limit_belongs_to_receiver() {
+        let network = MemoryNetwork::new();
+        let limited = network.clone().with_max_queued(2);
+        let mut sender = network.bind(any_addr()).unwrap();
+        let receiver = limited.bind(any_addr()).unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        for _ in 0..5 {
+            sender.send_to(b"x", receiver_addr).unwrap();
+        }
+        assert_eq!(network.queued(receiver_addr), 2);
+        assert_eq!(network.dropped(), 3);
+    }
+}
@@ -0,0 +1,101 @@
+//! Pluggable datagram transports.
+//!
+//! [`Transport`] abstracts the non-blocking datagram I/O that
+//! [`crate::server::NetServer`] and [`crate::client::NetClient`] run on top of.
+//! [`crate::socket::UdpSocket`] is the default implementation; custom
+//! transports enable relays, socket sharing, and in-memory testing
+//! (see [`memory`]).
+use std::net::SocketAddr;
+
+pub mod memory;
+
+use crate::socket::SocketError;
+use crate::stats::SocketStats;
+
+/// Default number of datagrams moved per batched receive or send.
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// A non-blocking, unreliable datagram transport.
+///
+/// Implementations must never block: `recv_from` returns
+/// [`SocketError::WouldBlock`] when no datagram is queued.
+///
+/// The batch methods default to looping the single-datagram calls;
+/// implementations override them when the platform can move many datagrams
+/// per system call.
+pub trait Transport {
+    /// Sends a single datagram to `addr`, returning the number of bytes sent.
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, SocketError>;
+
+    /// Receives the next queued datagram and the address it came from.
+    fn recv_from(&mut self) -> Result<(&[u8], SocketAddr), SocketError>;
+
+    /// Returns the local address this transport is reachable at.
+    fn local_addr(&self) -> Result<SocketAddr, SocketError>;
+
+    /// Returns transport-level I/O counters.
+    fn stats(&self) -> &SocketStats;
+
+    /// Receives up to `max` queued datagrams, appending them to `out`.
+    /// Returns the number received; `Ok(0)` means nothing was queued.
+    fn recv_batch(
+        &mut self,
+        out: &mut Vec<(Vec<u8>, SocketAddr)>,
+        max: usize,
+    ) -> Result<usize, SocketError> {
+        let mut count = 0;
+        while count < max {
+            match self.recv_from() {
+                Ok((data, addr)) => {
+                    out.push((data.to_vec(), addr));
+                    count += 1;
+                }
+                Err(SocketError::WouldBlock) => break,
+                Err(e) if count == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(count)
+    }
+
+    /// Sends datagrams in order, returning how many were sent before the
+    /// first failure. Returns an error only if the first datagram fails.
+    fn send_batch(&mut self, datagrams: &[(Vec<u8>, SocketAddr)]) -> Result<usize, SocketError> {
+        for (i, (data, addr)) in datagrams.iter().enumerate() {
+            if let Err(e) = self.send_to(data, *addr) {
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, SocketError> {
+        (**self).send_to(data, addr)
+    }
+
+    fn recv_from(&mut self) -> Result<(&[u8], SocketAddr), SocketError> {
+        (**self).recv_from()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, SocketError> {
+        (**self).local_addr()
+    }
+
+    fn stats(&self) -> &SocketStats {
+        (**self).stats()
+    }
+
+    fn recv_batch(
+        &mut self,
+        out: &mut Vec<(Vec<u8>, SocketAddr)>,
+        max: usize,
+    ) -> Result<usize, SocketError> {
+        (**self).recv_batch(out, max)
+    }
+
+    fn send_batch(&mut self, datagrams: &[(Vec<u8>, SocketAddr)]) -> Result<usize, SocketError> {
+        (**self).send_batch(datagrams)
+    }
+}
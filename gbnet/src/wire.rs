@@ -1,31 +1,189 @@
 //! Shared packet sending utilities used by both [`crate::server::NetServer`] and [`crate::client::NetClient`].
 use std::net::SocketAddr;
 
-use crate::packet::{Packet, PacketHeader, PacketType};
+use crate::clock::SharedClock;
+use crate::config::NetworkConfig;
+use crate::packet::{Packet, PacketHeader, PacketType, MIN_HANDSHAKE_REQUEST_SIZE};
 use crate::security;
-use crate::socket::UdpSocket;
+use crate::simulator::NetworkSimulator;
+use crate::socket::SocketError;
+use crate::transport::{Transport, DEFAULT_BATCH_SIZE};
 
-/// Serialize and send a control packet with CRC32 appended.
-/// Used by both NetServer and NetClient for handshake/control packets.
-pub fn send_raw_packet(
-    socket: &mut UdpSocket,
-    addr: SocketAddr,
+/// Serialize a control packet and append its CRC32.
+pub fn encode_raw_packet(
     protocol_id: u32,
     sequence: u16,
     packet_type: PacketType,
-) {
+) -> Option<Vec<u8>> {
+    encode_raw_packet_with_payload(protocol_id, sequence, packet_type, Vec::new())
+}
+
+/// Serialize a control packet carrying `payload`, e.g. a connect token on a
+/// connection request, and append its CRC32.
+pub fn encode_raw_packet_with_payload(
+    protocol_id: u32,
+    sequence: u16,
+    packet_type: PacketType,
+    payload: Vec<u8>,
+) -> Option<Vec<u8>> {
     let header = PacketHeader {
         protocol_id,
         sequence,
         ack: 0,
         ack_bits: 0,
     };
-    let packet = Packet::new(header, packet_type);
-    if let Ok(data) = packet.serialize() {
-        let mut data_with_crc = data;
-        security::append_crc32(&mut data_with_crc);
-        if let Err(e) = socket.send_to(&data_with_crc, addr) {
+    encode_packet(&Packet::new(header, packet_type).with_payload(payload))
+}
+
+/// Serialize a packet and append its CRC32.
+pub fn encode_packet(packet: &Packet) -> Option<Vec<u8>> {
+    let mut data = packet.serialize().ok()?;
+    security::append_crc32(&mut data);
+    Some(data)
+}
+
+/// Serialize and send a control packet with CRC32 appended.
+/// Used by both NetServer and NetClient for handshake/control packets.
+pub fn send_raw_packet<T: Transport + ?Sized>(
+    transport: &mut T,
+    addr: SocketAddr,
+    protocol_id: u32,
+    sequence: u16,
+    packet_type: PacketType,
+) {
+    if let Some(data) = encode_raw_packet(protocol_id, sequence, packet_type) {
+        if let Err(e) = transport.send_to(&data, addr) {
             log::warn!("Failed to send raw packet to {}: {:?}", addr, e);
         }
     }
 }
+
+/// The connection request a client built with `config` sends.
+pub(crate) fn connection_request(config: &NetworkConfig) -> PacketType {
+    PacketType::ConnectionRequest {
+        protocol_version: crate::packet::PROTOCOL_VERSION,
+        app_version: config.app_version,
+    }
+}
+
+/// Frame `payload` for a client handshake packet of type `packet_type`: a
+/// little-endian `u16` length, the payload, then zeros until the datagram
+/// reaches [`MIN_HANDSHAKE_REQUEST_SIZE`].
+pub(crate) fn pad_handshake_payload(packet_type: &PacketType, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(MIN_HANDSHAKE_REQUEST_SIZE);
+    framed.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    framed.extend_from_slice(payload);
+    let encoded_len = encode_raw_packet_with_payload(0, 0, packet_type.clone(), framed.clone())
+        .map_or(0, |data| data.len());
+    if encoded_len < MIN_HANDSHAKE_REQUEST_SIZE {
+        framed.resize(framed.len() + MIN_HANDSHAKE_REQUEST_SIZE - encoded_len, 0);
+    }
+    framed
+}
+
+/// The payload framed by [`pad_handshake_payload`], or `None` if the frame
+/// is malformed.
+pub(crate) fn unpad_handshake_payload(framed: &[u8]) -> Option<&[u8]> {
+    let len = u16::from_le_bytes(framed.get(..2)?.try_into().ok()?) as usize;
+    framed.get(2..2 + len)
+}
+
+/// Datagram pipeline between an endpoint and its [`Transport`].
+///
+/// Applies [`NetworkConfig::simulation`] and
+/// [`NetworkConfig::incoming_simulation`], and batches I/O: outgoing datagrams
+/// are queued by [`DatagramLink::send`] and written together by
+/// [`DatagramLink::flush`], so transports that support it move many datagrams
+/// per system call.
+#[derive(Debug, Default)]
+pub(crate) struct DatagramLink {
+    outgoing: Option<NetworkSimulator>,
+    incoming: Option<NetworkSimulator>,
+    outbox: Vec<(Vec<u8>, SocketAddr)>,
+}
+
+impl DatagramLink {
+    pub(crate) fn from_config(config: &NetworkConfig) -> Self {
+        Self {
+            outgoing: config.simulation.clone().map(NetworkSimulator::new),
+            incoming: config
+                .incoming_simulation
+                .clone()
+                .map(NetworkSimulator::new),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: SharedClock) {
+        self.outgoing = self
+            .outgoing
+            .take()
+            .map(|sim| sim.with_clock(clock.clone()));
+        self.incoming = self.incoming.take().map(|sim| sim.with_clock(clock));
+    }
+
+    /// Queue a datagram, possibly dropping, delaying, or duplicating it.
+    pub(crate) fn send(&mut self, data: Vec<u8>, addr: SocketAddr) {
+        match self.outgoing.as_mut() {
+            Some(sim) => self.outbox.extend(sim.process_send(&data, addr)),
+            None => self.outbox.push((data, addr)),
+        }
+    }
+
+    /// Write queued datagrams and any whose simulated delay has elapsed.
+    /// Returns the datagrams that failed to send, with their destinations.
+    pub(crate) fn flush<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+    ) -> Vec<(Vec<u8>, SocketAddr)> {
+        if let Some(sim) = self.outgoing.as_mut() {
+            self.outbox.extend(sim.receive_ready());
+        }
+
+        let mut failed = Vec::new();
+        let mut start = 0;
+        while start < self.outbox.len() {
+            match transport.send_batch(&self.outbox[start..]) {
+                Ok(sent) if sent > 0 => start += sent,
+                result => {
+                    let addr = self.outbox[start].1;
+                    let data = std::mem::take(&mut self.outbox[start].0);
+                    log::warn!("Failed to send to {}: {:?}", addr, result.err());
+                    failed.push((data, addr));
+                    start += 1;
+                }
+            }
+        }
+        self.outbox.clear();
+        failed
+    }
+
+    /// Drain the transport and return datagrams ready for processing.
+    pub(crate) fn receive<T: Transport + ?Sized>(
+        &mut self,
+        transport: &mut T,
+    ) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut received = Vec::new();
+        loop {
+            match transport.recv_batch(&mut received, DEFAULT_BATCH_SIZE) {
+                Ok(0) | Err(SocketError::WouldBlock) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("Receive failed: {:?}", e);
+                    break;
+                }
+            }
+        }
+        match self.incoming.as_mut() {
+            Some(sim) => {
+                let mut ready = Vec::with_capacity(received.len());
+                for (data, addr) in received {
+                    ready.extend(sim.process_send(&data, addr));
+                }
+                ready.extend(sim.receive_ready());
+                ready
+            }
+            None => received,
+        }
+    }
+}
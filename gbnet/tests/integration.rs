@@ -1,12 +1,14 @@
 use gbnet::{
     BitBuffer, BitDeserialize, BitSerialize, Channel, ChannelConfig, ClientEvent, Connection,
-    FragmentAssembler, NetClient, NetServer, NetworkConfig, NetworkSimulator, Packet, PacketHeader,
-    PacketType, ServerEvent, SimulationConfig, UdpSocket,
+    DisconnectReason, FragmentAssembler, ManualClock, NetClient, NetServer, NetworkConfig,
+    NetworkSimulator, Packet, PacketHeader, PacketType, ServerEvent, SimulationConfig, Transport,
+    UdpSocket, VersionPolicy,
 };
 
+use gbnet::transport::memory::{MemoryNetwork, MemoryTransport};
 use gbnet::NetworkSerialize;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::thread;
 use std::time::Duration;
 
@@ -159,7 +161,7 @@ fn test_client_server_handshake() {
         }
 
         for event in &server_events {
-            if let ServerEvent::ClientConnected(_addr) = event {
+            if let ServerEvent::ClientConnected { .. } = event {
                 assert_eq!(server.client_count(), 1);
             }
         }
@@ -175,6 +177,33 @@ fn test_client_server_handshake() {
     assert_eq!(server.client_count(), 1);
 }
 
+#[test]
+fn test_client_server_with_custom_transport() {
+    let config = NetworkConfig::default();
+    let any_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+    let server_transport: Box<dyn Transport> = Box::new(UdpSocket::bind(any_addr).unwrap());
+    let mut server = NetServer::with_transport(server_transport, config.clone()).unwrap();
+    let actual_server_addr = server.local_addr().unwrap();
+
+    let client_socket = UdpSocket::bind(any_addr).unwrap();
+    let mut client = NetClient::with_transport(client_socket, actual_server_addr, config).unwrap();
+
+    for _ in 0..20 {
+        server.update();
+        client.update();
+        if client.is_connected() && server.client_count() == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(client.is_connected());
+    assert_eq!(server.client_count(), 1);
+    assert!(server.transport().stats().packets_received > 0);
+    assert!(client.transport().stats().packets_sent > 0);
+}
+
 #[test]
 fn test_client_server_message_exchange() {
     let config = NetworkConfig::default();
@@ -442,6 +471,8 @@ fn test_network_simulator_loss_and_delivery() {
         duplicate_chance: 0.0,
         out_of_order_chance: 0.0,
         bandwidth_limit_bytes_per_sec: 0,
+        seed: None,
+        script: Vec::new(),
     };
     let mut sim = NetworkSimulator::new(config);
     let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
@@ -464,3 +495,1750 @@ fn test_fragment_too_many_fragments_error() {
     let result = gbnet::fragment::fragment_message(0, &big_data, 100);
     assert!(result.is_err(), "Should error on too many fragments");
 }
+
+fn memory_server(network: &MemoryNetwork, config: NetworkConfig) -> NetServer<MemoryTransport> {
+    let transport = network.bind("10.0.0.1:7777".parse().unwrap()).unwrap();
+    NetServer::with_transport(transport, config).unwrap()
+}
+
+/// Binds each client on its own virtual host so per-IP rate limiting does not
+/// conflate them.
+fn memory_client(
+    network: &MemoryNetwork,
+    host: u8,
+    server_addr: SocketAddr,
+    config: NetworkConfig,
+) -> NetClient<MemoryTransport> {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 1, host)), 0);
+    let transport = network.bind(addr).unwrap();
+    NetClient::with_transport(transport, server_addr, config).unwrap()
+}
+
+/// Connects one client to a server over a memory network, driving both from
+/// `clock` when given.
+fn connected_pair(
+    network: &MemoryNetwork,
+    config: NetworkConfig,
+    clock: Option<&ManualClock>,
+) -> (NetServer<MemoryTransport>, NetClient<MemoryTransport>) {
+    let mut server = memory_server(network, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(network, 1, server_addr, config);
+    if let Some(clock) = clock {
+        server = server.with_clock(clock.shared());
+        client = client.with_clock(clock.shared());
+    }
+    complete_handshake(&mut server, &mut client);
+    (server, client)
+}
+
+/// Drives `server` and `client` until the client's handshake completes.
+fn complete_handshake(
+    server: &mut NetServer<MemoryTransport>,
+    client: &mut NetClient<MemoryTransport>,
+) {
+    for _ in 0..10 {
+        server.update();
+        client.update();
+    }
+    assert!(client.is_connected());
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn test_memory_transport_handshake_and_messages() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default();
+    let mut server = memory_server(&network, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, config);
+
+    let mut connected_events = 0;
+    for _ in 0..10 {
+        for event in server.update() {
+            if matches!(event, ServerEvent::ClientConnected { .. }) {
+                connected_events += 1;
+            }
+        }
+        client.update();
+    }
+    assert!(client.is_connected());
+    assert_eq!(server.client_count(), 1);
+    assert_eq!(connected_events, 1);
+
+    client.send(0, b"hello server").unwrap();
+    client.update();
+    let received: Vec<_> = server
+        .update()
+        .into_iter()
+        .filter_map(|e| match e {
+            ServerEvent::Message { addr, data, .. } => Some((addr, data)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received.len(), 1);
+    let (client_addr, data) = &received[0];
+    assert_eq!(data, b"hello server");
+
+    server.send(*client_addr, 0, b"hello client").unwrap();
+    server.update();
+    let got = client
+        .update()
+        .into_iter()
+        .any(|e| matches!(e, ClientEvent::Message { data, .. } if data == b"hello client"));
+    assert!(got, "Client should receive the reply");
+}
+
+#[test]
+fn test_memory_transport_many_clients_and_disconnect() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default();
+    let mut server = memory_server(&network, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    let mut clients: Vec<_> = (1..=8)
+        .map(|host| memory_client(&network, host, server_addr, config.clone()))
+        .collect();
+
+    for _ in 0..10 {
+        server.update();
+        for client in &mut clients {
+            client.update();
+        }
+    }
+    assert!(clients.iter().all(|c| c.is_connected()));
+    assert_eq!(server.client_count(), 8);
+
+    server.broadcast(0, b"tick", None);
+    server.update();
+    for client in &mut clients {
+        let got = client
+            .update()
+            .into_iter()
+            .any(|e| matches!(e, ClientEvent::Message { .. }));
+        assert!(got, "Every client should receive the broadcast");
+    }
+
+    clients[0].disconnect();
+    let events = server.update();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(_, _))));
+    assert_eq!(server.client_count(), 7);
+
+    let events = clients[0].update();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(_))));
+    assert!(!clients[0].is_connected());
+}
+
+#[test]
+fn test_simulation_total_loss_blocks_handshake() {
+    let network = MemoryNetwork::new();
+    let server = memory_server(&network, NetworkConfig::default());
+    let server_addr = server.local_addr().unwrap();
+    let lossy = NetworkConfig::default().with_simulation(SimulationConfig {
+        packet_loss: 1.0,
+        ..Default::default()
+    });
+    let mut client = memory_client(&network, 1, server_addr, lossy);
+
+    for _ in 0..5 {
+        client.update();
+    }
+    assert!(!client.is_connected());
+    assert_eq!(server.transport().stats().packets_received, 0);
+    assert_eq!(network.queued(server_addr), 0);
+}
+
+#[test]
+fn test_simulation_latency_delays_datagrams() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default());
+    let server_addr = server.local_addr().unwrap();
+    let laggy = NetworkConfig::default().with_simulation(SimulationConfig {
+        latency_ms: 30,
+        ..Default::default()
+    });
+    let mut client = memory_client(&network, 1, server_addr, laggy);
+
+    client.update();
+    assert_eq!(
+        network.queued(server_addr),
+        0,
+        "Request should be held back by simulated latency"
+    );
+
+    thread::sleep(Duration::from_millis(40));
+    client.update();
+    assert_eq!(network.queued(server_addr), 1);
+
+    for _ in 0..20 {
+        server.update();
+        client.update();
+        if client.is_connected() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(35));
+    }
+    assert!(client.is_connected());
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn test_incoming_simulation_duplicates_delivered_once() {
+    let network = MemoryNetwork::new();
+    let dup = SimulationConfig {
+        duplicate_chance: 1.0,
+        ..Default::default()
+    };
+    let (mut server, mut client) = connected_pair(
+        &network,
+        NetworkConfig::default().with_incoming_simulation(dup),
+        None,
+    );
+
+    client.send(0, b"once").unwrap();
+    client.update();
+    let messages = server
+        .update()
+        .into_iter()
+        .filter(|e| matches!(e, ServerEvent::Message { .. }))
+        .count();
+    assert_eq!(messages, 1, "Duplicated datagram must be delivered once");
+}
+
+#[test]
+fn test_manual_clock_drives_server_and_client_timeouts() {
+    let clock = ManualClock::new();
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default();
+    let timeout = config.connection_timeout;
+    let (mut server, mut client) = connected_pair(&network, config, Some(&clock));
+
+    // The link goes dead: anything in flight is lost while time jumps forward.
+    while server.transport_mut().recv_from().is_ok() {}
+    while client.transport_mut().recv_from().is_ok() {}
+    clock.advance(timeout + Duration::from_millis(1));
+
+    let server_events = server.update();
+    assert!(server_events.iter().any(|e| matches!(
+        e,
+        ServerEvent::ClientDisconnected(_, gbnet::DisconnectReason::Timeout)
+    )));
+    let client_events = client.update();
+    assert!(client_events.iter().any(|e| matches!(
+        e,
+        ClientEvent::Disconnected(gbnet::DisconnectReason::Timeout)
+    )));
+}
+
+/// Pumps server and client updates until the client connects or `attempts` runs out.
+fn connect_over_udp(server: &mut NetServer, client: &mut NetClient, attempts: usize) {
+    for _ in 0..attempts {
+        server.update();
+        client.update();
+        if client.is_connected() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_client_server_over_ipv6() {
+    let config = NetworkConfig::default();
+    let server_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0);
+    let mut server = NetServer::bind(server_addr, config.clone()).unwrap();
+    let actual_server_addr = server.local_addr().unwrap();
+
+    let mut client = NetClient::connect(actual_server_addr, config).unwrap();
+    assert!(client.transport().local_addr().unwrap().is_ipv6());
+
+    connect_over_udp(&mut server, &mut client, 50);
+    assert!(client.is_connected());
+    assert_eq!(server.client_count(), 1);
+
+    client.send(0, b"hello over v6").unwrap();
+    client.update();
+    let mut received = None;
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(10));
+        received = server.update().into_iter().find_map(|e| match e {
+            ServerEvent::Message { addr, data, .. } => Some((addr, data)),
+            _ => None,
+        });
+        if received.is_some() {
+            break;
+        }
+    }
+    let (client_addr, data) = received.expect("server should receive the message");
+    assert!(client_addr.is_ipv6());
+    assert_eq!(data, b"hello over v6");
+}
+
+#[test]
+fn test_dual_stack_server_accepts_both_families() {
+    let config = NetworkConfig::default();
+    let mut server = NetServer::bind_dual_stack(0, config.clone()).unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    let v4_server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let v6_server = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+    let mut v4_client = NetClient::connect(v4_server, config.clone()).unwrap();
+    let mut v6_client = NetClient::connect(v6_server, config).unwrap();
+
+    let mut connected = Vec::new();
+    for _ in 0..50 {
+        for event in server.update() {
+            if let ServerEvent::ClientConnected { addr, .. } = event {
+                connected.push(addr);
+            }
+        }
+        v4_client.update();
+        v6_client.update();
+        if v4_client.is_connected() && v6_client.is_connected() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(v4_client.is_connected());
+    assert!(v6_client.is_connected());
+    assert_eq!(server.client_count(), 2);
+    // IPv4 clients are reported with plain, not IPv4-mapped, addresses.
+    assert!(connected.iter().any(|a| a.is_ipv4()));
+    assert!(connected.iter().any(|a| a.is_ipv6()));
+}
+
+#[test]
+fn test_client_uses_configured_local_bind_addr() {
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let mut server = NetServer::bind(server_addr, NetworkConfig::default()).unwrap();
+    let actual_server_addr = server.local_addr().unwrap();
+
+    let config = NetworkConfig::default()
+        .with_local_bind_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+    let mut client = NetClient::connect(actual_server_addr, config).unwrap();
+    let local = client.transport().local_addr().unwrap();
+    assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    connect_over_udp(&mut server, &mut client, 50);
+    assert!(client.is_connected());
+}
+
+#[test]
+fn test_client_rejects_bind_addr_of_other_family() {
+    let v4_server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9000);
+    let v6_server = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9000);
+
+    let v4_bind = NetworkConfig::default()
+        .with_local_bind_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+    assert!(NetClient::connect(v6_server, v4_bind).is_err());
+
+    let v6_bind = NetworkConfig::default()
+        .with_local_bind_addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0));
+    assert!(NetClient::connect(v4_server, v6_bind).is_err());
+}
+
+#[test]
+fn test_messages_on_high_channel_ids() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default().with_max_channels(256);
+    let (mut server, mut client) = connected_pair(&network, config, None);
+
+    client.send(200, b"channel two hundred").unwrap();
+    client.send(8, b"channel eight").unwrap();
+    client.update();
+    let mut received: Vec<(u8, Vec<u8>)> = server
+        .update()
+        .into_iter()
+        .filter_map(|e| match e {
+            ServerEvent::Message { channel, data, .. } => Some((channel, data)),
+            _ => None,
+        })
+        .collect();
+    received.sort();
+    assert_eq!(
+        received,
+        vec![
+            (8, b"channel eight".to_vec()),
+            (200, b"channel two hundred".to_vec())
+        ]
+    );
+
+    let client_addr = client.transport().local_addr().unwrap();
+    server.send(client_addr, 255, b"last channel").unwrap();
+    server.update();
+    let got = client.update().into_iter().any(
+        |e| matches!(e, ClientEvent::Message { channel: 255, data } if data == b"last channel"),
+    );
+    assert!(got, "client should receive on channel 255");
+}
+
+#[test]
+fn test_version_mismatch_denied_with_reason() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default().with_app_version(7));
+    let server_addr = server.local_addr().unwrap();
+    let mut outdated = memory_client(
+        &network,
+        1,
+        server_addr,
+        NetworkConfig::default().with_app_version(6),
+    );
+
+    let mut reason = None;
+    for _ in 0..5 {
+        server.update();
+        for event in outdated.update() {
+            if let ClientEvent::Disconnected(r) = event {
+                reason = Some(r);
+            }
+        }
+    }
+    assert_eq!(reason, Some(DisconnectReason::VersionMismatch));
+    assert!(!outdated.is_connected());
+    assert_eq!(server.client_count(), 0);
+}
+
+#[test]
+fn test_version_policy_accepts_newer_clients() {
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default()
+        .with_app_version(7)
+        .with_version_policy(VersionPolicy::AtLeast(7));
+    let mut server = memory_server(&network, server_config);
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(
+        &network,
+        1,
+        server_addr,
+        NetworkConfig::default().with_app_version(9),
+    );
+
+    complete_handshake(&mut server, &mut client);
+}
+
+#[test]
+fn test_manual_admission_accepts_and_denies_with_payload() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(
+        &network,
+        NetworkConfig::default().with_manual_admission(true),
+    );
+    let server_addr = server.local_addr().unwrap();
+    let mut admitted = memory_client(&network, 1, server_addr, NetworkConfig::default());
+    let mut refused = memory_client(&network, 2, server_addr, NetworkConfig::default());
+    let maintenance = gbnet::packet::deny_reason::FIRST_APPLICATION;
+
+    let (mut requests, mut connected, mut reason) = (0, Vec::new(), None);
+    for _ in 0..10 {
+        for event in server.update() {
+            match event {
+                ServerEvent::ConnectionRequest { addr, .. } => {
+                    requests += 1;
+                    if addr.ip() == IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)) {
+                        assert!(server.accept_connection(addr));
+                    } else {
+                        assert!(server.deny_connection(addr, maintenance, b"back at 18:00"));
+                    }
+                    assert!(!server.accept_connection(addr));
+                }
+                ServerEvent::ClientConnected { addr, .. } => connected.push(addr),
+                _ => {}
+            }
+        }
+        admitted.update();
+        for event in refused.update() {
+            if let ClientEvent::Disconnected(r) = event {
+                reason = Some(r);
+            }
+        }
+    }
+    assert_eq!(requests, 2);
+    assert!(admitted.is_connected());
+    assert_eq!(server.client_count(), 1);
+    assert_eq!(connected.len(), 1);
+    assert_eq!(reason, Some(DisconnectReason::Unknown(maintenance)));
+    assert_eq!(refused.deny_payload(), b"back at 18:00");
+}
+
+#[test]
+fn test_connect_data_and_accept_payload_delivered() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(
+        &network,
+        NetworkConfig::default().with_manual_admission(true),
+    );
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, NetworkConfig::default())
+        .with_connect_data(b"name=ana;build=1f2e")
+        .unwrap();
+
+    let (mut requested, mut connected) = (None, None);
+    for _ in 0..10 {
+        for event in server.update() {
+            match event {
+                ServerEvent::ConnectionRequest {
+                    addr, connect_data, ..
+                } => {
+                    requested = Some(connect_data);
+                    assert!(server.accept_connection_with_data(addr, &[7u8; 200]));
+                }
+                ServerEvent::ClientConnected { connect_data, .. } => connected = Some(connect_data),
+                _ => {}
+            }
+        }
+        client.update();
+    }
+    assert!(client.is_connected());
+    assert_eq!(requested.as_deref(), Some(&b"name=ana;build=1f2e"[..]));
+    assert_eq!(connected.as_deref(), Some(&b"name=ana;build=1f2e"[..]));
+    assert_eq!(
+        client.accept_payload(),
+        &[7u8; gbnet::packet::MAX_ACCEPT_PAYLOAD][..]
+    );
+}
+
+#[test]
+fn test_oversized_connect_data_rejected() {
+    let data = vec![0u8; gbnet::packet::MAX_CONNECT_DATA + 1];
+    assert!(NetClient::connect_with_data(
+        "127.0.0.1:9".parse().unwrap(),
+        NetworkConfig::default(),
+        &data
+    )
+    .is_err());
+
+    let network = MemoryNetwork::new();
+    let client = memory_client(
+        &network,
+        1,
+        "10.0.0.1:7777".parse().unwrap(),
+        NetworkConfig::default(),
+    );
+    assert!(client.with_connect_data(&data).is_err());
+}
+
+#[test]
+fn test_undecided_connection_request_expires() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default()
+        .with_manual_admission(true)
+        .with_connection_timeout(Duration::from_secs(2));
+    let mut server = memory_server(&network, config.clone()).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, config).with_clock(clock.shared());
+
+    let mut requested = None;
+    for _ in 0..5 {
+        for event in server.update() {
+            if let ServerEvent::ConnectionRequest { addr, .. } = event {
+                requested = Some(addr);
+            }
+        }
+        client.update();
+    }
+    let addr = requested.expect("server should surface the request");
+    clock.advance(Duration::from_secs(3));
+    server.update();
+    assert!(!server.accept_connection(addr));
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn test_banned_range_denied_and_connected_client_kicked() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default());
+    let server_addr = server.local_addr().unwrap();
+    let mut kept = memory_client(&network, 1, server_addr, NetworkConfig::default());
+    let mut kicked = memory_client(&network, 2, server_addr, NetworkConfig::default());
+    for _ in 0..10 {
+        server.update();
+        kept.update();
+        kicked.update();
+    }
+    assert_eq!(server.client_count(), 2);
+
+    let kicked_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2));
+    server.ban(kicked_ip, None);
+    assert!(server.is_blocked(kicked_ip));
+    let kick_reason = kicked.update().into_iter().find_map(|event| match event {
+        ClientEvent::Disconnected(reason) => Some(reason),
+        _ => None,
+    });
+    assert_eq!(kick_reason, Some(DisconnectReason::Banned));
+    assert_eq!(server.client_count(), 1);
+
+    server.ban("10.0.2.0/24".parse::<gbnet::IpRange>().unwrap(), None);
+    let mut unaffected = memory_client(&network, 3, server_addr, NetworkConfig::default());
+    let mut refused_in_range = {
+        let transport = network
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 9)), 0))
+            .unwrap();
+        NetClient::with_transport(transport, server_addr, NetworkConfig::default()).unwrap()
+    };
+    let mut reason = None;
+    for _ in 0..10 {
+        server.update();
+        kept.update();
+        unaffected.update();
+        for event in refused_in_range.update() {
+            if let ClientEvent::Disconnected(r) = event {
+                reason = Some(r);
+            }
+        }
+    }
+    assert_eq!(reason, Some(DisconnectReason::Banned));
+    assert!(unaffected.is_connected());
+    assert!(kept.is_connected());
+    assert_eq!(server.client_count(), 2);
+    assert!(server.server_stats().packets_blocked > 0);
+    assert!(server.server_stats().connection_requests_blocked > 0);
+
+    assert!(server.unban(kicked_ip));
+    assert!(!server.is_blocked(kicked_ip));
+}
+
+#[test]
+fn test_banned_range_flood_does_not_starve_allowed_clients() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_handshake_rate_limit(20);
+    let mut server = memory_server(&network, config.clone()).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    server.ban("10.0.2.0/24".parse::<gbnet::IpRange>().unwrap(), None);
+
+    let mut banned: Vec<_> = (1..=40)
+        .map(|host| {
+            let transport = network
+                .bind(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 2, host)),
+                    0,
+                ))
+                .unwrap();
+            NetClient::with_transport(transport, server_addr, config.clone())
+                .unwrap()
+                .with_clock(clock.shared())
+        })
+        .collect();
+    let mut allowed = memory_client(&network, 1, server_addr, config).with_clock(clock.shared());
+
+    for _ in 0..10 {
+        server.update();
+        allowed.update();
+        for client in &mut banned {
+            client.update();
+        }
+    }
+    assert!(allowed.is_connected());
+    assert_eq!(server.client_count(), 1);
+    assert_eq!(server.server_stats().connection_requests_blocked, 40);
+}
+
+#[test]
+fn test_allow_list_blocks_sources_outside_it() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default());
+    server.allow_range("10.0.1.1".parse::<gbnet::IpRange>().unwrap());
+    let server_addr = server.local_addr().unwrap();
+    let mut allowed = memory_client(&network, 1, server_addr, NetworkConfig::default());
+    let mut outsider = memory_client(&network, 2, server_addr, NetworkConfig::default());
+    for _ in 0..10 {
+        server.update();
+        allowed.update();
+        outsider.update();
+    }
+    assert!(allowed.is_connected());
+    assert!(!outsider.is_connected());
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn test_undersized_requests_do_not_spend_handshake_budget() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_handshake_rate_limit(5);
+    let mut server = memory_server(&network, config.clone()).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let bare = gbnet::wire::encode_raw_packet(
+        config.protocol_id,
+        0,
+        PacketType::ConnectionRequest {
+            protocol_version: gbnet::packet::PROTOCOL_VERSION,
+            app_version: 0,
+        },
+    )
+    .unwrap();
+    for host in 1..=20 {
+        let mut spoofer = network
+            .bind(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(10, 0, 3, host)),
+                0,
+            ))
+            .unwrap();
+        spoofer.send_to(&bare, server_addr).unwrap();
+    }
+
+    let mut client = memory_client(&network, 1, server_addr, config).with_clock(clock.shared());
+    complete_handshake(&mut server, &mut client);
+}
+
+#[test]
+fn test_subnet_rate_limit_bounds_rotating_hosts() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_subnet_rate_limit(6);
+    let mut server = memory_server(&network, config.clone()).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let mut same_subnet: Vec<_> = (1..=4)
+        .map(|host| {
+            memory_client(&network, host, server_addr, config.clone()).with_clock(clock.shared())
+        })
+        .collect();
+    let mut elsewhere = {
+        let transport = network
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 1)), 0))
+            .unwrap();
+        NetClient::with_transport(transport, server_addr, config)
+            .unwrap()
+            .with_clock(clock.shared())
+    };
+
+    for _ in 0..10 {
+        server.update();
+        elsewhere.update();
+        for client in &mut same_subnet {
+            client.update();
+        }
+    }
+    assert!(elsewhere.is_connected());
+    let connected = same_subnet.iter().filter(|c| c.is_connected()).count();
+    assert!(connected < 4, "{} hosts in one /24 connected", connected);
+
+    // Once the window passes, the refused hosts retry and get through.
+    clock.advance(Duration::from_secs(1));
+    for client in same_subnet.iter_mut().filter(|c| !c.is_connected()) {
+        client.reconnect();
+    }
+    for _ in 0..10 {
+        server.update();
+        for client in &mut same_subnet {
+            client.update();
+        }
+    }
+    assert!(same_subnet.iter().all(|c| c.is_connected()));
+}
+
+/// Connects one client under a manual clock and has it send `count`
+/// messages, one datagram per tick, without the server reading any of them.
+fn flood_session(
+    network: &MemoryNetwork,
+    clock: &ManualClock,
+    config: NetworkConfig,
+    count: u8,
+) -> (NetServer<MemoryTransport>, NetClient<MemoryTransport>) {
+    let (mut server, mut client) = connected_pair(network, config, Some(clock));
+    server.update();
+    for i in 0..count {
+        client.send(0, &[i; 16]).unwrap();
+        client.update();
+    }
+    (server, client)
+}
+
+fn message_count(events: &[ServerEvent]) -> usize {
+    events
+        .iter()
+        .filter(|e| matches!(e, ServerEvent::Message { .. }))
+        .count()
+}
+
+#[test]
+fn test_inbound_flood_dropped_over_packet_limit() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_inbound_limits(10, 0);
+    let (mut server, client) = flood_session(&network, &clock, config, 30);
+    let client_addr = client.transport().local_addr().unwrap();
+
+    let delivered = message_count(&server.update());
+    let stats = server.stats(client_addr).unwrap();
+    assert!(delivered <= 10, "delivered {} messages", delivered);
+    assert!(stats.flood_packets_dropped >= 20);
+    assert_eq!(stats.flood_packets_deferred, 0);
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn test_inbound_flood_throttled_until_budget_refills() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default()
+        .with_inbound_limits(10, 0)
+        .with_flood_policy(gbnet::FloodPolicy::Throttle);
+    let (mut server, client) = flood_session(&network, &clock, config, 30);
+    let client_addr = client.transport().local_addr().unwrap();
+
+    let mut delivered = message_count(&server.update());
+    assert!(delivered <= 10, "delivered {} messages", delivered);
+    assert!(server.stats(client_addr).unwrap().flood_packets_deferred >= 20);
+    for _ in 0..3 {
+        clock.advance(Duration::from_secs(1));
+        delivered += message_count(&server.update());
+    }
+    assert_eq!(delivered, 30);
+    assert_eq!(server.stats(client_addr).unwrap().flood_packets_dropped, 0);
+}
+
+#[test]
+fn test_inbound_flood_kicks_client() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default()
+        .with_inbound_limits(10, 0)
+        .with_flood_policy(gbnet::FloodPolicy::Kick);
+    let (mut server, mut client) = flood_session(&network, &clock, config, 30);
+
+    assert!(server.update().iter().any(|e| matches!(
+        e,
+        ServerEvent::ClientDisconnected(_, DisconnectReason::Flooding)
+    )));
+    assert_eq!(server.client_count(), 0);
+    assert!(client
+        .update()
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(DisconnectReason::Flooding))));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_spoofed_packets_cannot_kick_encrypted_client() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default()
+        .with_encryption()
+        .with_inbound_limits(10, 0)
+        .with_flood_policy(gbnet::FloodPolicy::Kick);
+    let (mut server, mut client) = connected_pair(&network, config, Some(&clock));
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.transport().local_addr().unwrap();
+
+    // Forged from the client's address with a valid CRC, but unsealed.
+    for sequence in 0..30u16 {
+        let forged = Packet::new(
+            PacketHeader {
+                protocol_id: NetworkConfig::default().protocol_id,
+                sequence,
+                ack: 0,
+                ack_bits: 0,
+            },
+            PacketType::Payload {
+                channel: 0,
+                is_fragment: false,
+            },
+        )
+        .with_payload(b"forged".to_vec());
+        let mut forged = forged.serialize().unwrap();
+        gbnet::security::append_crc32(&mut forged);
+        client
+            .transport_mut()
+            .send_to(&forged, server_addr)
+            .unwrap();
+    }
+
+    let events = server.update();
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+    assert_eq!(server.client_count(), 1);
+    let stats = server.stats(client_addr).unwrap();
+    assert_eq!(stats.packets_rejected, 30);
+    assert_eq!(stats.flood_packets_dropped, 0);
+
+    clock.advance(Duration::from_secs(1));
+    client.send(0, b"still here").unwrap();
+    client.update();
+    assert_eq!(message_count(&server.update()), 1);
+    assert_eq!(server.client_count(), 1);
+}
+
+#[test]
+fn test_messages_per_channel_capped_each_tick() {
+    let network = MemoryNetwork::new();
+    let clock = ManualClock::new();
+    let config = NetworkConfig::default().with_max_messages_per_channel_per_tick(5);
+    let (mut server, client) = flood_session(&network, &clock, config.clone(), 20);
+    let client_addr = client.transport().local_addr().unwrap();
+    assert_eq!(message_count(&server.update()), 5);
+    assert_eq!(
+        server.stats(client_addr).unwrap().flood_messages_dropped,
+        15
+    );
+
+    let throttled = config.with_flood_policy(gbnet::FloodPolicy::Throttle);
+    let network = MemoryNetwork::new();
+    let (mut server, _client) = flood_session(&network, &clock, throttled, 20);
+    let per_tick: Vec<usize> = (0..5).map(|_| message_count(&server.update())).collect();
+    assert_eq!(per_tick, vec![5, 5, 5, 5, 0]);
+}
+
+/// Wraps a transport, recording the size of every handshake request it
+/// receives and every handshake reply it sends.
+struct HandshakeRecorder<T> {
+    inner: T,
+    requests: Vec<usize>,
+    replies: Vec<usize>,
+}
+
+fn handshake_type(data: &[u8]) -> Option<PacketType> {
+    let body = data.get(..data.len().checked_sub(4)?)?;
+    Packet::deserialize(body)
+        .ok()
+        .map(|packet| packet.packet_type)
+        .filter(PacketType::is_handshake)
+}
+
+impl<T: Transport> Transport for HandshakeRecorder<T> {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize, gbnet::SocketError> {
+        if handshake_type(data).is_some_and(|t| !t.is_handshake_request()) {
+            self.replies.push(data.len());
+        }
+        self.inner.send_to(data, addr)
+    }
+
+    fn recv_from(&mut self) -> Result<(&[u8], SocketAddr), gbnet::SocketError> {
+        let (data, addr) = self.inner.recv_from()?;
+        if handshake_type(data).is_some_and(|t| t.is_handshake_request()) {
+            self.requests.push(data.len());
+        }
+        Ok((data, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, gbnet::SocketError> {
+        self.inner.local_addr()
+    }
+
+    fn stats(&self) -> &gbnet::SocketStats {
+        self.inner.stats()
+    }
+}
+
+/// Run a handshake against a recording server, denying held clients with an
+/// oversized payload, and return the smallest handshake request and largest
+/// handshake reply the server saw.
+fn handshake_sizes(
+    server_config: NetworkConfig,
+    client_config: NetworkConfig,
+    banned: bool,
+) -> (usize, usize) {
+    let network = MemoryNetwork::new();
+    let transport = HandshakeRecorder {
+        inner: network.bind("10.0.0.1:7777".parse().unwrap()).unwrap(),
+        requests: Vec::new(),
+        replies: Vec::new(),
+    };
+    let mut server = NetServer::with_transport(transport, server_config).unwrap();
+    if banned {
+        server.ban(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)), None);
+    }
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, client_config);
+    for _ in 0..10 {
+        for event in server.update() {
+            if let ServerEvent::ConnectionRequest { addr, .. } = event {
+                server.deny_connection(addr, 200, &[0xab; 300]);
+            }
+        }
+        client.update();
+    }
+    let recorder = server.transport();
+    assert!(
+        !recorder.replies.is_empty(),
+        "server sent no handshake reply"
+    );
+    (
+        *recorder.requests.iter().min().unwrap(),
+        *recorder.replies.iter().max().unwrap(),
+    )
+}
+
+#[test]
+fn test_handshake_replies_never_outweigh_requests() {
+    let no_cookie = NetworkConfig {
+        enable_stateless_cookie: false,
+        ..NetworkConfig::default()
+    };
+    let mut scenarios = vec![
+        (NetworkConfig::default(), NetworkConfig::default(), false),
+        (no_cookie.clone(), NetworkConfig::default(), false),
+        (
+            NetworkConfig::default().with_manual_admission(true),
+            NetworkConfig::default(),
+            false,
+        ),
+        (
+            NetworkConfig::default().with_app_version(2),
+            NetworkConfig::default().with_app_version(1),
+            false,
+        ),
+        (NetworkConfig::default(), NetworkConfig::default(), true),
+    ];
+    if cfg!(feature = "encryption") {
+        let identity = no_cookie
+            .with_encryption()
+            .with_server_identity_key([7u8; 32]);
+        scenarios.push((identity, NetworkConfig::default().with_encryption(), false));
+    }
+    for (server_config, client_config, banned) in scenarios {
+        let (smallest_request, largest_reply) =
+            handshake_sizes(server_config, client_config, banned);
+        assert!(
+            largest_reply <= smallest_request,
+            "{}-byte reply to a {}-byte request",
+            largest_reply,
+            smallest_request
+        );
+    }
+}
+
+#[test]
+fn test_undersized_connection_request_dropped() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default());
+    let server_addr = server.local_addr().unwrap();
+    let mut attacker = network.bind("10.0.1.1:4000".parse().unwrap()).unwrap();
+    let bare = gbnet::wire::encode_raw_packet(
+        NetworkConfig::default().protocol_id,
+        0,
+        PacketType::ConnectionRequest {
+            protocol_version: gbnet::packet::PROTOCOL_VERSION,
+            app_version: 0,
+        },
+    )
+    .unwrap();
+    assert!(bare.len() < gbnet::packet::MIN_HANDSHAKE_REQUEST_SIZE);
+    attacker.send_to(&bare, server_addr).unwrap();
+    server.update();
+    assert_eq!(network.queued(attacker.local_addr().unwrap()), 0);
+}
+
+#[test]
+fn test_undersized_connection_request_with_wrong_version_dropped() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default());
+    let server_addr = server.local_addr().unwrap();
+    let mut attacker = network.bind("10.0.1.1:4000".parse().unwrap()).unwrap();
+    let bare = gbnet::wire::encode_raw_packet(
+        NetworkConfig::default().protocol_id,
+        0,
+        PacketType::ConnectionRequest {
+            protocol_version: gbnet::packet::PROTOCOL_VERSION,
+            app_version: 0xdead,
+        },
+    )
+    .unwrap();
+    assert!(bare.len() < gbnet::packet::MIN_HANDSHAKE_REQUEST_SIZE);
+    attacker.send_to(&bare, server_addr).unwrap();
+    server.update();
+    assert_eq!(network.queued(attacker.local_addr().unwrap()), 0);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_servers_sharing_cookie_secret_accept_each_others_cookies() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default().with_cookie_secret([9u8; 32]);
+    let mut issuer = memory_server(&network, config.clone());
+    let peer_transport = network.bind("10.0.0.2:7777".parse().unwrap()).unwrap();
+    let mut peer = NetServer::with_transport(peer_transport, config.clone()).unwrap();
+    let mut client = network.bind("10.0.1.1:4000".parse().unwrap()).unwrap();
+    let padding = vec![0u8; gbnet::packet::MIN_HANDSHAKE_REQUEST_SIZE];
+
+    let request = gbnet::wire::encode_raw_packet_with_payload(
+        config.protocol_id,
+        0,
+        PacketType::ConnectionRequest {
+            protocol_version: gbnet::packet::PROTOCOL_VERSION,
+            app_version: 0,
+        },
+        padding.clone(),
+    )
+    .unwrap();
+    client
+        .send_to(&request, issuer.local_addr().unwrap())
+        .unwrap();
+    issuer.update();
+    let (data, _) = client.recv_from().unwrap();
+    let reply = Packet::deserialize(&data[..data.len() - 4]).unwrap();
+    let PacketType::ConnectionCookie {
+        cookie_high,
+        cookie_low,
+    } = reply.packet_type
+    else {
+        panic!("expected a cookie, got {:?}", reply.packet_type);
+    };
+
+    let echo = gbnet::wire::encode_raw_packet_with_payload(
+        config.protocol_id,
+        0,
+        PacketType::ConnectionRequestWithCookie {
+            cookie_high,
+            cookie_low,
+        },
+        padding,
+    )
+    .unwrap();
+    client.send_to(&echo, peer.local_addr().unwrap()).unwrap();
+    peer.update();
+    let (data, _) = client.recv_from().unwrap();
+    let reply = Packet::deserialize(&data[..data.len() - 4]).unwrap();
+    assert!(matches!(
+        reply.packet_type,
+        PacketType::ConnectionChallenge { .. }
+    ));
+}
+
+#[test]
+fn test_small_messages_coalesced_into_one_datagram() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default()
+        .with_channel_config(1, ChannelConfig::unreliable())
+        .with_channel_config(2, ChannelConfig::reliable_unordered());
+    let (mut server, mut client) = connected_pair(&network, config, None);
+
+    let sent_before = client.transport().stats().packets_sent;
+    for i in 0..10u8 {
+        client.send(i % 3, &[i; 20]).unwrap();
+    }
+    client.update();
+    let datagrams = client.transport().stats().packets_sent - sent_before;
+    assert!(
+        datagrams <= 2,
+        "expected coalescing, sent {datagrams} datagrams"
+    );
+
+    let mut received: Vec<(u8, Vec<u8>)> = server
+        .update()
+        .into_iter()
+        .filter_map(|e| match e {
+            ServerEvent::Message { channel, data, .. } => Some((channel, data)),
+            _ => None,
+        })
+        .collect();
+    received.sort();
+    let mut expected: Vec<(u8, Vec<u8>)> = (0..10u8).map(|i| (i % 3, vec![i; 20])).collect();
+    expected.sort();
+    assert_eq!(received, expected);
+
+    // One ack for the coalesced packet releases every reliable message in it.
+    client.update();
+    let pending: usize = client
+        .channel_stats()
+        .iter()
+        .map(|s| s.pending_ack_count)
+        .sum();
+    assert_eq!(pending, 0);
+}
+
+#[test]
+fn test_same_channel_messages_batched_end_to_end() {
+    let network = MemoryNetwork::new();
+    let (mut server, mut client) = connected_pair(&network, NetworkConfig::default(), None);
+
+    for i in 0..30u8 {
+        client.send(0, &[i; 16]).unwrap();
+    }
+    client.update();
+    assert_eq!(client.stats().batched_packets_sent, 1);
+    assert_eq!(client.stats().batched_messages_sent, 30);
+
+    let received: Vec<Vec<u8>> = server
+        .update()
+        .into_iter()
+        .filter_map(|e| match e {
+            ServerEvent::Message { data, .. } => Some(data),
+            _ => None,
+        })
+        .collect();
+    let expected: Vec<Vec<u8>> = (0..30u8).map(|i| vec![i; 16]).collect();
+    assert_eq!(received, expected);
+
+    client.update();
+    assert_eq!(client.channel_stats()[0].pending_ack_count, 0);
+}
+
+#[test]
+fn test_small_and_fragmented_messages_keep_order_on_sequenced_channel() {
+    let network = MemoryNetwork::new();
+    let config =
+        NetworkConfig::default().with_channel_config(0, ChannelConfig::unreliable_sequenced());
+    let (mut server, mut client) = connected_pair(&network, config, None);
+
+    client.send(0, b"small-first").unwrap();
+    client.send(0, &[9; 3000]).unwrap();
+    client.update();
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        for event in server.update() {
+            if let ServerEvent::Message { data, .. } = event {
+                received.push(data);
+            }
+        }
+    }
+    assert_eq!(received, vec![b"small-first".to_vec(), vec![9; 3000]]);
+}
+
+#[test]
+fn test_server_bandwidth_cap_per_connection() {
+    const CAP: usize = 5_000;
+    let clock = ManualClock::new();
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default().with_max_bandwidth(CAP);
+    let (mut server, mut client) = connected_pair(&network, config, Some(&clock));
+    let client_addr = client.transport().local_addr().unwrap();
+
+    for i in 0..300u16 {
+        server.send(client_addr, 0, &[i as u8; 100]).unwrap();
+    }
+
+    let sent_before = server.transport().stats().bytes_sent;
+    let mut received = 0;
+    for _ in 0..20 {
+        clock.advance(Duration::from_millis(100));
+        server.update();
+        received += client
+            .update()
+            .into_iter()
+            .filter(|e| matches!(e, ClientEvent::Message { .. }))
+            .count();
+    }
+    let sent = (server.transport().stats().bytes_sent - sent_before) as usize;
+
+    // Two seconds at the cap plus one MTU of burst and a little header slack.
+    assert!(sent <= 2 * CAP + 1_200 + 200, "sent {sent} bytes");
+    assert!(received > 0 && received < 300, "received {received}");
+}
+
+/// Advances `clock` in 100ms steps for `ticks` ticks, updating the server and
+/// every client, and returns how many messages each client received.
+fn run_ticks(
+    clock: &ManualClock,
+    server: &mut NetServer<MemoryTransport>,
+    clients: &mut [NetClient<MemoryTransport>],
+    ticks: usize,
+) -> Vec<usize> {
+    let mut received = vec![0; clients.len()];
+    for _ in 0..ticks {
+        clock.advance(Duration::from_millis(100));
+        server.update();
+        for (client, count) in clients.iter_mut().zip(&mut received) {
+            *count += client
+                .update()
+                .into_iter()
+                .filter(|e| matches!(e, ClientEvent::Message { .. }))
+                .count();
+        }
+    }
+    received
+}
+
+#[test]
+fn test_server_bandwidth_budget_shared_fairly() {
+    const BUDGET: usize = 10_000;
+    let clock = ManualClock::new();
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default().with_server_bandwidth(BUDGET);
+    let mut server = memory_server(&network, server_config).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let mut clients: Vec<_> = (1..=2)
+        .map(|host| {
+            memory_client(&network, host, server_addr, NetworkConfig::default())
+                .with_clock(clock.shared())
+        })
+        .collect();
+    run_ticks(&clock, &mut server, &mut clients, 5);
+    assert_eq!(server.client_count(), 2);
+    let heavy = clients[0].transport().local_addr().unwrap();
+    let light = clients[1].transport().local_addr().unwrap();
+
+    // The heavy client's backlog far exceeds the budget; the light client
+    // sends a trickle that must still get through every tick.
+    for i in 0..500u16 {
+        server.send(heavy, 0, &[i as u8; 100]).unwrap();
+    }
+    let sent_before = server.transport().stats().bytes_sent;
+    let mut light_received = 0;
+    for tick in 0..20u8 {
+        for _ in 0..3 {
+            server.send(light, 0, &[tick; 50]).unwrap();
+        }
+        light_received += run_ticks(&clock, &mut server, &mut clients[1..], 1)[0];
+        clients[0].update();
+    }
+    let sent = (server.transport().stats().bytes_sent - sent_before) as usize;
+    assert!(sent <= 2 * BUDGET + 1_200 + 400, "sent {sent} bytes");
+
+    // Header overhead can push a tick's trickle into the next one, but the
+    // light client is never starved by the backlog.
+    light_received += run_ticks(&clock, &mut server, &mut clients[1..], 3)[0];
+    assert_eq!(light_received, 60);
+}
+
+#[test]
+fn test_server_bandwidth_budget_respects_client_weights() {
+    let clock = ManualClock::new();
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default().with_server_bandwidth(8_000);
+    let mut server = memory_server(&network, server_config).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let mut clients: Vec<_> = (1..=2)
+        .map(|host| {
+            memory_client(&network, host, server_addr, NetworkConfig::default())
+                .with_clock(clock.shared())
+        })
+        .collect();
+    run_ticks(&clock, &mut server, &mut clients, 5);
+    let addrs: Vec<SocketAddr> = clients
+        .iter()
+        .map(|c| c.transport().local_addr().unwrap())
+        .collect();
+
+    server.set_client_weight(addrs[0], 3);
+    assert_eq!(server.client_weight(addrs[0]), Some(3));
+    assert_eq!(server.client_weight(addrs[1]), Some(1));
+    for addr in &addrs {
+        for i in 0..500u16 {
+            server.send(*addr, 0, &[i as u8; 100]).unwrap();
+        }
+    }
+
+    let received = run_ticks(&clock, &mut server, &mut clients, 20);
+    let ratio = received[0] as f32 / received[1] as f32;
+    assert!((2.5..=3.5).contains(&ratio), "received {received:?}");
+}
+
+#[cfg(feature = "encryption")]
+const TOKEN_KEY: [u8; 32] = [0x17; 32];
+
+#[cfg(feature = "encryption")]
+fn connect_token(server_addr: SocketAddr, client_id: u64, key: &[u8; 32]) -> gbnet::ConnectToken {
+    let protocol_id = NetworkConfig::default().protocol_id;
+    gbnet::ConnectToken::generate(
+        key,
+        protocol_id,
+        client_id,
+        vec![server_addr],
+        60,
+        b"team=blue",
+    )
+    .unwrap()
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_connect_token_identifies_client() {
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default().with_connect_token_key(TOKEN_KEY);
+    let mut server = memory_server(&network, server_config);
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, NetworkConfig::default())
+        .with_connect_token(connect_token(server_addr, 42, &TOKEN_KEY));
+    let mut tokenless = memory_client(&network, 2, server_addr, NetworkConfig::default());
+
+    let mut connected = Vec::new();
+    for _ in 0..10 {
+        for event in server.update() {
+            if let ServerEvent::ClientConnected {
+                client_id,
+                user_data,
+                ..
+            } = event
+            {
+                connected.push((client_id, user_data));
+            }
+        }
+        client.update();
+        tokenless.update();
+    }
+    assert!(client.is_connected());
+    assert!(!tokenless.is_connected());
+    assert_eq!(connected, vec![(Some(42), b"team=blue".to_vec())]);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_connect_token_replay_and_forgery_rejected() {
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default().with_connect_token_key(TOKEN_KEY);
+    let mut server = memory_server(&network, server_config);
+    let server_addr = server.local_addr().unwrap();
+    let token = connect_token(server_addr, 42, &TOKEN_KEY);
+    let mut clients = vec![
+        memory_client(&network, 1, server_addr, NetworkConfig::default())
+            .with_connect_token(token.clone()),
+        memory_client(&network, 2, server_addr, NetworkConfig::default()).with_connect_token(token),
+        memory_client(&network, 3, server_addr, NetworkConfig::default())
+            .with_connect_token(connect_token(server_addr, 43, &[0x99; 32])),
+    ];
+
+    for _ in 0..10 {
+        server.update();
+        for client in &mut clients {
+            client.update();
+        }
+    }
+    assert!(clients[0].is_connected());
+    assert!(!clients[1].is_connected(), "replayed token accepted");
+    assert!(!clients[2].is_connected(), "forged token accepted");
+    assert_eq!(server.client_count(), 1);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_connect_token_not_claimed_by_unproven_address() {
+    let network = MemoryNetwork::new();
+    let server_config = NetworkConfig::default().with_connect_token_key(TOKEN_KEY);
+    let mut server = memory_server(&network, server_config);
+    let server_addr = server.local_addr().unwrap();
+    let token = connect_token(server_addr, 42, &TOKEN_KEY);
+
+    // An eavesdropper replays the token first but never answers the cookie.
+    let _eavesdropper = memory_client(&network, 2, server_addr, NetworkConfig::default())
+        .with_connect_token(token.clone());
+    server.update();
+
+    let mut client =
+        memory_client(&network, 1, server_addr, NetworkConfig::default()).with_connect_token(token);
+    complete_handshake(&mut server, &mut client);
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn test_connect_token_key_requires_encryption_feature() {
+    let network = MemoryNetwork::new();
+    let transport = network.bind("10.0.0.1:7777".parse().unwrap()).unwrap();
+    let config = NetworkConfig::default().with_connect_token_key([0x17; 32]);
+    assert!(NetServer::with_transport(transport, config).is_err());
+}
+
+/// Takes every datagram queued for the server, as an on-path attacker would.
+#[cfg(feature = "encryption")]
+fn intercept(server: &mut NetServer<MemoryTransport>) -> Vec<Vec<u8>> {
+    let mut datagrams = Vec::new();
+    while let Ok((data, _)) = server.transport_mut().recv_from() {
+        datagrams.push(data.to_vec());
+    }
+    datagrams
+}
+
+/// Returns the messages the server delivers from what is currently queued.
+#[cfg(feature = "encryption")]
+fn server_messages(server: &mut NetServer<MemoryTransport>) -> Vec<Vec<u8>> {
+    server
+        .update()
+        .into_iter()
+        .filter_map(|e| match e {
+            ServerEvent::Message { data, .. } => Some(data),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_handshake_hides_connect_data_and_accept_payload() {
+    let network = MemoryNetwork::new();
+    let config = NetworkConfig::default().with_encryption();
+    let mut server = memory_server(&network, config.clone().with_manual_admission(true));
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, config)
+        .with_connect_data(b"ticket-4f1d9c")
+        .unwrap();
+    let client_addr = client.transport().local_addr().unwrap();
+    let leaks = |datagram: &[u8]| {
+        [&b"ticket-4f1d9c"[..], b"player-42"]
+            .iter()
+            .any(|secret| datagram.windows(secret.len()).any(|w| w == *secret))
+    };
+
+    let mut connect_data = None;
+    for _ in 0..10 {
+        for datagram in intercept(&mut server) {
+            assert!(!leaks(&datagram), "connect data sent in the clear");
+            client
+                .transport_mut()
+                .send_to(&datagram, server_addr)
+                .unwrap();
+        }
+        for event in server.update() {
+            match event {
+                ServerEvent::ConnectionRequest { addr, .. } => {
+                    assert!(server.accept_connection_with_data(addr, b"player-42"));
+                }
+                ServerEvent::ClientConnected {
+                    connect_data: data, ..
+                } => connect_data = Some(data),
+                _ => {}
+            }
+        }
+        let mut to_client = Vec::new();
+        while let Ok((datagram, _)) = client.transport_mut().recv_from() {
+            to_client.push(datagram.to_vec());
+        }
+        for datagram in to_client {
+            assert!(!leaks(&datagram), "accept payload sent in the clear");
+            server
+                .transport_mut()
+                .send_to(&datagram, client_addr)
+                .unwrap();
+        }
+        client.update();
+    }
+    assert!(client.is_connected());
+    assert_eq!(connect_data.as_deref(), Some(&b"ticket-4f1d9c"[..]));
+    assert_eq!(client.accept_payload(), b"player-42");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_session_hides_and_delivers_messages() {
+    let network = MemoryNetwork::new();
+    let (mut server, mut client) =
+        connected_pair(&network, NetworkConfig::default().with_encryption(), None);
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.transport().local_addr().unwrap();
+
+    client.send(0, b"secret plans").unwrap();
+    client.update();
+    for datagram in intercept(&mut server) {
+        assert!(!datagram
+            .windows(b"secret plans".len())
+            .any(|w| w == b"secret plans"));
+        client
+            .transport_mut()
+            .send_to(&datagram, server_addr)
+            .unwrap();
+    }
+    assert_eq!(server_messages(&mut server), vec![b"secret plans".to_vec()]);
+
+    server.send(client_addr, 0, b"acknowledged").unwrap();
+    server.update();
+    let got = client
+        .update()
+        .into_iter()
+        .any(|e| matches!(e, ClientEvent::Message { data, .. } if data == b"acknowledged"));
+    assert!(got, "Client should receive the sealed reply");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_session_drops_tampered_and_replayed_packets() {
+    let network = MemoryNetwork::new();
+    let (mut server, mut client) =
+        connected_pair(&network, NetworkConfig::default().with_encryption(), None);
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.transport().local_addr().unwrap();
+
+    client.send(0, b"fire").unwrap();
+    client.update();
+    let datagram = intercept(&mut server)
+        .into_iter()
+        .max_by_key(|d| d.len())
+        .unwrap();
+
+    // A byte flipped inside the ciphertext, with the CRC recomputed so only
+    // authentication can catch it.
+    let mut tampered = datagram[..datagram.len() - 4].to_vec();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0x01;
+    gbnet::security::append_crc32(&mut tampered);
+    client
+        .transport_mut()
+        .send_to(&tampered, server_addr)
+        .unwrap();
+    assert!(server_messages(&mut server).is_empty());
+
+    client
+        .transport_mut()
+        .send_to(&datagram, server_addr)
+        .unwrap();
+    assert_eq!(server_messages(&mut server), vec![b"fire".to_vec()]);
+
+    client
+        .transport_mut()
+        .send_to(&datagram, server_addr)
+        .unwrap();
+    assert!(server_messages(&mut server).is_empty(), "replay delivered");
+
+    // Unsealed packets are refused once the session is encrypted.
+    let plain = Packet::new(
+        PacketHeader {
+            protocol_id: NetworkConfig::default().protocol_id,
+            sequence: 1000,
+            ack: 0,
+            ack_bits: 0,
+        },
+        PacketType::Payload {
+            channel: 0,
+            is_fragment: false,
+        },
+    )
+    .with_payload(b"forged".to_vec());
+    let mut plain = plain.serialize().unwrap();
+    gbnet::security::append_crc32(&mut plain);
+    client.transport_mut().send_to(&plain, server_addr).unwrap();
+    assert!(server_messages(&mut server).is_empty());
+
+    assert_eq!(server.stats(client_addr).unwrap().packets_rejected, 3);
+    assert_eq!(server.client_count(), 1);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_pinned_server_key_authenticates_key_exchange() {
+    let seed = [0x5a; 32];
+    let pinned = gbnet::ServerIdentity::from_seed(&seed)
+        .unwrap()
+        .public_key();
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(
+        &network,
+        NetworkConfig::default().with_server_identity_key(seed),
+    );
+    let server_addr = server.local_addr().unwrap();
+    let mut trusting = memory_client(
+        &network,
+        1,
+        server_addr,
+        NetworkConfig::default().with_pinned_server_key(pinned),
+    );
+    let mut misled = memory_client(
+        &network,
+        2,
+        server_addr,
+        NetworkConfig::default().with_pinned_server_key([0x11; 32]),
+    );
+
+    for _ in 0..10 {
+        server.update();
+        trusting.update();
+        misled.update();
+    }
+    assert!(trusting.is_connected());
+    assert!(!misled.is_connected(), "unsigned key share accepted");
+    assert_eq!(server.client_count(), 1);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_server_denies_client_without_key_share() {
+    let network = MemoryNetwork::new();
+    let mut server = memory_server(&network, NetworkConfig::default().with_encryption());
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, NetworkConfig::default());
+
+    let mut events = Vec::new();
+    for _ in 0..10 {
+        server.update();
+        events.extend(client.update());
+    }
+    assert!(!client.is_connected());
+    assert_eq!(server.client_count(), 0);
+    assert!(events.iter().any(|event| matches!(
+        event,
+        ClientEvent::Disconnected(DisconnectReason::Unknown(reason))
+            if *reason == gbnet::packet::deny_reason::INVALID_CHALLENGE
+    )));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encrypted_session_rekeys_across_loss_and_reordering() {
+    let clock = ManualClock::new();
+    let network = MemoryNetwork::new();
+    // Loss starts after the handshake: packets are dropped and reordered
+    // around every key switch.
+    let simulation = SimulationConfig {
+        latency_ms: 20,
+        jitter_ms: 20,
+        out_of_order_chance: 0.3,
+        ..Default::default()
+    }
+    .with_seed(7)
+    .with_condition(
+        Duration::from_secs(1),
+        Duration::from_secs(3),
+        gbnet::NetworkCondition::LossBurst(0.1),
+    );
+    let config = NetworkConfig::default()
+        .with_encryption()
+        .with_rekey_after_packets(25)
+        .with_simulation(simulation);
+    let mut server = memory_server(&network, config.clone()).with_clock(clock.shared());
+    let server_addr = server.local_addr().unwrap();
+    let mut client = memory_client(&network, 1, server_addr, config).with_clock(clock.shared());
+    let client_addr = client.transport().local_addr().unwrap();
+
+    let (mut sent, mut received) = (0, 0);
+    for tick in 0..600 {
+        clock.advance(Duration::from_millis(10));
+        if client.is_connected() && tick < 300 {
+            client.send(0, &[tick as u8; 32]).unwrap();
+            sent += 1;
+        }
+        client.update();
+        received += server_messages(&mut server).len();
+    }
+
+    assert!(client.is_connected());
+    let rekeys = client.stats().rekeys;
+    assert!(rekeys >= 3, "client completed {} rekeys", rekeys);
+    assert!(server.stats(client_addr).unwrap().rekeys >= 3);
+    assert!(sent > 0);
+    assert_eq!(received, sent, "reliable messages lost across rekeys");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_authenticated_session_rejects_modified_packets() {
+    let network = MemoryNetwork::new();
+    let (mut server, mut client) = connected_pair(
+        &network,
+        NetworkConfig::default().with_authentication(),
+        None,
+    );
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.transport().local_addr().unwrap();
+
+    client.send(0, b"fire").unwrap();
+    client.update();
+    let datagram = intercept(&mut server)
+        .into_iter()
+        .max_by_key(|d| d.len())
+        .unwrap();
+    let at = datagram
+        .windows(4)
+        .position(|w| w == b"fire")
+        .expect("authenticated payloads stay readable");
+
+    // The on-path attacker rewrites the message and fixes up the CRC.
+    let mut forged = datagram[..datagram.len() - 4].to_vec();
+    forged[at..at + 4].copy_from_slice(b"hold");
+    gbnet::security::append_crc32(&mut forged);
+    client
+        .transport_mut()
+        .send_to(&forged, server_addr)
+        .unwrap();
+    assert!(server_messages(&mut server).is_empty());
+
+    client
+        .transport_mut()
+        .send_to(&datagram, server_addr)
+        .unwrap();
+    assert_eq!(server_messages(&mut server), vec![b"fire".to_vec()]);
+    assert_eq!(server.stats(client_addr).unwrap().packets_rejected, 1);
+}